@@ -0,0 +1,26 @@
+//! `/messages` responses from the Amnezichat server: envelope extraction
+//! and decryption of every payload found.
+//!
+//!     cargo +nightly fuzz run envelope -- -malloc_limit_mb=256
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/encryption.rs"]
+#[allow(dead_code)]
+mod encryption;
+#[path = "../../src/envelope.rs"]
+#[allow(dead_code)]
+mod envelope;
+
+fuzz_target!(|data: &[u8]| {
+    let body = String::from_utf8_lossy(data);
+    let markers = envelope::Markers::default();
+    let payloads = markers.extract(&body);
+    assert!(payloads.len() <= body.len() / (markers.begin.len() + markers.end.len()));
+    // Each decryption runs Argon2; a couple per input keeps fuzzing fast.
+    for payload in payloads.into_iter().take(2) {
+        let _ = encryption::decrypt_data(payload, "00");
+    }
+});
@@ -0,0 +1,26 @@
+//! Lines from the IRC server, as `CustomIrcClient::receive_message` hands
+//! them to `Message::parse` (and through it `parse_irc_message`).
+//!
+//!     cargo +nightly fuzz run irc_message -- -malloc_limit_mb=64
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/irc/proto.rs"]
+#[allow(dead_code)]
+mod proto;
+
+fuzz_target!(|data: &[u8]| {
+    // Invalid UTF-8 is read as latin-1 by the client.
+    let line = match std::str::from_utf8(data) {
+        Ok(line) => line.to_string(),
+        Err(_) => data.iter().map(|&b| b as char).collect(),
+    };
+    if let Some(message) = proto::Message::parse(&line) {
+        // Nothing parsed can be larger than the line it came from.
+        let size: usize = message.params.iter().map(String::len).sum::<usize>() + message.command.len();
+        assert!(size <= line.len());
+        let _ = message.tag("batch");
+    }
+});
@@ -0,0 +1,141 @@
+//! The `bridge` command, with which operators list and change the mapped
+//! channels while the bridge runs. It is only taken in private messages,
+//! since it carries the admin password and room keys.
+
+use std::time::{Duration, Instant};
+
+use crate::bridge::{same_nick, Mapping};
+use crate::encryption::ROOM_KEY_LEN;
+
+/// How long a login lasts; after that the password is asked for again.
+pub const SESSION_LENGTH: Duration = Duration::from_secs(60 * 60);
+
+pub const USAGE: &str = "Usage: bridge login <password> | list | add #channel room-id:key | remove #channel";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AdminCommand {
+    Login(String),
+    List,
+    Add(Mapping),
+    Remove(String),
+}
+
+/// Parses the arguments of the `bridge` command.
+pub fn parse(args: &str) -> Option<AdminCommand> {
+    let mut words = args.split_whitespace();
+    let command = match words.next()?.to_ascii_lowercase().as_str() {
+        "login" => AdminCommand::Login(words.next()?.to_string()),
+        "list" => AdminCommand::List,
+        "add" => {
+            let channel = words.next().filter(|c| is_channel_name(c))?;
+            let (room_id, key) = words.next()?.split_once(':')?;
+            let key = key.to_ascii_lowercase();
+            if room_id.is_empty() || hex::decode(&key).map_or(true, |k| k.len() != ROOM_KEY_LEN) {
+                return None;
+            }
+            AdminCommand::Add(Mapping { channel: channel.to_string(), room_id: room_id.to_string(), shared_secret: key })
+        }
+        "remove" => AdminCommand::Remove(words.next().filter(|c| is_channel_name(c))?.to_string()),
+        _ => return None,
+    };
+    words.next().is_none().then_some(command)
+}
+
+fn is_channel_name(name: &str) -> bool {
+    name.len() > 1 && name.starts_with(['#', '&']) && !name.contains(',')
+}
+
+/// Who has logged in with the admin password, as `nick!user@host`. A login
+/// lasts `SESSION_LENGTH` at most, and ends early when the nick changes or
+/// quits or the IRC connection is replaced. The bridge doesn't see every
+/// QUIT, so it is the user and host that keep someone taking the nick over
+/// from inheriting a login.
+pub struct AdminSessions {
+    password: Option<String>,
+    sessions: Vec<(String, Instant)>,
+}
+
+impl AdminSessions {
+    /// Without a password nobody can log in.
+    pub fn new(password: Option<String>) -> Self {
+        AdminSessions { password, sessions: Vec::new() }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.password.is_some()
+    }
+
+    pub fn login(&mut self, mask: &str, attempt: &str, now: Instant) -> bool {
+        let Some(password) = &self.password else { return false };
+        // Compares every byte, so the time taken doesn't tell how much of
+        // the attempt was right.
+        let matches = password.len() == attempt.len() && password.bytes().zip(attempt.bytes()).fold(0, |d, (a, b)| d | (a ^ b)) == 0;
+        if matches {
+            self.sessions.retain(|(m, _)| !same_mask(m, mask));
+            self.sessions.push((mask.to_string(), now));
+        }
+        matches
+    }
+
+    pub fn is_admin(&self, mask: &str, now: Instant) -> bool {
+        self.sessions.iter().any(|(m, since)| same_mask(m, mask) && now.saturating_duration_since(*since) < SESSION_LENGTH)
+    }
+
+    pub fn forget(&mut self, nick: &str) {
+        self.sessions.retain(|(m, _)| !same_nick(m.split('!').next().unwrap_or(m), nick));
+    }
+
+    pub fn clear(&mut self) {
+        self.sessions.clear();
+    }
+}
+
+/// Nicks compare as IRC does, hosts without regard to case.
+fn same_mask(a: &str, b: &str) -> bool {
+    let (a_nick, a_host) = a.split_once('!').unwrap_or((a, ""));
+    let (b_nick, b_host) = b.split_once('!').unwrap_or((b, ""));
+    same_nick(a_nick, b_nick) && a_host.eq_ignore_ascii_case(b_host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_subcommands() {
+        let key = "ab".repeat(32);
+        assert_eq!(parse("LIST"), Some(AdminCommand::List));
+        assert_eq!(parse("login hunter2"), Some(AdminCommand::Login("hunter2".into())));
+        assert_eq!(
+            parse(&format!("add #new room2:{}", key.to_uppercase())),
+            Some(AdminCommand::Add(Mapping { channel: "#new".into(), room_id: "room2".into(), shared_secret: key.clone() }))
+        );
+        assert_eq!(parse("remove #new"), Some(AdminCommand::Remove("#new".into())));
+
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("add #new room2"), None);
+        assert_eq!(parse("add #new room2:abcd"), None);
+        assert_eq!(parse(&format!("add new room2:{}", key)), None);
+        assert_eq!(parse(&format!("add #a,#b room2:{}", key)), None);
+        assert_eq!(parse("remove #new extra"), None);
+    }
+
+    #[test]
+    fn logins_belong_to_one_user_for_a_while() {
+        let now = Instant::now();
+        let mut sessions = AdminSessions::new(Some("hunter2".into()));
+        assert!(!sessions.login("alice!a@host", "hunter3", now));
+        assert!(!sessions.is_admin("alice!a@host", now));
+        assert!(sessions.login("alice!a@host", "hunter2", now));
+        assert!(sessions.is_admin("ALICE!a@HOST", now));
+        // Someone else under the nick, unseen by the bridge.
+        assert!(!sessions.is_admin("alice!eve@elsewhere", now));
+        assert!(!sessions.is_admin("alice!a@host", now + SESSION_LENGTH));
+        sessions.forget("Alice");
+        assert!(!sessions.is_admin("alice!a@host", now));
+
+        let mut disabled = AdminSessions::new(None);
+        assert!(!disabled.enabled());
+        assert!(!disabled.login("alice!a@host", "", now));
+    }
+}
@@ -0,0 +1,96 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Lines sent for `.log` when no count is given.
+pub const DEFAULT_LOG_LINES: usize = 10;
+/// Most lines one `.log` sends, whatever `--log-size` keeps.
+pub const MAX_LOG_LINES: usize = 20;
+/// Least time between two `.log` requests from one nick.
+#[cfg(not(test))]
+pub const LOG_COOLDOWN: Duration = Duration::from_secs(60);
+/// Short enough for a test to wait out.
+#[cfg(test)]
+pub const LOG_COOLDOWN: Duration = Duration::from_secs(2);
+
+/// Which side a bridged message came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Irc,
+    Room,
+}
+
+/// The last messages that crossed the bridge in either direction, for the
+/// `.log` command. Kept in memory only; the oldest are dropped once
+/// `capacity` is reached.
+pub struct Backlog {
+    capacity: usize,
+    entries: VecDeque<(Side, String, String)>,
+}
+
+impl Backlog {
+    pub fn new(capacity: usize) -> Self {
+        Backlog { capacity, entries: VecDeque::with_capacity(capacity) }
+    }
+
+    pub fn record(&mut self, side: Side, nick: &str, text: &str) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((side, nick.to_string(), text.to_string()));
+    }
+
+    /// The last `count` messages, oldest first, formatted for IRC.
+    pub fn last(&self, count: usize) -> Vec<String> {
+        let skip = self.entries.len().saturating_sub(count);
+        self.entries
+            .iter()
+            .skip(skip)
+            .map(|(side, nick, text)| {
+                let side = match side {
+                    Side::Irc => "irc",
+                    Side::Room => "room",
+                };
+                format!("[{}] <{}> {}", side, nick, text)
+            })
+            .collect()
+    }
+}
+
+/// Parses the `.log` argument: a count, or nothing for the default.
+pub fn parse_count(args: &str) -> Option<usize> {
+    if args.trim().is_empty() {
+        return Some(DEFAULT_LOG_LINES);
+    }
+    args.trim().parse().ok().filter(|n| *n > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_most_recent_messages() {
+        let mut backlog = Backlog::new(3);
+        backlog.record(Side::Irc, "alice", "one");
+        backlog.record(Side::Room, "bob", "two");
+        backlog.record(Side::Irc, "alice", "three");
+        backlog.record(Side::Room, "bob", "four");
+        assert_eq!(backlog.last(10), vec!["[room] <bob> two", "[irc] <alice> three", "[room] <bob> four"]);
+        assert_eq!(backlog.last(1), vec!["[room] <bob> four"]);
+
+        let mut disabled = Backlog::new(0);
+        disabled.record(Side::Irc, "alice", "one");
+        assert!(disabled.last(10).is_empty());
+    }
+
+    #[test]
+    fn parses_counts() {
+        assert_eq!(parse_count(""), Some(DEFAULT_LOG_LINES));
+        assert_eq!(parse_count(" 5 "), Some(5));
+        assert_eq!(parse_count("0"), None);
+        assert_eq!(parse_count("lots"), None);
+    }
+}
@@ -1,351 +1,3827 @@
-use std::collections::HashSet;
-use std::io::{self, BufRead, BufReader, Write};
-use std::net::TcpStream;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
-use base64::engine::general_purpose;
-use base64::Engine;
-use tokio::sync::{mpsc, Mutex};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, Semaphore};
+use tokio::task::JoinHandle;
 use tokio::time::{sleep, timeout};
+use tokio_util::sync::CancellationToken;
 
-use crate::encryption::encrypt_data;
-use crate::network_operations::{receive_and_fetch_messages, send_encrypted_message};
+use crate::admin::{self, AdminCommand, AdminSessions};
+use crate::backlog::{self, Backlog, Side};
+use crate::channel::{ChannelState, JoinRetry, MuteChange};
+use crate::commands::{self, parse_command};
+use crate::encryption::{decrypt_data, encrypt_data, sign_relay, verify_relay};
+use crate::flood::{FloodLimit, FloodLimiter, FloodVerdict};
+use crate::graphemes;
+use crate::health::{AmnezichatStatus, DedupStatus, Health, IrcStatus, StatusSnapshot};
+use crate::identity::{AuthStatus, IdentifyPolicy, IdentityCache};
+use crate::irc::client::{cap_change, wanted_caps, CustomIrcClient, Incoming, IrcConnection, ServerError, Watchdog};
+use crate::irc::proto::{Command, Message};
+use crate::logging::{self, log_error_in, log_recovered, Context};
+use crate::markup::{self, MarkupMode};
+use crate::playback::PlaybackFilter;
+use crate::network_operations::{receive_and_fetch_messages, send_encrypted_message, RoomEvent, ServerList, TooLarge};
+use crate::oversize::{RoomLimit, MIN_PART_BYTES};
+use crate::queue::{OutboundQueue, OverflowPolicy};
+use crate::reactions::ReactionTargets;
+use crate::replies::ReplyHistory;
+use crate::same_person::SamePerson;
+use crate::sanitize::{sanitize, strip_formatting, Direction, UnicodeFilter};
+use crate::spool::{self, Spool};
+use crate::threads;
+use crate::transform::{no_transform, MessageTransform};
 
 pub struct Bridge {
-    #[allow(dead_code)]
-    irc_client: Arc<Mutex<CustomIrcClient>>,
-    #[allow(dead_code)]
-    tx: mpsc::Sender<(String, String)>,
-    #[allow(dead_code)]
+    irc: Arc<IrcLink>,
+    queue: Arc<OutboundQueue<IrcLine>>,
+    stopping: Arc<AtomicBool>,
+    routes: Routes,
+    quit_message: String,
+    part_on_quit: bool,
+    status: RoomStatus,
+    health: Arc<Health>,
     seen_amz: Arc<Mutex<HashSet<String>>>,
-    #[allow(dead_code)]
     seen_irc: Arc<Mutex<HashSet<String>>>,
+    /// The receive, send and watchdog tasks, cancelled through `cancel` at
+    /// the end of `shutdown` so nothing of this bridge outlives it when
+    /// another is started in the same process. Poll tasks belong to their
+    /// routes.
+    tasks: std::sync::Mutex<Vec<JoinHandle<()>>>,
+    cancel: CancellationToken,
+}
+
+/// How a room message containing newlines is sent to IRC.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MultilineMode {
+    /// Join everything into a single PRIVMSG.
+    #[default]
+    Collapse,
+    /// One PRIVMSG per line.
+    Split,
+}
+
+impl MultilineMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "collapse" => Some(MultilineMode::Collapse),
+            "split" => Some(MultilineMode::Split),
+            _ => None,
+        }
+    }
+}
+
+/// How the bridge watches for its configured nick to free up after it had
+/// to register with a fallback (`nick_`), so it can switch back.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NickRegain {
+    /// MONITOR where the server offers it, otherwise ISON.
+    #[default]
+    Monitor,
+    /// ISON every `ISON_INTERVAL`, for servers whose MONITOR misbehaves.
+    Ison,
+    /// Keep the fallback nick.
+    Off,
+}
+
+impl NickRegain {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "monitor" => Some(NickRegain::Monitor),
+            "ison" => Some(NickRegain::Ison),
+            "off" => Some(NickRegain::Off),
+            _ => None,
+        }
+    }
+}
+
+/// How the sender's name is coloured in room messages sent to IRC.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NickColors {
+    Off,
+    /// Every name in this mIRC colour.
+    Single(u8),
+    /// A colour from `NICK_PALETTE` picked by hashing the name, so each
+    /// sender keeps the same one.
+    Hashed,
+}
+
+impl Default for NickColors {
+    fn default() -> Self {
+        NickColors::Single(11)
+    }
+}
+
+/// mIRC colours readable on both light and dark backgrounds.
+const NICK_PALETTE: [u8; 11] = [2, 3, 4, 5, 6, 7, 9, 10, 11, 12, 13];
+
+impl NickColors {
+    /// Parses `off`, `hash` or a single mIRC colour number (0-15).
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "off" => Some(NickColors::Off),
+            "hash" => Some(NickColors::Hashed),
+            n => n.parse().ok().filter(|c| *c < 16).map(NickColors::Single),
+        }
+    }
+
+    fn color_for(self, nick: &str) -> Option<u8> {
+        match self {
+            NickColors::Off => None,
+            NickColors::Single(color) => Some(color),
+            NickColors::Hashed => {
+                // FNV-1a: unlike std's hasher, stable across builds.
+                let hash = nick.to_lowercase().bytes().fold(0x811c9dc5u32, |h, b| (h ^ b as u32).wrapping_mul(0x01000193));
+                Some(NICK_PALETTE[hash as usize % NICK_PALETTE.len()])
+            }
+        }
+    }
+
+    /// `nick >` in bold and colour, ready to put in front of a message.
+    fn label(self, nick: &str) -> String {
+        match self.color_for(nick) {
+            Some(color) => format!("\x02\x03{:02}{} >\x02\x03", color, nick),
+            None => format!("\x02{} >\x02", nick),
+        }
+    }
+}
+
+/// Most PRIVMSGs one room message is split into; further lines are folded
+/// into the last one.
+const MAX_SPLIT_LINES: usize = 8;
+
+/// Optional behaviour, set from command line flags.
+#[derive(Clone)]
+pub struct BridgeOptions {
+    pub identify_policy: IdentifyPolicy,
+    pub unicode_filter: UnicodeFilter,
+    pub command_prefix: String,
+    /// Built-in commands that get no answer; `all` turns them all off.
+    pub disabled_commands: Vec<String>,
+    pub relay_notices: bool,
+    pub idle_timeout: Duration,
+    pub max_missed_pongs: u32,
+    pub multiline: MultilineMode,
+    pub quit_message: String,
+    pub part_on_quit: bool,
+    /// Name this bridge's IRC network is tagged with in the room, so that
+    /// several bridges sharing one room relay each other's networks.
+    pub network: Option<String>,
+    /// Per-nick limit on messages relayed into the room; `None` disables it.
+    pub flood_limit: Option<FloodLimit>,
+    /// Tell a nick once per window that its messages are being dropped.
+    pub flood_notice: bool,
+    /// Post IRC connection lost/restored notices into the room.
+    pub room_status: bool,
+    /// Applied to every message in both directions before forwarding.
+    pub transform: Arc<dyn MessageTransform>,
+    /// Remove IRC bold, color and other formatting codes from everything
+    /// posted to the room.
+    pub strip_irc_formatting: bool,
+    /// Disabling one of these makes a one-way bridge, e.g. an announcement
+    /// feed.
+    pub relay_irc_to_amnezichat: bool,
+    pub relay_amnezichat_to_irc: bool,
+    /// Room messages waiting for IRC, and what to do once that many are.
+    pub queue_size: usize,
+    pub queue_overflow: OverflowPolicy,
+    /// Quote the message an IRC `nick: ...` line answers.
+    pub quote_replies: bool,
+    /// Show reactions in the room on IRC; off by default, since they can
+    /// be noisy.
+    pub relay_reactions: bool,
+    /// IRC nicks of people also in the room under their own name, and
+    /// whether their IRC messages are left out of the room or annotated.
+    pub same_person: SamePerson,
+    /// Room posts in flight at once. Above 1, a burst from IRC may be
+    /// stored in the room out of order.
+    pub max_room_sends: usize,
+    /// Sign everything posted to the room and only forward `[IRC...]` room
+    /// messages that carry a valid signature.
+    pub signing_key: Option<[u8; 32]>,
+    /// How Amnezichat markup in room messages is shown on IRC.
+    pub markup: MarkupMode,
+    pub nick_colors: NickColors,
+    /// Shown in front of every message this bridge posts to the room and to
+    /// IRC respectively, e.g. `[libera]`. Unlike the `[IRC]` origin tag they
+    /// are only for readers.
+    pub label_to_room: Option<String>,
+    pub label_to_irc: Option<String>,
+    /// Bridged messages kept in memory for `.log`; 0 disables it.
+    pub log_size: usize,
+    /// Room messages from before startup sent to IRC, marked as history.
+    /// Whatever the room holds beyond these at the first poll is only
+    /// marked as seen, so starting the bridge never floods IRC with it.
+    pub replay_history: usize,
+    /// Password for the `bridge` command; without one it is off.
+    pub admin_password: Option<String>,
+    /// Learn the accounts of everyone in a channel with one WHOX on joining
+    /// it, instead of a WHOIS per sender.
+    pub who_on_join: bool,
+    /// How often to post each channel's user count and topic to its room;
+    /// `None` never does.
+    pub channel_summary: Option<Duration>,
+    /// How IRC messages are fitted under the server's size limit.
+    pub room_limit: RoomLimit,
+    /// Kept fresh while the bridge is working, for an external watchdog.
+    pub liveness_file: Option<PathBuf>,
+    /// Leave IRC after this long without a message bridged either way, and
+    /// poll the rooms every `idle_poll_interval` until one needs relaying.
+    /// IRC messages sent meanwhile are missed.
+    pub idle_disconnect: Option<Duration>,
+    pub idle_poll_interval: Duration,
+    /// Directory where messages not yet delivered either way are kept, so
+    /// they are sent after a restart instead of lost.
+    pub spool: Option<PathBuf>,
+}
+
+impl Default for BridgeOptions {
+    fn default() -> Self {
+        BridgeOptions {
+            identify_policy: IdentifyPolicy::default(),
+            unicode_filter: UnicodeFilter::default(),
+            command_prefix: ".".to_string(),
+            disabled_commands: Vec::new(),
+            relay_notices: false,
+            idle_timeout: Duration::from_secs(120),
+            max_missed_pongs: 2,
+            multiline: MultilineMode::default(),
+            quit_message: "Amnezichat bridge shutting down".to_string(),
+            part_on_quit: false,
+            network: None,
+            flood_limit: Some(FloodLimit::default()),
+            flood_notice: false,
+            room_status: false,
+            transform: no_transform(),
+            strip_irc_formatting: false,
+            relay_irc_to_amnezichat: true,
+            relay_amnezichat_to_irc: true,
+            queue_size: 100,
+            queue_overflow: OverflowPolicy::default(),
+            quote_replies: false,
+            relay_reactions: false,
+            same_person: SamePerson::default(),
+            max_room_sends: 1,
+            signing_key: None,
+            markup: MarkupMode::default(),
+            nick_colors: NickColors::default(),
+            label_to_room: None,
+            label_to_irc: None,
+            log_size: backlog::MAX_LOG_LINES,
+            replay_history: 0,
+            admin_password: None,
+            who_on_join: false,
+            channel_summary: None,
+            room_limit: RoomLimit::default(),
+            liveness_file: None,
+            idle_disconnect: None,
+            idle_poll_interval: Duration::from_secs(30),
+            spool: None,
+        }
+    }
+}
+
+/// Everything needed to (re)establish the IRC connection.
+#[derive(Clone, Debug, Default)]
+pub struct IrcSettings {
+    pub server: String,
+    pub nick: String,
+    /// Channels joined after registration. `Bridge::new` fills this in from
+    /// its mappings.
+    pub channels: Vec<String>,
+    pub server_password: Option<String>,
+    pub sasl_username: Option<String>,
+    pub sasl_password: Option<String>,
+    /// Read for the SASL password at every connection instead of using
+    /// `sasl_password`, so a rotated password is used from the next
+    /// reconnect on.
+    pub sasl_password_file: Option<PathBuf>,
+    /// How long to wait for the TCP connection; `None` uses
+    /// `DEFAULT_CONNECT_TIMEOUT`.
+    pub connect_timeout: Option<Duration>,
+    pub trace: bool,
+    /// Request `away-notify` and relay away/account changes into the room.
+    pub presence: bool,
+    /// Limit for the whole registration (CAP, SASL, MOTD); `None` uses
+    /// `DEFAULT_REGISTRATION_TIMEOUT`.
+    pub registration_timeout: Option<Duration>,
+    /// USER fields; default to the nick and `DEFAULT_REALNAME`.
+    pub ident: Option<String>,
+    pub realname: Option<String>,
+    /// Longest wait after a reconnect before anything is sent; the actual
+    /// wait is picked between half of it and all of it, so bridges split
+    /// off together don't all speak at once when the net rejoins.
+    pub rejoin_delay: Option<Duration>,
+    /// Sent to every channel once that wait is over.
+    pub rejoin_announce: Option<String>,
+    /// Post room messages under the sender's own name with RELAYMSG where
+    /// the server offers `draft/relaymsg`.
+    pub relaymsg: bool,
+    /// How `nick` is won back after registering with a fallback.
+    pub regain_nick: NickRegain,
+    /// Least time between two messages (PRIVMSG, NOTICE, RELAYMSG) written
+    /// to IRC, for channels that penalize bursts; `None` writes them as
+    /// fast as they come.
+    pub send_delay: Option<Duration>,
+}
+
+/// One IRC channel bridged to one Amnezichat room.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mapping {
+    pub channel: String,
+    pub room_id: String,
+    pub shared_secret: String,
+}
+
+/// A mapping and the per-room state kept for it while the bridge runs.
+struct Route {
+    mapping: Mapping,
+    replies: Option<std::sync::Mutex<ReplyHistory>>,
+    backlog: std::sync::Mutex<Backlog>,
+    channel: std::sync::Mutex<ChannelState>,
+    /// The room's poll task, and what stops it when the mapping is removed.
+    poll: std::sync::Mutex<Option<JoinHandle<()>>>,
+    cancel: CancellationToken,
+}
+
+impl Route {
+    fn new(mapping: Mapping, options: &BridgeOptions) -> Self {
+        Route {
+            mapping,
+            replies: options.quote_replies.then(|| std::sync::Mutex::new(ReplyHistory::new())),
+            backlog: std::sync::Mutex::new(Backlog::new(options.log_size)),
+            channel: std::sync::Mutex::new(ChannelState::new()),
+            poll: std::sync::Mutex::new(None),
+            cancel: CancellationToken::new(),
+        }
+    }
+}
+
+/// The routes of a running bridge. Operators can add and remove mappings
+/// at runtime, so every task reads the current list from here.
+#[derive(Clone, Default)]
+struct Routes(Arc<std::sync::RwLock<Vec<Arc<Route>>>>);
+
+impl Routes {
+    fn snapshot(&self) -> Vec<Arc<Route>> {
+        self.0.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn channels(&self) -> Vec<String> {
+        self.snapshot().iter().map(|r| r.mapping.channel.clone()).collect()
+    }
+
+    /// Whether `channel` is one of the mapped channels.
+    fn bridges(&self, channel: &str, casemapping: Casemapping) -> bool {
+        self.snapshot().iter().any(|r| casemapping.same(&r.mapping.channel, channel))
+    }
+
+    fn add(&self, route: Arc<Route>) {
+        self.0.write().unwrap_or_else(|e| e.into_inner()).push(route);
+    }
+
+    /// Takes out the routes `matches` picks and stops their tasks.
+    fn stop(&self, matches: impl Fn(&Mapping) -> bool) -> Vec<Arc<Route>> {
+        let mut routes = self.0.write().unwrap_or_else(|e| e.into_inner());
+        let (stopped, kept) = routes.drain(..).partition(|r| matches(&r.mapping));
+        *routes = kept;
+        for route in &stopped {
+            route.cancel.cancel();
+        }
+        stopped
+    }
+}
+
+/// The route a message sent to `target` belongs to. Private messages go to
+/// the first mapping; channels that aren't mapped have none.
+fn route_for<'a>(routes: &'a [Arc<Route>], target: &str, casemapping: Casemapping) -> Option<&'a Arc<Route>> {
+    if is_channel(target) {
+        routes.iter().find(|r| casemapping.same(&r.mapping.channel, target))
+    } else {
+        routes.first()
+    }
+}
+
+/// All mappings share one IRC connection (one nick, one set of tasks); the
+/// receive loop hands each message to the mapping of its target channel.
+#[derive(Clone)]
+pub struct BridgeConfig {
+    pub mappings: Vec<Mapping>,
+    pub servers: Arc<ServerList>,
+    pub irc: IrcSettings,
+    pub options: BridgeOptions,
 }
 
 impl Bridge {
-    pub fn new(
-        shared_secret: String,
-        amnezichat_url: String,
-        irc_url: String,
-        room_id: String,
-        irc_nick: String,
-        irc_channel: String,
-        sasl_username: Option<String>,
-        sasl_password: Option<String>,
-    ) -> io::Result<Self> {
-        let client = CustomIrcClient::connect_and_auth(
-            &irc_url,
-            &irc_nick,
-            &irc_channel,
-            sasl_username.as_deref(),
-            sasl_password.as_deref(),
-        )?;
-        let irc_client = Arc::new(Mutex::new(client));
-
-        let (tx, mut rx) = mpsc::channel(100);
+    pub fn new(config: BridgeConfig) -> io::Result<Self> {
+        let BridgeConfig {
+            mappings,
+            servers,
+            mut irc,
+            options,
+        } = config;
+        if mappings.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "No channel is mapped to a room"));
+        }
+        for (i, mapping) in mappings.iter().enumerate() {
+            // Before connecting the server's casemapping isn't known; the
+            // default is the one that folds the most.
+            if mappings[..i].iter().any(|m| Casemapping::default().same(&m.channel, &mapping.channel)) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is mapped more than once", mapping.channel)));
+            }
+        }
+        irc.channels = mappings.iter().map(|m| m.channel.clone()).collect();
+        let identify_policy = options.identify_policy;
+        let unicode_filter = options.unicode_filter;
+        let command_prefix = options.command_prefix.clone();
+        let relay_notices = options.relay_notices;
+
+        let spool = options.spool.as_deref().map(Spool::open).transpose()?.map(Arc::new);
+        let started = SystemTime::now();
+        // Lines for rooms no longer bridged would never be replayed.
+        for entry in spool.iter().flat_map(|s| s.pending(spool::Destination::Irc, started)) {
+            if !mappings.iter().any(|m| m.room_id == entry.room_id) {
+                logging::warn("spool", format!("Dropping a spooled line for room {}, which is no longer bridged", entry.room_id));
+                spool.iter().for_each(|s| s.done(&entry.path));
+            }
+        }
+
+        let (connection, incoming) = CustomIrcClient::connect_and_auth(&irc)?.start()?;
+        let link = Arc::new(IrcLink::new(connection));
+
+        let queue = Arc::new(OutboundQueue::new(options.queue_size, options.queue_overflow));
         let seen_amz = Arc::new(Mutex::new(HashSet::new()));
         let seen_irc = Arc::new(Mutex::new(HashSet::new()));
+        let stopping = Arc::new(AtomicBool::new(false));
+        let health = Arc::new(Health::new(options.liveness_file.clone(), options.idle_disconnect));
+        let routes = Routes::default();
+        let poller = Poller {
+            queue: Arc::clone(&queue),
+            seen: Arc::clone(&seen_amz),
+            servers: Arc::clone(&servers),
+            stopping: Arc::clone(&stopping),
+            health: Arc::clone(&health),
+            options: options.clone(),
+            spool: spool.clone(),
+            started,
+        };
+        for mapping in mappings {
+            let route = Arc::new(Route::new(mapping, &options));
+            poller.start(&route);
+            routes.add(route);
+        }
+        let mut tasks = Vec::new();
+        let cancel = CancellationToken::new();
+
+        let mut sender = RoomSender::new(room_prefix(options.network.as_deref(), options.label_to_room.as_deref()), Arc::clone(&servers), &options);
+        sender.spool = spool.clone();
+        sender.cancel = cancel.clone();
+        let status = RoomStatus { enabled: options.room_status, routes: routes.clone(), sender: sender.clone() };
 
         {
-            let polling_tx = tx.clone();
-            let seen_amz_clone = Arc::clone(&seen_amz);
-            let secret_poll = shared_secret.clone();
-            let url_poll = amnezichat_url.clone();
-            let room_poll = room_id.clone();
-            let irc_chan_poll = irc_channel.clone();
-
-            tokio::spawn(async move {
+            let link_recv = Arc::clone(&link);
+            let mut incoming = incoming;
+            let seen_irc_clone = Arc::clone(&seen_irc);
+            let route_table = routes.clone();
+            let poller_recv = poller.clone();
+            let admin_password = options.admin_password.clone();
+            let irc_recv = irc.clone();
+            let stopping_recv = Arc::clone(&stopping);
+            let status_recv = status.clone();
+            let health_recv = Arc::clone(&health);
+            let mut flood = options.flood_limit.map(FloodLimiter::new);
+            let flood_notice = options.flood_notice;
+            let transform_recv = Arc::clone(&options.transform);
+            let relay_to_room = options.relay_irc_to_amnezichat;
+            let log_size = options.log_size;
+            let cancel_recv = cancel.clone();
+            let same_person = options.same_person.clone();
+            let who_on_join = options.who_on_join && identify_policy != IdentifyPolicy::Off;
+            let disabled_commands = options.disabled_commands.clone();
+            let strip_irc_formatting = options.strip_irc_formatting;
+            let wanted_caps = wanted_caps(&irc);
+            let to_room = move |text: &str| {
+                let text = sanitize(Direction::IrcToAmnezichat, text, unicode_filter);
+                if strip_irc_formatting {
+                    strip_formatting(&text)
+                } else {
+                    text
+                }
+            };
+
+            tasks.push(spawn_until(cancel.clone(), async move {
+                let mut identities = IdentityCache::new();
+                let mut playback = PlaybackFilter::new();
+                let mut admins = AdminSessions::new(admin_password);
+                let mut log_requests: HashMap<String, Instant> = HashMap::new();
+                let mut session = link_recv.current().connected_at;
                 loop {
-                    match timeout(Duration::from_secs(10), receive_and_fetch_messages(&room_poll, &secret_poll, &url_poll, false)).await {
-                        Ok(Ok(msgs)) => {
-                            for m in msgs {
-                                let mut set = seen_amz_clone.lock().await;
-                                if set.contains(&m) {
+                    let received = incoming.recv().await.unwrap_or_else(|| Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed")));
+                    let irc = link_recv.current();
+                    let routes = route_table.snapshot();
+                    if irc.connected_at != session {
+                        session = irc.connected_at;
+                        identities.clear();
+                        playback.clear();
+                        admins.clear();
+                        for route in &routes {
+                            route.channel.lock().unwrap_or_else(|e| e.into_inner()).clear();
+                        }
+                    }
+                    match received {
+                        Ok(raw) => {
+                            health_recv.alive();
+                            let line = Message::parse(&raw);
+                            if let Some(line) = &line {
+                                if line.command == "PING" {
+                                    let _ = irc.send(Command::Pong(line.params.last().map(|t| t.as_str()).unwrap_or("")));
                                     continue;
                                 }
-                                set.insert(m.clone());
-                                let content = m.strip_prefix("[AMZ]").map(|s| s.to_string()).unwrap_or_else(|| m.clone());
-                                if !content.starts_with("[IRC]") {
-                                    let transformed = if let Some((user, msg)) = content.split_once(": ") {
-                                        format!("\x02\x0311{} >\x02\x03 {}", user.trim(), msg.trim())
-                                    } else {
-                                        content.clone()
-                                    };
-                                    let _ = polling_tx.send((irc_chan_poll.clone(), transformed)).await;
+                                if line.command == "PONG" {
+                                    if let Some(token) = line.params.last() {
+                                        irc.state().keepalive.acknowledge(token);
+                                    }
+                                    continue;
+                                }
+                                if line.command == "CAP" {
+                                    let request = cap_change(line, &wanted_caps, &mut irc.state());
+                                    if let Some(request) = request {
+                                        let _ = irc.send(Command::CapReq(&request));
+                                    }
+                                    continue;
+                                }
+                                if line.command == "ERROR" {
+                                    let reason = line.params.last().cloned().unwrap_or_default();
+                                    logging::warn("irc-closed", format!("IRC server closed the link: {}", reason));
+                                    irc.state().closed = Some(reason);
+                                    continue;
+                                }
+                                match nick_news(line, &irc_recv.nick, &irc.nick()) {
+                                    Some(NickNews::Changed(nick)) => {
+                                        if same_nick(&irc.nick(), &irc_recv.nick) {
+                                            // Not asked for: services or the server renamed us,
+                                            // e.g. to a guest nick.
+                                            logging::warn("irc-nick", format!("The server renamed the bridge to {}", nick));
+                                            match irc_recv.regain_nick {
+                                                NickRegain::Monitor if irc.monitor => {
+                                                    let _ = irc.send(Command::Monitor { add: true, nick: &irc_recv.nick });
+                                                }
+                                                NickRegain::Off => {}
+                                                _ => {
+                                                    let _ = irc.send(Command::Ison(&irc_recv.nick));
+                                                }
+                                            }
+                                        } else if same_nick(&nick, &irc_recv.nick) {
+                                            logging::info("irc-nick", format!("Got the nick {} back", nick));
+                                            if irc.monitor && irc_recv.regain_nick == NickRegain::Monitor {
+                                                let _ = irc.send(Command::Monitor { add: false, nick: &nick });
+                                            }
+                                        }
+                                        irc.state().nick = nick;
+                                    }
+                                    Some(NickNews::Free) => {
+                                        let _ = irc.send(Command::Nick(&irc_recv.nick));
+                                        continue;
+                                    }
+                                    None => {}
+                                }
+                                if line.command == "BATCH" {
+                                    playback.observe_batch(&line.params);
+                                    continue;
+                                }
+                                if (line.command == "PRIVMSG" || line.command == "NOTICE")
+                                    && playback.is_replay(line.nick.as_deref(), line.tag("batch"), line.tag("time"), irc.connected_at)
+                                {
+                                    continue;
                                 }
                             }
-                        }
-                        Ok(Err(e)) => eprintln!("Amnezichat pull error: {}", e),
-                        Err(_) => eprintln!("Amnezichat pull timeout"),
-                    }
-                    sleep(Duration::from_secs(1)).await;
-                }
-            });
-        }
 
-        {
-            let client_recv = Arc::clone(&irc_client);
-            let seen_irc_clone = Arc::clone(&seen_irc);
-            let secret_recv = shared_secret.clone();
-            let url_recv = amnezichat_url.clone();
-            let room_recv = room_id.clone();
-            let irc_url_clone = irc_url.clone();
-            let irc_nick_clone = irc_nick.clone();
-            let irc_chan_clone = irc_channel.clone();
-            let sasl_user_clone = sasl_username.clone();
-            let sasl_pass_clone = sasl_password.clone();
-
-            tokio::spawn(async move {
-                loop {
-                    let mut guard = client_recv.lock().await;
-                    match timeout(Duration::from_secs(35), async { guard.receive_message() }).await {
-                        Ok(Ok(raw)) => {
-                            if raw.starts_with("PING") {
-                                let _ = guard.send_raw(&raw.replace("PING", "PONG"));
+                            let own_nick = irc.nick();
+                            let update = line.as_ref().and_then(|l| {
+                                routes.iter().find_map(|route| {
+                                    let mut state = route.channel.lock().unwrap_or_else(|e| e.into_inner());
+                                    Some((l, route, channel_update(l, &mut state, &route.mapping.channel, &own_nick, irc.casemapping)?))
+                                })
+                            });
+                            if let Some((line, route, update)) = update {
+                                let channel = &route.mapping.channel;
+                                if let ChannelUpdate::Rejoin(delay) = update {
+                                    let link = Arc::clone(&link_recv);
+                                    let channel = channel.clone();
+                                    spawn_until(cancel_recv.clone(), async move {
+                                        sleep(delay).await;
+                                        let _ = link.current().send(Command::Join(&channel));
+                                    });
+                                }
+                                if line.command == "JOIN" && who_on_join && irc.whox {
+                                    let _ = irc.send(Command::Who { mask: channel, fields: "%na" });
+                                }
+                                if let Some(notice) = update.notice(line, channel) {
+                                    logging::log(logging::Level::Info, "irc-channel", Context { channel: Some(channel), ..Context::default() }, &notice);
+                                    if relay_to_room {
+                                        let notice = to_room(&notice);
+                                        sender.post(route, format!("* {}", notice)).await;
+                                    }
+                                }
                                 continue;
                             }
 
-                            if let Some((target, msg, nick)) = parse_irc_message(&raw) {
-                                let key = format!("{}:{}", nick, msg);
+                            if let Some(line) = line.as_ref().filter(|l| l.command == "NICK" || l.command == "QUIT") {
+                                if let Some(old) = &line.nick {
+                                    admins.forget(old);
+                                }
+                            }
+                            if let Some(line) = &line {
+                                track_operators(line, &routes, irc.casemapping);
+                            }
+
+                            if identify_policy != IdentifyPolicy::Off {
+                                if let Some(line) = &line {
+                                    match line.command.as_str() {
+                                        "330" if line.params.len() >= 3 => {
+                                            identities.record_account(&line.params[1], &line.params[2]);
+                                        }
+                                        "318" if line.params.len() >= 2 => {
+                                            let nick = &line.params[1];
+                                            let (status, held) = identities.complete(nick);
+                                            if let Some(unverified) = relay_decision(identify_policy, Some(&status)) {
+                                                for held in held {
+                                                    let Some(route) = route_for(&routes, &held.target, irc.casemapping) else { continue };
+                                                    let label = if held.notice {
+                                                        notice_label(nick, unverified)
+                                                    } else {
+                                                        route.backlog.lock().unwrap_or_else(|e| e.into_inner()).record(Side::Irc, nick, &held.text);
+                                                        sender_label(nick, unverified)
+                                                    };
+                                                    if let Some(label) = same_person.label(nick, label) {
+                                                        sender.relay(route, label, held.text).await;
+                                                    }
+                                                }
+                                            }
+                                        }
+                                        "ACCOUNT" => {
+                                            if let (Some(nick), Some(account)) = (&line.nick, line.params.first()) {
+                                                identities.set_account(nick, (account != "*").then_some(account.as_str()));
+                                            }
+                                        }
+                                        // extended-join: channel, account, realname.
+                                        "JOIN" if line.params.len() >= 3 => {
+                                            if let Some(nick) = &line.nick {
+                                                identities.set_account(nick, (line.params[1] != "*").then_some(line.params[1].as_str()));
+                                            }
+                                        }
+                                        // The WHOX reply to `%na`: nick, then account or 0.
+                                        "354" if line.params.len() >= 3 => {
+                                            identities.set_account(&line.params[1], (line.params[2] != "0").then_some(line.params[2].as_str()));
+                                        }
+                                        "NICK" | "QUIT" => {
+                                            if let Some(old) = &line.nick {
+                                                identities.invalidate(old);
+                                            }
+                                            if let Some(new) = line.params.first().filter(|_| line.command == "NICK") {
+                                                identities.invalidate(new);
+                                            }
+                                        }
+                                        _ => {}
+                                    }
+                                }
+                            }
+
+                            if irc_recv.presence && relay_to_room {
+                                if let Some(update) = line.as_ref().and_then(presence_update) {
+                                    // Away and account changes aren't tied to
+                                    // a channel, so every room hears of them.
+                                    let update = to_room(&update);
+                                    for route in &routes {
+                                        sender.post(route, format!("* {}", update)).await;
+                                    }
+                                    continue;
+                                }
+                            }
+
+                            if let Some(ChatMessage { kind, target, text, nick }) = parse_irc_message(&raw) {
+                                // Our own lines come back with echo-message or
+                                // through a bouncer; relaying them would loop.
+                                if same_nick(&nick, &own_nick) {
+                                    continue;
+                                }
+                                // Nor are room messages we posted with RELAYMSG.
+                                let relayed_by = line.as_ref().and_then(|l| l.tag("draft/relaymsg"));
+                                if relayed_by.is_some_and(|by| same_nick(by, &own_nick)) || irc.relaymsg().is_some_and(|s| is_relay_nick(&nick, s)) {
+                                    continue;
+                                }
+                                let Some(route) = route_for(&routes, &target, irc.casemapping) else { continue };
+                                // Who sent it, for admin logins.
+                                let mask = line.as_ref().and_then(|l| l.prefix.clone()).unwrap_or_else(|| nick.clone());
+                                health_recv.bridged();
+                                // Private notices are services chatter, not
+                                // for any room.
+                                if kind == MessageKind::Notice && !(relay_notices && is_channel(&target) && is_user_notice(&nick, &text, &own_nick)) {
+                                    continue;
+                                }
+                                let mut msg = to_room(&text);
+                                transform_recv.apply(Direction::IrcToAmnezichat, &mut msg);
+                                if msg.is_empty() {
+                                    continue;
+                                }
+                                if let Some(FloodVerdict::Drop { first }) = flood.as_mut().map(|f| f.check(&nick, Instant::now())) {
+                                    if first && flood_notice {
+                                        let _ = irc.send(Command::Notice {
+                                            target: &nick,
+                                            text: "You are sending messages too fast; some are not being relayed to Amnezichat.",
+                                        });
+                                    }
+                                    continue;
+                                }
+                                // File transfers can't cross a text bridge, and
+                                // the offer itself is only control data.
+                                if kind == MessageKind::Privmsg && ctcp_command(&text).is_some_and(|c| c.eq_ignore_ascii_case("DCC")) {
+                                    if !is_channel(&target) {
+                                        let _ = irc.send(Command::Notice { target: &nick, text: DCC_DECLINE });
+                                    }
+                                    continue;
+                                }
+                                if kind == MessageKind::Privmsg && !is_channel(&target) && admins.enabled() {
+                                    let command = parse_command(&text, &command_prefix, &own_nick)
+                                        .filter(|(c, _)| c == "bridge" && !commands::is_disabled(c, &disabled_commands));
+                                    if let Some((_, args)) = command {
+                                        for reply in administer(admin::parse(&args), &mask, &mut admins, &route_table, &poller_recv, &irc) {
+                                            let _ = irc.send(Command::Notice { target: &nick, text: &reply });
+                                        }
+                                        continue;
+                                    }
+                                }
+
+                                let command = Some(&msg)
+                                    .filter(|_| kind == MessageKind::Privmsg)
+                                    .and_then(|msg| parse_command(msg, &command_prefix, &own_nick))
+                                    .filter(|(c, _)| !commands::is_disabled(c, &disabled_commands));
+                                if let Some((command, args)) = command {
+                                    if command == "amnezichat" {
+                                        let response = format!("{}: Anti-forensic and secure messenger. Source code: https://github.com/Amnezichat/Amnezichat", nick);
+                                        let _ = irc.send_message(reply_target(&target, &nick), &response);
+                                        continue;
+                                    }
+                                    if command == "roomid" {
+                                        // Always privately: the room id is what
+                                        // it takes to join the room.
+                                        let reply = match room_id_for(&routes, route, &target, &args, irc.casemapping) {
+                                            Some(route) if admins.is_admin(&mask, Instant::now()) || route.channel.lock().unwrap_or_else(|e| e.into_inner()).is_operator(&nick) => {
+                                                format!("{} is bridged to room {}", route.mapping.channel, route.mapping.room_id)
+                                            }
+                                            Some(route) => format!("Only operators of {} and bridge admins can see its room id.", route.mapping.channel),
+                                            None => "Usage: roomid [#channel]".to_string(),
+                                        };
+                                        let _ = irc.send(Command::Notice { target: &nick, text: &reply });
+                                        continue;
+                                    }
+                                    if command == "log" && log_size > 0 {
+                                        // Once a minute per nick, so nobody can get
+                                        // the bridge flooded off by asking over and over.
+                                        let now = Instant::now();
+                                        log_requests.retain(|_, at| now.duration_since(*at) < backlog::LOG_COOLDOWN);
+                                        if log_requests.contains_key(&nick.to_lowercase()) {
+                                            continue;
+                                        }
+                                        log_requests.insert(nick.to_lowercase(), now);
+                                        // Always privately, so catching up doesn't flood
+                                        // the channel.
+                                        let mut lines = match backlog::parse_count(&args) {
+                                            Some(count) => route.backlog.lock().unwrap_or_else(|e| e.into_inner()).last(count.min(log_size).min(backlog::MAX_LOG_LINES)),
+                                            None => vec!["Usage: log [count]".to_string()],
+                                        };
+                                        if lines.is_empty() {
+                                            lines.push("Nothing has been bridged yet.".to_string());
+                                        }
+                                        // Paced like replayed history, without holding
+                                        // up the receive loop.
+                                        let (irc, nick) = (irc.clone(), nick.clone());
+                                        spawn_until(cancel_recv.clone(), async move {
+                                            for (n, line) in lines.iter().enumerate() {
+                                                if n > 0 {
+                                                    sleep(HISTORY_PACE).await;
+                                                }
+                                                let _ = irc.send(Command::Notice { target: &nick, text: graphemes::truncate(line, MAX_MESSAGE_CHARS) });
+                                            }
+                                        });
+                                        continue;
+                                    }
+                                }
+
+                                if msg.starts_with("[AMZ]") || !relay_to_room {
+                                    continue;
+                                }
+
+                                // After the commands, so asking twice still
+                                // gets two answers.
+                                let key = format!("{}:{}:{}", target, nick, msg);
                                 let mut set = seen_irc_clone.lock().await;
                                 if set.contains(&key) {
                                     continue;
                                 }
                                 set.insert(key.clone());
+                                drop(set);
 
-                                if msg.trim() == ".amnezichat" {
-                                    let response = format!("{}: Anti-forensic and secure messenger. Source code: https://github.com/Amnezichat/Amnezichat", nick);
-                                    let _ = guard.send_message(&target, &response);
+                                let status = identities.status(&nick).cloned();
+                                if identify_policy != IdentifyPolicy::Off && status.is_none() {
+                                    if identities.hold(&nick, &target, &msg, kind == MessageKind::Notice, Instant::now()) {
+                                        let _ = irc.send(Command::Whois(&nick));
+                                    }
                                     continue;
                                 }
 
-                                if !msg.starts_with("[AMZ]") {
-                                    let formatted = format!("[IRC]<strong>{}</strong>: {}", nick, msg);
-                                    match encrypt_data(&formatted, &secret_recv) {
-                                        Ok(enc) => {
-                                            if let Err(e) = timeout(Duration::from_secs(5), send_encrypted_message(&enc, &room_recv, &url_recv)).await {
-                                                eprintln!("Amnezichat send timeout or failure: {}", e);
-                                            }
+                                if let Some(unverified) = relay_decision(identify_policy, status.as_ref()) {
+                                    if kind == MessageKind::Notice {
+                                        if let Some(label) = same_person.label(&nick, notice_label(&nick, unverified)) {
+                                            sender.relay(route, label, msg).await;
                                         }
-                                        Err(e) => eprintln!("Encryption error: {}", e),
+                                        continue;
+                                    }
+                                    let msg = match &route.replies {
+                                        Some(replies) => {
+                                            let mut replies = replies.lock().unwrap_or_else(|e| e.into_inner());
+                                            let annotated = replies.annotate(&msg, Instant::now());
+                                            replies.record(&nick, &msg, Instant::now());
+                                            annotated.unwrap_or(msg)
+                                        }
+                                        None => msg,
+                                    };
+                                    route.backlog.lock().unwrap_or_else(|e| e.into_inner()).record(Side::Irc, &nick, &msg);
+                                    if let Some(label) = same_person.label(&nick, sender_label(&nick, unverified)) {
+                                        sender.relay(route, label, msg).await;
                                     }
                                 }
                             }
                         }
-                        Ok(Err(e)) => {
-                            eprintln!("Error receiving message: {:?}", e);
-                            drop(guard);
-                            reconnect_irc(&client_recv, &irc_url_clone, &irc_nick_clone, &irc_chan_clone, sasl_user_clone.clone(), sasl_pass_clone.clone()).await;
-                        }
-                        Err(_) => {
-                            eprintln!("Receive message timed out. Reconnecting...");
-                            drop(guard);
-                            reconnect_irc(&client_recv, &irc_url_clone, &irc_nick_clone, &irc_chan_clone, sasl_user_clone.clone(), sasl_pass_clone.clone()).await;
+                        Err(e) => {
+                            if stopping_recv.load(Ordering::SeqCst) {
+                                break;
+                            }
+                            if health_recv.is_dormant() {
+                                // Left on purpose; nobody needs telling.
+                                health_recv.reconnecting();
+                                health_recv.woken().await;
+                                logging::info("irc-idle", "A room message is waiting; connecting to IRC again.");
+                                let quiet = RoomStatus { enabled: false, ..status_recv.clone() };
+                                incoming = reconnect_irc(&link_recv, &irc_recv, &route_table, Backoff::default(), &quiet, &health_recv, &cancel_recv).await;
+                                continue;
+                            }
+                            logging::warn("irc-receive", format!("Error receiving message: {:?}", e));
+                            incoming = reconnect_irc(&link_recv, &irc_recv, &route_table, Backoff::default(), &status_recv, &health_recv, &cancel_recv).await;
                         }
                     }
                 }
-            });
+            }));
         }
 
         {
-            let client_send = Arc::clone(&irc_client);
-            tokio::spawn(async move {
-                while let Some((tgt, msg)) = rx.recv().await {
-                    let mut guard = client_send.lock().await;
-                    let _ = guard.send_message(&tgt, &msg);
+            let link_send = Arc::clone(&link);
+            let stopping_send = Arc::clone(&stopping);
+            let queue_send = Arc::clone(&queue);
+            let spool_send = spool.clone();
+            let routes_send = routes.clone();
+            tasks.push(spawn_until(cancel.clone(), async move {
+                loop {
+                    let line = queue_send.pop().await;
+                    // Room content only ever goes to a mapped channel, so a
+                    // routing bug can't post it anywhere else.
+                    if !routes_send.bridges(&line.target, link_send.current().casemapping) {
+                        let context = Context { channel: Some(&line.target), direction: Some("amnezichat-to-irc"), ..Context::default() };
+                        log_error_in("irc-send", context, "Dropping a room message for a channel that isn't bridged");
+                        if let (Some(spool), Some(path)) = (&spool_send, &line.spooled) {
+                            spool.done(path);
+                        }
+                        continue;
+                    }
+                    // Held messages wait here (and back up the queue) while
+                    // IRC is being reconnected.
+                    loop {
+                        let irc = link_send.current();
+                        if irc.state().sends_held && !stopping_send.load(Ordering::SeqCst) {
+                            sleep(SEND_RETRY).await;
+                            continue;
+                        }
+                        // A write that fails, maybe partway through the line,
+                        // closes the connection; the line then goes out again
+                        // whole on the next one.
+                        if irc.send_line(&line).is_ok() && irc.flush().await {
+                            if let (Some(spool), Some(path)) = (&spool_send, &line.spooled) {
+                                spool.done(path);
+                            }
+                            break;
+                        }
+                        if stopping_send.load(Ordering::SeqCst) {
+                            break;
+                        }
+                        sleep(SEND_RETRY).await;
+                    }
                 }
-            });
+            }));
         }
 
-        {
-            let client_ping = Arc::clone(&irc_client);
-            let irc_url_clone = irc_url.clone();
-            let irc_nick_clone = irc_nick.clone();
-            let irc_chan_clone = irc_channel.clone();
-            let sasl_user_clone = sasl_username.clone();
-            let sasl_pass_clone = sasl_password.clone();
-
-            tokio::spawn(async move {
+        if irc.regain_nick != NickRegain::Off {
+            let link_nick = Arc::clone(&link);
+            let health_nick = Arc::clone(&health);
+            let (wanted, regain) = (irc.nick.clone(), irc.regain_nick);
+            tasks.push(spawn_until(cancel.clone(), async move {
+                // The receive loop sends NICK once the server says the nick
+                // is free; this only asks it to.
+                let mut monitoring = None;
+                let mut last_ison: Option<Instant> = None;
                 loop {
-                    sleep(Duration::from_secs(60)).await;
-                    let mut guard = client_ping.lock().await;
-                    if let Err(e) = guard.send_raw("PING :keepalive\r\n") {
-                        eprintln!("Failed to send keep-alive PING: {}", e);
-                        drop(guard);
-                        reconnect_irc(&client_ping, &irc_url_clone, &irc_nick_clone, &irc_chan_clone, sasl_user_clone.clone(), sasl_pass_clone.clone()).await;
+                    let irc = link_nick.current();
+                    if health_nick.irc_connected.load(Ordering::SeqCst) && !same_nick(&irc.nick(), &wanted) {
+                        if regain == NickRegain::Monitor && irc.monitor {
+                            if monitoring != Some(irc.connected_at) {
+                                monitoring = Some(irc.connected_at);
+                                let _ = irc.send(Command::Monitor { add: true, nick: &wanted });
+                            }
+                        } else if last_ison.is_none_or(|at| at.elapsed() >= ISON_INTERVAL) {
+                            last_ison = Some(Instant::now());
+                            let _ = irc.send(Command::Ison(&wanted));
+                        }
                     }
+                    sleep(WATCHDOG_TICK).await;
                 }
-            });
+            }));
         }
 
-        Ok(Bridge { irc_client, tx, seen_amz, seen_irc })
-    }
-}
-
-async fn reconnect_irc(
-    client: &Arc<Mutex<CustomIrcClient>>,
-    server: &str,
-    nick: &str,
-    channel: &str,
-    sasl_username: Option<String>,
-    sasl_password: Option<String>,
-) {
-    let mut delay_secs = 5;
-    loop {
-        match CustomIrcClient::connect_and_auth(server, nick, channel, sasl_username.as_deref(), sasl_password.as_deref()) {
-            Ok(newc) => {
-                let mut guard = client.lock().await;
-                *guard = newc;
-                eprintln!("Reconnected to IRC.");
-                break;
-            }
-            Err(e) => {
-                eprintln!("Reconnect failed: {}. Retrying in {}s...", e, delay_secs);
-                sleep(Duration::from_secs(delay_secs)).await;
-                delay_secs = (delay_secs * 2).min(60);
-            }
+        if spool.is_some() && options.relay_irc_to_amnezichat {
+            let sender_spool = status.sender.clone();
+            tasks.push(spawn_until(cancel.clone(), async move {
+                // Posts left from an earlier run first; after that, posts
+                // that failed here and have had time to be through.
+                let mut before = started;
+                loop {
+                    sender_spool.resend_spooled(before).await;
+                    sleep(SPOOL_RETRY).await;
+                    before = SystemTime::now() - SPOOL_RETRY;
+                }
+            }));
         }
-    }
-}
-
-pub struct CustomIrcClient {
-    stream: TcpStream,
-    reader: BufReader<TcpStream>,
-}
 
-impl CustomIrcClient {
-    pub fn new(server_url: &str) -> io::Result<Self> {
-        let stream = TcpStream::connect(server_url)?;
-        stream.set_read_timeout(Some(Duration::from_secs(60)))?;
-        let reader = BufReader::new(stream.try_clone()?);
-        Ok(Self { stream, reader })
-    }
+        {
+            let link_ping = Arc::clone(&link);
+            let stopping_ping = Arc::clone(&stopping);
+            let health_ping = Arc::clone(&health);
 
-    pub fn connect_and_auth(
-        server_url: &str,
-        nick: &str,
-        channel: &str,
-        sasl_username: Option<&str>,
-        sasl_password: Option<&str>,
-    ) -> io::Result<Self> {
-        let mut c = Self::new(server_url)?;
+            let idle_timeout = options.idle_timeout;
+            let max_missed_pongs = options.max_missed_pongs;
 
-        if let (Some(user), Some(pass)) = (sasl_username, sasl_password) {
-            c.send_raw("CAP REQ :sasl\r\n")?;
-            loop {
-                let line = c.receive_message()?;
-                if line.contains("CAP") && line.contains("ACK") && line.contains("sasl") {
-                    break;
+            tasks.push(spawn_until(cancel.clone(), async move {
+                let mut last_ping = Instant::now();
+                loop {
+                    sleep(WATCHDOG_TICK).await;
+                    if stopping_ping.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    // Another task is already reconnecting, possibly waiting
+                    // out a ban; a stale connection is expected meanwhile.
+                    if !health_ping.irc_connected.load(Ordering::SeqCst) {
+                        continue;
+                    }
+                    // Closing the connection is enough: the receive task
+                    // notices and reconnects.
+                    let irc = link_ping.current();
+                    let mut state = irc.state();
+                    let silent_for = state.last_received.elapsed();
+                    let ping_due = last_ping.elapsed() >= KEEPALIVE_INTERVAL;
+                    match state.keepalive.check(Instant::now(), silent_for, idle_timeout, ping_due, max_missed_pongs) {
+                        Watchdog::Wait => continue,
+                        Watchdog::Dead(reason) => {
+                            logging::warn("irc-keepalive", format!("{}. Reconnecting...", reason));
+                            irc.close();
+                            continue;
+                        }
+                        Watchdog::Ping => {}
+                    }
+                    last_ping = Instant::now();
+                    let token = format!("amz-{:016x}", rand::random::<u64>());
+                    state.keepalive.outstanding = Some((token.clone(), Instant::now()));
+                    drop(state);
+                    if let Err(e) = irc.send(Command::Ping(&token)) {
+                        logging::warn("irc-keepalive", format!("Failed to send keep-alive PING: {}", e));
+                        irc.close();
+                    }
                 }
-            }
+            }));
+        }
 
-            c.send_raw("AUTHENTICATE PLAIN\r\n")?;
-            loop {
-                let line = c.receive_message()?;
-                if line.trim() == "AUTHENTICATE +" {
-                    break;
+        {
+            let link_join = Arc::clone(&link);
+            let routes_join = routes.clone();
+            let health_join = Arc::clone(&health);
+            tasks.push(spawn_until(cancel.clone(), async move {
+                loop {
+                    sleep(JOIN_TICK).await;
+                    if !health_join.irc_connected.load(Ordering::SeqCst) {
+                        continue;
+                    }
+                    for route in routes_join.snapshot() {
+                        let channel = &route.mapping.channel;
+                        let retry = route.channel.lock().unwrap_or_else(|e| e.into_inner()).join_retry(Instant::now());
+                        match retry {
+                            Some(JoinRetry::Again) => {
+                                logging::info("irc-join", format!("{} not joined yet; sending JOIN again", channel));
+                                let _ = link_join.current().send(Command::Join(channel));
+                            }
+                            Some(JoinRetry::GiveUp(reason)) => {
+                                let reason = reason.map(|r| format!(": {}", r)).unwrap_or_else(|| ": the server never confirmed the JOIN".to_string());
+                                let context = Context { channel: Some(channel), ..Context::default() };
+                                log_error_in("irc-join", context, format!("Cannot join {}{}; nothing is bridged there", channel, reason));
+                            }
+                            None => {}
+                        }
+                    }
                 }
-            }
-
-            let auth_str = format!("\0{}\0{}", user, pass);
-            let auth_base64 = general_purpose::STANDARD.encode(auth_str);
-            c.send_raw(&format!("AUTHENTICATE {}\r\n", auth_base64))?;
+            }));
+        }
 
-            loop {
-                let line = c.receive_message()?;
-                if line.contains("903") {
-                    break;
-                } else if line.contains("904") || line.contains("905") {
-                    return Err(io::Error::new(io::ErrorKind::PermissionDenied, "SASL authentication failed"));
+        if let Some(after) = options.idle_disconnect {
+            let link_idle = Arc::clone(&link);
+            let health_idle = Arc::clone(&health);
+            tasks.push(spawn_until(cancel.clone(), async move {
+                loop {
+                    sleep(IDLE_TICK).await;
+                    if !health_idle.irc_connected.load(Ordering::SeqCst) || !health_idle.doze() {
+                        continue;
+                    }
+                    logging::info("irc-idle", format!("Nothing bridged for {:?}; leaving IRC until a room message needs relaying.", after));
+                    // Room messages wait for the next connection; the receive
+                    // task makes it once one arrives.
+                    let irc = link_idle.current();
+                    irc.state().sends_held = true;
+                    let _ = irc.send(Command::Quit(IDLE_QUIT_MESSAGE));
+                    let _ = timeout(SHUTDOWN_FLUSH_TIMEOUT, irc.flush()).await;
+                    irc.close();
                 }
-            }
-
-            c.send_raw("CAP END\r\n")?;
+            }));
         }
 
-        c.send_nick(nick)?;
-        c.send_user(nick, "0", "*", nick)?;
-
-        loop {
-            let line = c.receive_message()?;
-            if line.contains("376") || line.contains("422") {
-                break;
-            }
+        if let Some(interval) = options.channel_summary.filter(|_| options.relay_irc_to_amnezichat) {
+            let link_summary = Arc::clone(&link);
+            let routes_summary = routes.clone();
+            let health_summary = Arc::clone(&health);
+            tasks.push(spawn_until(cancel.clone(), async move {
+                loop {
+                    sleep(interval).await;
+                    if !health_summary.irc_connected.load(Ordering::SeqCst) {
+                        continue;
+                    }
+                    // The receive task posts the summary once the NAMES
+                    // reply is complete.
+                    let irc = link_summary.current();
+                    for route in routes_summary.snapshot() {
+                        route.channel.lock().unwrap_or_else(|e| e.into_inner()).request_summary();
+                        let _ = irc.send(Command::Names(&route.mapping.channel));
+                    }
+                }
+            }));
         }
 
-        c.join_channel(channel)?;
-        Ok(c)
+        Ok(Bridge {
+            irc: link,
+            queue,
+            stopping,
+            routes,
+            quit_message: options.quit_message.replace(['\r', '\n'], " "),
+            part_on_quit: options.part_on_quit,
+            status,
+            health,
+            seen_amz,
+            seen_irc,
+            tasks: std::sync::Mutex::new(tasks),
+            cancel,
+        })
     }
 
-    pub fn send_nick(&mut self, nick: &str) -> io::Result<()> {
-        self.send_raw(&format!("NICK {}\r\n", nick))
-    }
+    /// Stops reconnecting, gives queued messages a moment to reach IRC, then
+    /// optionally parts the channels and quits with the configured message.
+    pub async fn shutdown(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
 
-    pub fn send_user(&mut self, user: &str, mode: &str, host: &str, real: &str) -> io::Result<()> {
-        self.send_raw(&format!("USER {} {} {} :{}\r\n", user, mode, host, real))
-    }
+        let deadline = Instant::now() + SHUTDOWN_FLUSH_TIMEOUT;
+        while !self.queue.is_empty() && Instant::now() < deadline {
+            sleep(Duration::from_millis(50)).await;
+        }
 
-    pub fn join_channel(&mut self, chan: &str) -> io::Result<()> {
-        self.send_raw(&format!("JOIN {}\r\n", chan))
+        // Lines reach the writer in order, so the QUIT goes after any
+        // message the send task has taken off the queue.
+        let irc = self.irc.current();
+        if self.part_on_quit {
+            let _ = irc.send(Command::Part { channel: &self.routes.channels().join(","), reason: &self.quit_message });
+        }
+        let _ = irc.send(Command::Quit(&self.quit_message));
+        let _ = timeout(SHUTDOWN_FLUSH_TIMEOUT, irc.flush()).await;
+        let _ = timeout(SHUTDOWN_FLUSH_TIMEOUT, self.status.sender.drain()).await;
+        self.status.post("\u{26a0} IRC bridge shut down").await;
+        let mut tasks = std::mem::take(&mut *self.tasks.lock().unwrap_or_else(|e| e.into_inner()));
+        for route in self.routes.snapshot() {
+            self.stop_mapping(&route.mapping.room_id);
+            tasks.extend(route.poll.lock().unwrap_or_else(|e| e.into_inner()).take());
+        }
+        self.cancel.cancel();
+        for task in tasks {
+            let _ = task.await;
+        }
     }
 
-    pub fn send_message(&mut self, tgt: &str, m: &str) -> io::Result<()> {
-        let clean = m.replace(['\r', '\n'], " ")
-            .chars().take(400).collect::<String>();
-        self.send_raw(&format!("PRIVMSG {} :{}\r\n", tgt, clean))
+    /// Stops bridging the room `room_id`: its poll task ends, and messages
+    /// in its channel are no longer relayed. The bridge stays in the
+    /// channel. Returns whether the room was mapped.
+    pub fn stop_mapping(&self, room_id: &str) -> bool {
+        let stopped = self.routes.stop(|m| m.room_id == room_id);
+        for route in &stopped {
+            logging::log(
+                logging::Level::Info,
+                "mapping-stopped",
+                Context { room_id: Some(room_id), channel: Some(&route.mapping.channel), ..Context::default() },
+                "Stopped bridging",
+            );
+        }
+        !stopped.is_empty()
     }
 
-    pub fn send_raw(&mut self, data: &str) -> io::Result<()> {
-        self.stream.write_all(data.as_bytes())?;
-        self.stream.flush()?;
-        Ok(())
+    /// Round trip of the last answered keep-alive PING.
+    pub fn irc_latency(&self) -> Option<Duration> {
+        self.irc.current().state().keepalive.last_rtt
     }
 
-    pub fn receive_message(&mut self) -> io::Result<String> {
-        let mut buf = String::new();
-        let n = self.reader.read_line(&mut buf)?;
-        if n == 0 {
-            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed"));
+    pub async fn status_snapshot(&self) -> StatusSnapshot {
+        let last_received = self.irc.current().state().last_received;
+        let latency = self.irc_latency();
+        StatusSnapshot {
+            irc: IrcStatus {
+                connected: self.health.irc_connected.load(Ordering::SeqCst),
+                dormant: self.health.is_dormant(),
+                last_received_secs_ago: last_received.elapsed().as_secs(),
+                reconnects: self.health.irc_reconnects.load(Ordering::SeqCst),
+                latency_ms: latency.map(|d| d.as_millis()),
+            },
+            amnezichat: AmnezichatStatus {
+                last_poll_secs_ago: self.health.last_amnezichat_poll().map(|t| t.elapsed().as_secs()),
+            },
+            dedup: DedupStatus { amnezichat: self.seen_amz.lock().await.len(), irc: self.seen_irc.lock().await.len() },
+            queued_to_irc: self.queue.len(),
+            dropped_to_irc: self.queue.dropped(),
         }
-        Ok(buf)
     }
 }
 
-fn parse_irc_message(raw: &str) -> Option<(String, String, String)> {
-    let t = raw.trim();
-    if !t.contains("PRIVMSG") {
-        return None;
+/// Everything a room's poll task needs besides its route, so that rooms
+/// mapped at runtime get one like the rest.
+#[derive(Clone)]
+struct Poller {
+    queue: Arc<OutboundQueue<IrcLine>>,
+    seen: Arc<Mutex<HashSet<String>>>,
+    servers: Arc<ServerList>,
+    stopping: Arc<AtomicBool>,
+    health: Arc<Health>,
+    options: BridgeOptions,
+    /// Lines spooled before `started` are left from an earlier run.
+    spool: Option<Arc<Spool>>,
+    started: SystemTime,
+}
+
+impl Poller {
+    /// Starts polling the route's room, unless room messages aren't relayed
+    /// at all.
+    fn start(&self, route: &Arc<Route>) {
+        if !self.options.relay_amnezichat_to_irc {
+            return;
+        }
+        let task = spawn_until(route.cancel.clone(), self.clone().run(Arc::clone(route)));
+        *route.poll.lock().unwrap_or_else(|e| e.into_inner()) = Some(task);
     }
-    let parts: Vec<&str> = t.splitn(4, ' ').collect();
-    if parts.len() < 4 {
-        return None;
+
+    async fn run(self, route: Arc<Route>) {
+        let Poller { queue, seen, servers, stopping, health, options, spool, started } = self;
+        let BridgeOptions {
+            network,
+            transform,
+            signing_key,
+            label_to_irc,
+            replay_history,
+            multiline,
+            markup: markup_mode,
+            nick_colors,
+            unicode_filter,
+            relay_reactions,
+            idle_poll_interval,
+            ..
+        } = options;
+        let mut delay = POLL_INTERVAL;
+        let mut first_poll = true;
+        let mut reaction_targets = relay_reactions.then(ReactionTargets::new);
+        let Mapping { channel: irc_chan_poll, room_id: room_poll, shared_secret: secret_poll } = &route.mapping;
+        let context = Context { room_id: Some(room_poll), channel: Some(irc_chan_poll), direction: Some("amnezichat-to-irc") };
+        // Lines a previous run took from the room but never sent go first;
+        // the first poll only marks the room's history as seen, so nothing
+        // else would bring them back.
+        for entry in spool.iter().flat_map(|s| s.pending(spool::Destination::Irc, started)) {
+            if entry.room_id != *room_poll {
+                continue;
+            }
+            match decrypt_data(&entry.payload, secret_poll).map_err(|e| e.to_string()).and_then(|json| serde_json::from_str::<IrcLine>(&json).map_err(|e| e.to_string())) {
+                Ok(line) => {
+                    health.wake();
+                    enqueue(&queue, spool.as_deref(), IrcLine { target: irc_chan_poll.clone(), spooled: Some(entry.path), ..line }).await;
+                }
+                Err(e) => log_error_in("spool", context, format!("Cannot read {} from the spool: {}", entry.path.display(), e)),
+            }
+        }
+        while !stopping.load(Ordering::SeqCst) {
+            match timeout(Duration::from_secs(10), receive_and_fetch_messages(room_poll, secret_poll, &servers, false)).await {
+                Ok(Ok(mut events)) => {
+                    log_recovered("amnezichat-poll");
+                    health.polled_amnezichat();
+                    health.alive();
+                    delay = POLL_INTERVAL;
+                    if reaction_targets.is_none() {
+                        events.retain(|e| !matches!(e, RoomEvent::Reaction(_)));
+                    }
+                    // The first poll returns the room's existing
+                    // history; only its last `replay_history`
+                    // messages go to IRC.
+                    let history = std::mem::take(&mut first_poll);
+                    let mut skip = if history { history_to_skip(&events, replay_history) } else { 0 };
+                    // Server order is the only order there is (see
+                    // receive_and_fetch_messages). Everything from here
+                    // to IRC is first in, first out, so it is kept.
+                    for event in events {
+                        let m = event.text();
+                        // Rooms share the set, so the key includes the room.
+                        let key = format!("{}\0{}", room_poll, m);
+                        let mut set = seen.lock().await;
+                        if set.contains(&key) {
+                            continue;
+                        }
+                        set.insert(key);
+                        drop(set);
+                        let content = m.strip_prefix("[AMZ]").unwrap_or(&m);
+                        let verified = match (&signing_key, content.starts_with("[IRC")) {
+                            (Some(key), true) => verify_relay(key, room_poll, content),
+                            _ => Some(content),
+                        };
+                        // Skipped history is remembered too, since it is
+                        // what reactions right after startup refer to.
+                        if let (Some(targets), RoomEvent::Message(_), Some(content)) = (&mut reaction_targets, &event, verified) {
+                            if let Some((user, body)) = reaction_target(content) {
+                                targets.record(&m, &user, &body);
+                            }
+                        }
+                        if skip > 0 {
+                            skip -= 1;
+                            continue;
+                        }
+                        if let (Some(targets), RoomEvent::Reaction(reaction)) = (&reaction_targets, &event) {
+                            let clean = |text: &str| markup::render(&sanitize(Direction::AmnezichatToIrc, text, unicode_filter), MarkupMode::Strip);
+                            let line = targets.describe(&clean(&reaction.user), &clean(&reaction.emoji), &reaction.target);
+                            let line = if history { format!("[history] {}", line) } else { line };
+                            let line = match &label_to_irc {
+                                Some(label) => format!("{} {}", label, line),
+                                None => line,
+                            };
+                            health.wake();
+                            enqueue(&queue, spool.as_deref(), IrcLine::new(irc_chan_poll, line, None).spool(spool.as_deref(), &route.mapping)).await;
+                            continue;
+                        }
+                        let Some(content) = verified else {
+                            log_error_in("relay-signature", context, "Dropping an [IRC] room message without a valid bridge signature");
+                            continue;
+                        };
+                        if let Some(content) = room_message_for_irc(content, network.as_deref()) {
+                            let content = threads::flatten(&content).into_owned();
+                            if let Some((user, body)) = content.split_once(": ") {
+                                let (user, body) = (markup::render(user, MarkupMode::Strip), markup::render(body, MarkupMode::Strip));
+                                if let Some(replies) = &route.replies {
+                                    replies.lock().unwrap_or_else(|e| e.into_inner()).record(&user, &body, Instant::now());
+                                }
+                                route.backlog.lock().unwrap_or_else(|e| e.into_inner()).record(Side::Room, &user, &body);
+                            }
+                            let (user, segments) = irc_segments(&content, multiline, markup_mode, unicode_filter, transform.as_ref());
+                            for segment in segments {
+                                let mut prefix = String::new();
+                                if let Some(label) = &label_to_irc {
+                                    prefix = format!("{} ", label);
+                                }
+                                if history {
+                                    prefix.push_str("[history] ");
+                                }
+                                let text = match &user {
+                                    Some(user) => format!("{}{} {}", prefix, nick_colors.label(user), segment),
+                                    None => format!("{}{}", prefix, segment),
+                                };
+                                let sender = user.clone().map(|user| (user, format!("{}{}", prefix, segment)));
+                                health.wake();
+                                enqueue(&queue, spool.as_deref(), IrcLine::new(irc_chan_poll, text, sender).spool(spool.as_deref(), &route.mapping)).await;
+                                if history {
+                                    sleep(HISTORY_PACE).await;
+                                }
+                            }
+                        }
+                    }
+                }
+                Ok(Err(e)) => {
+                    delay = (delay * 2).min(POLL_BACKOFF_MAX);
+                    log_error_in("amnezichat-poll", context, format!("Amnezichat pull error: {}", e));
+                }
+                Err(_) => {
+                    delay = (delay * 2).min(POLL_BACKOFF_MAX);
+                    log_error_in("amnezichat-poll", context, "Amnezichat pull timeout");
+                }
+            }
+            sleep(if health.is_dormant() { delay.max(idle_poll_interval) } else { delay }).await;
+        }
+    }
+}
+
+/// Carries out a `bridge` command from `mask` (`nick!user@host`) and
+/// returns the replies.
+fn administer(
+    command: Option<AdminCommand>,
+    mask: &str,
+    admins: &mut AdminSessions,
+    routes: &Routes,
+    poller: &Poller,
+    client: &IrcConnection,
+) -> Vec<String> {
+    let reply = match command {
+        None => admin::USAGE.to_string(),
+        Some(AdminCommand::Login(password)) => {
+            if admins.login(mask, &password, Instant::now()) {
+                logging::info("admin", format!("{} logged in to administer the bridge", mask));
+                "Logged in.".to_string()
+            } else {
+                logging::warn("admin", format!("Failed admin login from {}", mask));
+                "Wrong password.".to_string()
+            }
+        }
+        Some(_) if !admins.is_admin(mask, Instant::now()) => "Log in first: bridge login <password>".to_string(),
+        Some(AdminCommand::List) => {
+            return routes.snapshot().iter().map(|r| format!("{} <-> room {}", r.mapping.channel, r.mapping.room_id)).collect();
+        }
+        Some(AdminCommand::Add(mapping)) => {
+            if routes.snapshot().iter().any(|r| client.casemapping.same(&r.mapping.channel, &mapping.channel)) {
+                return vec![format!("{} is already bridged.", mapping.channel)];
+            }
+            if let Err(e) = client.send(Command::Join(&mapping.channel)) {
+                return vec![format!("Cannot join {}: {}", mapping.channel, e)];
+            }
+            logging::info("admin", format!("{} bridged {} to room {}", mask, mapping.channel, mapping.room_id));
+            let reply = format!("Bridging {} to room {}.", mapping.channel, mapping.room_id);
+            let route = Arc::new(Route::new(mapping, &poller.options));
+            poller.start(&route);
+            routes.add(route);
+            reply
+        }
+        Some(AdminCommand::Remove(channel)) => {
+            if routes.snapshot().len() == 1 {
+                return vec!["The last bridged channel can't be removed.".to_string()];
+            }
+            let Some(route) = routes.stop(|m| client.casemapping.same(&m.channel, &channel)).pop() else {
+                return vec![format!("{} is not bridged.", channel)];
+            };
+            let _ = client.send(Command::Part { channel: &route.mapping.channel, reason: "No longer bridged" });
+            logging::info("admin", format!("{} stopped bridging {}", mask, route.mapping.channel));
+            format!("Stopped bridging {}.", route.mapping.channel)
+        }
+    };
+    vec![reply]
+}
+
+/// How many distinct messages at the start of the room's history to leave
+/// out so that only the last `replay` are sent.
+fn history_to_skip(events: &[RoomEvent], replay: usize) -> usize {
+    let mut distinct = HashSet::new();
+    events.iter().filter(|e| distinct.insert(e.text())).count().saturating_sub(replay)
+}
+
+/// Who wrote a room message and what it says, for naming it when someone
+/// reacts to it. `content` is the message as the poller reads it: without
+/// `[AMZ]`, and with any relay signature checked and removed.
+fn reaction_target(content: &str) -> Option<(String, String)> {
+    let content = match content.strip_prefix("[IRC") {
+        Some(tagged) => tagged.split_once(']')?.1,
+        None => content,
+    };
+    let (user, body) = content.split_once(": ")?;
+    Some((markup::render(user, MarkupMode::Strip), markup::render(body, MarkupMode::Strip)))
+}
+
+/// A line waiting for IRC. `sender` holds the room member it is from and
+/// the text without their name, for RELAYMSG; `spooled`, where the line is
+/// kept on disk until it has been sent.
+#[derive(Debug, Serialize, Deserialize)]
+struct IrcLine {
+    target: String,
+    text: String,
+    sender: Option<(String, String)>,
+    #[serde(skip)]
+    spooled: Option<PathBuf>,
+}
+
+impl IrcLine {
+    fn new(target: &str, text: String, sender: Option<(String, String)>) -> Self {
+        IrcLine { target: target.to_string(), text, sender, spooled: None }
+    }
+
+    /// Keeps the line in `spool`, sealed with the room's key, until it is
+    /// sent.
+    fn spool(mut self, spool: Option<&Spool>, mapping: &Mapping) -> Self {
+        let Some(spool) = spool else { return self };
+        let sealed = serde_json::to_string(&self).map_err(|e| e.to_string()).and_then(|json| encrypt_data(&json, &mapping.shared_secret).map_err(|e| e.to_string()));
+        match sealed {
+            Ok(sealed) => self.spooled = spool.store(spool::Destination::Irc, &mapping.room_id, &sealed),
+            Err(e) => logging::warn("spool", format!("Cannot spool a line for {}: {}", mapping.channel, e)),
+        }
+        self
+    }
+}
+
+/// Queues `line` for IRC. A line the queue drops to make room is taken out
+/// of the spool as well, or it would turn up in the channel after the next
+/// restart, out of its place.
+async fn enqueue(queue: &OutboundQueue<IrcLine>, spool: Option<&Spool>, line: IrcLine) {
+    if let Some(dropped) = queue.push(line).await {
+        if let (Some(spool), Some(path)) = (spool, &dropped.spooled) {
+            spool.done(path);
+        }
+    }
+}
+
+/// The nick a room member's messages are relayed under: their name, cut to
+/// what IRC allows in a nick, then `separator` and `RELAYMSG_SUFFIX`. `None`
+/// when nothing of the name is left.
+fn relay_nick(user: &str, separator: char) -> Option<String> {
+    let nick: String = user
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || "_-[]\\`^{}|".contains(*c))
+        .skip_while(|c| c.is_ascii_digit() || *c == '-')
+        .take(MAX_RELAY_NICK)
+        .collect();
+    (!nick.is_empty()).then(|| format!("{}{}{}", nick, separator, RELAYMSG_SUFFIX))
+}
+
+fn is_relay_nick(nick: &str, separator: char) -> bool {
+    nick.strip_suffix(RELAYMSG_SUFFIX).is_some_and(|rest| rest.ends_with(separator))
+}
+
+/// The sender of a decrypted room message, cleaned for IRC, and the lines
+/// of its text.
+fn irc_segments(
+    content: &str,
+    multiline: MultilineMode,
+    markup_mode: MarkupMode,
+    unicode: UnicodeFilter,
+    transform: &dyn MessageTransform,
+) -> (Option<String>, Vec<String>) {
+    let clean_as = |text: &str, mode| {
+        let mut text = sanitize(Direction::AmnezichatToIrc, text, unicode);
+        transform.apply(Direction::AmnezichatToIrc, &mut text);
+        markup::render(&text, mode).trim().to_string()
+    };
+    let clean = |text: &str| clean_as(text, markup_mode);
+    let (user, body) = match content.split_once(": ") {
+        // The nick is already shown in bold and colour.
+        Some((user, body)) => (Some(clean_as(user, MarkupMode::Strip)), body),
+        None => (None, content),
+    };
+
+    let mut segments: Vec<String> = match multiline {
+        MultilineMode::Collapse => vec![clean(body)],
+        MultilineMode::Split => body.lines().map(clean).collect(),
+    };
+    segments.retain(|s| !s.is_empty());
+    if segments.len() > MAX_SPLIT_LINES {
+        let rest = segments.split_off(MAX_SPLIT_LINES - 1).join(" ");
+        segments.push(rest);
+    }
+    (user, segments)
+}
+
+pub fn is_channel(target: &str) -> bool {
+    target.starts_with(['#', '&', '+', '!'])
+}
+
+/// Where to answer a command: the channel it was said in, or the sender
+/// when it arrived as a private query.
+fn reply_target<'a>(target: &'a str, sender: &'a str) -> &'a str {
+    if is_channel(target) {
+        target
+    } else {
+        sender
+    }
+}
+
+/// Decides whether a message from a nick with the given WHOIS status is
+/// relayed. `Some(true)` relays it tagged as unverified.
+fn relay_decision(policy: IdentifyPolicy, status: Option<&AuthStatus>) -> Option<bool> {
+    match (policy, status) {
+        (IdentifyPolicy::Off, _) => Some(false),
+        (_, Some(AuthStatus::Identified(_))) => Some(false),
+        (IdentifyPolicy::Tag, _) => Some(true),
+        (IdentifyPolicy::Drop, _) => None,
+    }
+}
+
+fn sender_label(nick: &str, unverified: bool) -> String {
+    if unverified {
+        format!("{} (unverified)", nick)
+    } else {
+        nick.to_string()
+    }
+}
+
+/// Notices are shown as `-nick-`, as IRC clients do.
+fn notice_label(nick: &str, unverified: bool) -> String {
+    format!("-{}-", sender_label(nick, unverified))
+}
+
+/// Marker put in front of IRC messages relayed into the room.
+fn origin_tag(network: Option<&str>) -> String {
+    match network {
+        Some(name) => format!("[IRC:{}]", name),
+        None => "[IRC]".to_string(),
+    }
+}
+
+/// The origin tag followed by the configured label, put in front of
+/// everything posted to the room.
+fn room_prefix(network: Option<&str>, label: Option<&str>) -> String {
+    match label {
+        Some(label) => format!("{}{} ", origin_tag(network), label),
+        None => origin_tag(network),
+    }
+}
+
+/// Decides whether a room message goes out to IRC. Plain `[IRC]` messages
+/// and those tagged with our own network came from IRC and are dropped;
+/// messages from another network are forwarded with the network as prefix.
+fn room_message_for_irc(content: &str, network: Option<&str>) -> Option<String> {
+    if content.starts_with("[IRC]") {
+        return None;
+    }
+    if let Some((origin, rest)) = content.strip_prefix("[IRC:").and_then(|r| r.split_once(']')) {
+        if network.is_some_and(|own| own.eq_ignore_ascii_case(origin)) {
+            return None;
+        }
+        return Some(format!("[{}] {}", origin, rest));
+    }
+    Some(content.to_string())
+}
+
+/// Posts `msg` in as many room messages as `limit` needs, each with the
+/// origin and label so it is still recognized as a relay. A part the server
+/// refuses as too large is cut in half and tried again.
+async fn relay_irc_message(sender: &RoomSender, label: &str, msg: &str, mapping: &Mapping) {
+    let Mapping { room_id, shared_secret, .. } = mapping;
+    let limit = sender.limit;
+    let mut parts: VecDeque<String> = limit.fit(msg).into();
+    while let Some(part) = parts.pop_front() {
+        let too_large = sender.post_to_room(&format!("{}<strong>{}</strong>: {}", sender.origin, label, part), shared_secret, room_id).await;
+        if too_large && part.len() > MIN_PART_BYTES {
+            logging::warn(
+                "amnezichat-send",
+                format!("The server refused a {} byte message as too large; retrying in smaller parts (see --room-max-bytes)", part.len()),
+            );
+            for smaller in limit.policy.fit(&part, part.len() / 2).into_iter().rev() {
+                parts.push_front(smaller);
+            }
+        }
+    }
+}
+
+/// Posts the bridge's messages to the rooms, at most `--max-room-sends` at
+/// once. The receive loop hands each post off and only waits while every
+/// permit is taken, so a burst from IRC queues here rather than reaching the
+/// server all at once. With a single permit, posts arrive in the order they
+/// were handed off.
+#[derive(Clone)]
+struct RoomSender {
+    origin: String,
+    servers: Arc<ServerList>,
+    signing_key: Option<[u8; 32]>,
+    limit: RoomLimit,
+    permits: Arc<Semaphore>,
+    max_in_flight: u32,
+    spool: Option<Arc<Spool>>,
+    /// Ends posts still in flight once the bridge shuts down.
+    cancel: CancellationToken,
+}
+
+impl RoomSender {
+    fn new(origin: String, servers: Arc<ServerList>, options: &BridgeOptions) -> Self {
+        let max_in_flight = options.max_room_sends.max(1);
+        RoomSender {
+            origin,
+            servers,
+            signing_key: options.signing_key,
+            limit: options.room_limit,
+            permits: Arc::new(Semaphore::new(max_in_flight)),
+            max_in_flight: max_in_flight as u32,
+            spool: None,
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    /// Relays an IRC message from `label` to the route's room.
+    async fn relay(&self, route: &Arc<Route>, label: String, msg: String) {
+        let (sender, route) = (self.clone(), Arc::clone(route));
+        self.spawn(async move {
+            relay_irc_message(&sender, &label, &msg, &route.mapping).await;
+        })
+        .await;
+    }
+
+    /// Posts `text` as is, after the origin tag.
+    async fn post(&self, route: &Arc<Route>, text: String) {
+        let (sender, route) = (self.clone(), Arc::clone(route));
+        self.spawn(async move { sender.send(&route, &text).await }).await;
+    }
+
+    /// Like `post`, but returns only once the post is done.
+    async fn post_now(&self, route: &Route, text: &str) {
+        let _permit = self.permits.acquire().await;
+        self.send(route, text).await;
+    }
+
+    async fn send(&self, route: &Route, text: &str) {
+        let Mapping { room_id, shared_secret, .. } = &route.mapping;
+        self.post_to_room(&format!("{}{}", self.origin, text), shared_secret, room_id).await;
+    }
+
+    /// Encrypts, signs and posts one room message. Returns true only when
+    /// the server refused it as too large; other failures are logged, and
+    /// with `--spool` the message is kept to be posted again later.
+    async fn post_to_room(&self, formatted: &str, secret: &str, room_id: &str) -> bool {
+        let signed;
+        let formatted = match &self.signing_key {
+            Some(key) => {
+                signed = sign_relay(key, room_id, formatted);
+                &signed
+            }
+            None => formatted,
+        };
+        let context = Context { room_id: Some(room_id), direction: Some("irc-to-amnezichat"), ..Context::default() };
+        match encrypt_data(formatted, secret) {
+            Ok(enc) => {
+                let spooled = self.spool.as_ref().and_then(|spool| spool.store(spool::Destination::Room, room_id, &enc));
+                let (delivered, too_large) = match timeout(Duration::from_secs(5), send_encrypted_message(&enc, room_id, &self.servers)).await {
+                    Ok(Ok(())) => {
+                        log_recovered("amnezichat-send");
+                        (true, false)
+                    }
+                    Ok(Err(e)) if e.is::<TooLarge>() => (true, true),
+                    Ok(Err(e)) => {
+                        log_error_in("amnezichat-send", context, format!("Amnezichat send failure: {}", e));
+                        (false, false)
+                    }
+                    Err(_) => {
+                        log_error_in("amnezichat-send", context, "Amnezichat send timeout");
+                        (false, false)
+                    }
+                };
+                // A message refused as too large is posted again in parts,
+                // each spooled on its own.
+                if let (Some(spool), Some(path), true) = (&self.spool, &spooled, delivered) {
+                    spool.done(path);
+                }
+                return too_large;
+            }
+            Err(e) => log_error_in("encryption", context, format!("Encryption error: {}", e)),
+        }
+        false
+    }
+
+    /// Posts the room messages left in the spool from before `before`,
+    /// oldest first, stopping at the first the server doesn't take.
+    async fn resend_spooled(&self, before: SystemTime) {
+        let Some(spool) = &self.spool else { return };
+        for entry in spool.pending(spool::Destination::Room, before) {
+            let sent = match timeout(Duration::from_secs(5), send_encrypted_message(&entry.payload, &entry.room_id, &self.servers)).await {
+                Ok(Ok(())) => true,
+                Ok(Err(e)) if e.is::<TooLarge>() => {
+                    logging::warn("spool", format!("Dropping a spooled message the server refuses as too large for room {}", entry.room_id));
+                    true
+                }
+                _ => false,
+            };
+            if !sent {
+                return;
+            }
+            spool.done(&entry.path);
+        }
+    }
+
+    async fn spawn(&self, send: impl Future<Output = ()> + Send + 'static) {
+        let Ok(permit) = Arc::clone(&self.permits).acquire_owned().await else { return };
+        spawn_until(self.cancel.clone(), async move {
+            send.await;
+            drop(permit);
+        });
+    }
+
+    /// Waits for the posts already handed off.
+    async fn drain(&self) {
+        let _ = self.permits.acquire_many(self.max_in_flight).await;
+    }
+}
+
+/// Delay schedule between reconnect attempts: doubles from `initial` up to
+/// `max`.
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff { initial: Duration::from_secs(5), max: Duration::from_secs(60) }
+    }
+}
+
+/// How the server's `ERROR` reason suggests we come back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CloseKind {
+    /// K/G/Z-lines and similar bans; reconnecting right away only makes
+    /// the ban worse.
+    Banned,
+    /// Reconnecting too fast, too many connections, or killed by an
+    /// operator.
+    Throttled,
+    /// Server restarts, ping timeouts and anything else.
+    Transient,
+}
+
+impl CloseKind {
+    fn classify(reason: &str) -> Self {
+        let reason = reason.to_ascii_lowercase();
+        let has = |words: &[&str]| words.iter().any(|w| reason.contains(w));
+        if has(&["k-line", "g-line", "z-line", "d-line", "kline", "gline", "zline", "dline", "akill", "banned"]) {
+            CloseKind::Banned
+        } else if has(&["throttl", "too fast", "too many", "killed"]) {
+            CloseKind::Throttled
+        } else {
+            CloseKind::Transient
+        }
+    }
+
+    /// Schedule to use instead of the caller's, waiting `initial` before
+    /// even the first attempt; `None` for transient closes.
+    fn backoff(self) -> Option<Backoff> {
+        match self {
+            CloseKind::Banned => Some(Backoff { initial: Duration::from_secs(30 * 60), max: Duration::from_secs(6 * 60 * 60) }),
+            CloseKind::Throttled => Some(Backoff { initial: Duration::from_secs(60), max: Duration::from_secs(10 * 60) }),
+            CloseKind::Transient => None,
+        }
+    }
+}
+
+fn server_error(e: &io::Error) -> Option<&str> {
+    e.get_ref().and_then(|inner| inner.downcast_ref::<ServerError>()).map(|ServerError(reason)| reason.as_str())
+}
+
+/// The bridge's own connection notices for the rooms. They carry the IRC
+/// origin tag, so the bridge doesn't echo them back to IRC.
+#[derive(Clone)]
+struct RoomStatus {
+    enabled: bool,
+    /// Every mapped room; they share the connection these notices are
+    /// about.
+    routes: Routes,
+    sender: RoomSender,
+}
+
+impl RoomStatus {
+    #[cfg(test)]
+    fn disabled() -> Self {
+        RoomStatus {
+            enabled: false,
+            routes: Routes::default(),
+            sender: RoomSender::new(String::new(), Arc::new(ServerList::single("http://127.0.0.1:9")), &BridgeOptions::default()),
+        }
+    }
+
+    async fn post(&self, text: &str) {
+        if !self.enabled {
+            return;
+        }
+        for route in self.routes.snapshot() {
+            self.sender.post_now(&route, text).await;
+        }
+    }
+}
+
+/// Reconnects with `settings`, joining the channels currently in `routes`,
+/// and returns what the new connection reads. The rejoin delay, if any, is
+/// waited out in a task that ends with `cancel`.
+async fn reconnect_irc(
+    link: &IrcLink,
+    settings: &IrcSettings,
+    routes: &Routes,
+    mut backoff: Backoff,
+    status: &RoomStatus,
+    health: &Health,
+    cancel: &CancellationToken,
+) -> Incoming {
+    let settings = &IrcSettings { channels: routes.channels(), ..settings.clone() };
+    health.reconnecting();
+    let closed = link.current().state().closed.take();
+    match &closed {
+        Some(reason) => status.post(&format!("\u{26a0} IRC server closed the link ({}), messages will be queued", reason)).await,
+        None => status.post("\u{26a0} IRC connection lost, messages will be queued").await,
+    }
+    if let Some(slow) = closed.as_deref().and_then(|reason| CloseKind::classify(reason).backoff()) {
+        backoff = slow;
+        logging::warn("irc-reconnect", format!("Waiting {:?} before reconnecting to IRC.", backoff.initial));
+        sleep(backoff.initial).await;
+    }
+    let mut delay = backoff.initial;
+    loop {
+        match CustomIrcClient::connect_and_auth(settings).and_then(CustomIrcClient::start) {
+            Ok((connection, incoming)) => {
+                if settings.rejoin_delay.is_some() || settings.rejoin_announce.is_some() {
+                    connection.state().sends_held = true;
+                    spawn_until(cancel.clone(), finish_rejoin(connection.clone(), settings.clone()));
+                }
+                link.replace(connection);
+                logging::info("irc-reconnect", "Reconnected to IRC.");
+                health.reconnected();
+                status.post("\u{2705} IRC reconnected").await;
+                return incoming;
+            }
+            Err(e) => {
+                if let Some(slow) = server_error(&e).and_then(|reason| CloseKind::classify(reason).backoff()) {
+                    if slow.initial > backoff.initial {
+                        backoff = slow;
+                        delay = delay.max(slow.initial);
+                    }
+                }
+                logging::warn("irc-reconnect", format!("Reconnect failed: {}. Retrying in {:?}...", e, delay));
+                sleep(delay).await;
+                delay = (delay * 2).min(backoff.max);
+            }
+        }
+    }
+}
+
+/// Runs `task` until it ends or `cancel` is cancelled, whichever is first.
+fn spawn_until<F>(cancel: CancellationToken, task: F) -> JoinHandle<()>
+where
+    F: std::future::Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = cancel.cancelled() => {}
+            _ = task => {}
+        }
+    })
+}
+
+/// Waits out the rejoin delay of `connection`, then announces the bridge
+/// and lets queued messages through. If the connection has been replaced
+/// meanwhile, the announcement goes nowhere and the new one has its own.
+async fn finish_rejoin(connection: IrcConnection, settings: IrcSettings) {
+    if let Some(max) = settings.rejoin_delay {
+        let delay = rand::thread_rng().gen_range(max / 2..=max);
+        logging::info("irc-reconnect", format!("Waiting {:?} before sending to IRC again.", delay));
+        sleep(delay).await;
+    }
+    if let Some(text) = &settings.rejoin_announce {
+        for channel in &settings.channels {
+            let _ = connection.send_message(channel, text);
+        }
+    }
+    connection.state().sends_held = false;
+}
+
+/// Pause between Amnezichat polls while the server is healthy; failures
+/// double it up to `POLL_BACKOFF_MAX`.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const POLL_BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// Lets channel operators tell what the bot is.
+pub const DEFAULT_REALNAME: &str = "Amnezichat IRC Bridge - https://github.com/Amnezichat/Amnezichat";
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+pub const DEFAULT_REGISTRATION_TIMEOUT: Duration = Duration::from_secs(30);
+/// PRIVMSG text is cut to this many characters, on a grapheme boundary,
+/// to stay well inside the 512-byte line limit for typical text.
+pub const MAX_MESSAGE_CHARS: usize = 400;
+/// Characters of a room member's name kept in their RELAYMSG nick.
+const MAX_RELAY_NICK: usize = 16;
+/// Gap between replayed history lines, so a long replay doesn't flood.
+const HISTORY_PACE: Duration = Duration::from_millis(500);
+const SEND_RETRY: Duration = Duration::from_secs(1);
+/// How often ISON asks whether the configured nick is free, where the server
+/// has no MONITOR (or `--regain-nick ison`).
+const ISON_INTERVAL: Duration = Duration::from_secs(60);
+/// How often room posts left in the spool are tried again; also how old
+/// one must be, so a post still in flight isn't sent twice.
+const SPOOL_RETRY: Duration = Duration::from_secs(30);
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(60);
+const WATCHDOG_TICK: Duration = Duration::from_secs(10);
+/// How often unconfirmed JOINs are looked at.
+const JOIN_TICK: Duration = Duration::from_secs(1);
+/// How often `--idle-disconnect` checks whether the bridge has gone quiet.
+const IDLE_TICK: Duration = Duration::from_secs(1);
+/// Answer to a DCC offer (a file transfer or chat) sent to the bridge.
+const DCC_DECLINE: &str = "This bridge doesn't accept file transfers or DCC chats; share a link instead.";
+const IDLE_QUIT_MESSAGE: &str = "Idle; back when the room has something to say";
+
+/// Put after the separator in relayed nicks, e.g. `alice/amz`.
+const RELAYMSG_SUFFIX: &str = "amz";
+
+/// The connection every task currently uses. Only the receive task replaces
+/// it, after reconnecting.
+pub struct IrcLink(std::sync::RwLock<IrcConnection>);
+
+impl IrcLink {
+    pub fn new(connection: IrcConnection) -> Self {
+        IrcLink(std::sync::RwLock::new(connection))
+    }
+
+    pub fn current(&self) -> IrcConnection {
+        self.0.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Switches to `connection` and closes the one it replaces.
+    fn replace(&self, connection: IrcConnection) {
+        std::mem::replace(&mut *self.0.write().unwrap_or_else(|e| e.into_inner()), connection).close();
+    }
+}
+
+impl IrcConnection {
+    /// Sends a line from the room, with RELAYMSG when the connection has it
+    /// and the sender's name makes a usable nick.
+    fn send_line(&self, line: &IrcLine) -> io::Result<()> {
+        let relayed = self.relaymsg().filter(|_| is_channel(&line.target)).zip(line.sender.as_ref());
+        match relayed.and_then(|(separator, (user, text))| Some((relay_nick(user, separator)?, text))) {
+            Some((nick, text)) => self.send(Command::Relaymsg { target: &line.target, nick: &nick, text: graphemes::truncate(text, MAX_MESSAGE_CHARS) }),
+            None => self.send_message(&line.target, &line.text),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MessageKind {
+    Privmsg,
+    Notice,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct ChatMessage {
+    kind: MessageKind,
+    target: String,
+    text: String,
+    nick: String,
+}
+
+fn parse_irc_message(raw: &str) -> Option<ChatMessage> {
+    let line = Message::parse(raw)?;
+    let kind = match line.command.as_str() {
+        "PRIVMSG" => MessageKind::Privmsg,
+        "NOTICE" => MessageKind::Notice,
+        _ => return None,
+    };
+    if line.params.len() < 2 {
+        return None;
+    }
+    Some(ChatMessage {
+        kind,
+        target: line.params[0].clone(),
+        text: line.params[1].clone(),
+        nick: line.nick?,
+    })
+}
+
+/// Describes an `AWAY` (away-notify) or `ACCOUNT` (account-notify) line for
+/// the room.
+fn presence_update(line: &Message) -> Option<String> {
+    let nick = line.nick.as_deref()?;
+    match line.command.as_str() {
+        "AWAY" => Some(match line.params.first().filter(|m| !m.is_empty()) {
+            Some(message) => format!("{} is now away: {}", nick, message),
+            None => format!("{} is back", nick),
+        }),
+        "ACCOUNT" => Some(match line.params.first().map(|a| a.as_str()) {
+            Some("*") | None => format!("{} logged out", nick),
+            Some(account) => format!("{} identified as {}", nick, account),
+        }),
+        _ => None,
+    }
+}
+
+/// A change in the bridge's standing in its channel.
+#[derive(Debug, PartialEq, Eq)]
+enum ChannelUpdate {
+    /// Kicked; rejoin after this long.
+    Rejoin(Duration),
+    Rejoined,
+    Mode(MuteChange),
+    CannotSend,
+    /// The server refused the JOIN; it is retried for a while.
+    JoinFailed,
+    /// The requested user count and topic.
+    Summary(usize, Option<String>),
+    /// Tracked, but not worth a notice.
+    Quiet,
+}
+
+impl ChannelUpdate {
+    /// What to tell the room, worded from the line that caused it.
+    fn notice(&self, line: &Message, channel: &str) -> Option<String> {
+        let by = line.nick.as_deref().unwrap_or("the server");
+        Some(match self {
+            ChannelUpdate::Rejoin(delay) => {
+                let reason = line.params.get(2).filter(|r| !r.is_empty()).map(|r| format!(" ({})", r)).unwrap_or_default();
+                format!("The bridge was kicked from {} by {}{}; rejoining in {}s", channel, by, reason, delay.as_secs())
+            }
+            ChannelUpdate::Rejoined => format!("The bridge rejoined {}", channel),
+            ChannelUpdate::Mode(MuteChange::Muted) => {
+                format!("{} is moderated and the bridge has no voice; room messages are not reaching IRC", channel)
+            }
+            ChannelUpdate::Mode(MuteChange::Unmuted) => format!("The bridge can speak in {} again", channel),
+            ChannelUpdate::CannotSend => {
+                let reason = line.params.get(2).map(|r| format!(" ({})", r)).unwrap_or_default();
+                format!("The bridge cannot send to {}{}", channel, reason)
+            }
+            ChannelUpdate::JoinFailed => {
+                let reason = line.params.get(2).map(|r| format!(" ({})", r)).unwrap_or_default();
+                format!("The bridge could not join {}{}; trying again", channel, reason)
+            }
+            ChannelUpdate::Summary(users, topic) => {
+                let users = if *users == 1 { "1 user".to_string() } else { format!("{} users", users) };
+                match topic {
+                    Some(topic) => format!("{}: {}, topic: {}", channel, users, topic),
+                    None => format!("{}: {}, no topic", channel, users),
+                }
+            }
+            ChannelUpdate::Quiet => return None,
+        })
+    }
+}
+
+/// Picks out KICK, MODE, JOIN, 404 and refused JOIN lines about the bridge
+/// (`own_nick`) in `channel`, and the topic and NAMES replies for the
+/// summary.
+fn channel_update(line: &Message, state: &mut ChannelState, channel: &str, own_nick: &str, casemapping: Casemapping) -> Option<ChannelUpdate> {
+    let in_channel = |i: usize| line.params.get(i).is_some_and(|c| casemapping.same(c, channel));
+    let is_self = |nick: Option<&String>| nick.is_some_and(|n| same_nick(n, own_nick));
+    match line.command.as_str() {
+        "KICK" if in_channel(0) && is_self(line.params.get(1)) => Some(ChannelUpdate::Rejoin(state.kicked(Instant::now()))),
+        "JOIN" if in_channel(0) && is_self(line.nick.as_ref()) => {
+            Some(if state.joined() { ChannelUpdate::Rejoined } else { ChannelUpdate::Quiet })
+        }
+        "MODE" if in_channel(0) && line.params.len() >= 2 => {
+            Some(match state.apply_mode(&line.params[1], &line.params[2..], own_nick) {
+                Some(change) => ChannelUpdate::Mode(change),
+                None => ChannelUpdate::Quiet,
+            })
+        }
+        "404" if in_channel(1) => Some(if state.cannot_send() { ChannelUpdate::CannotSend } else { ChannelUpdate::Quiet }),
+        // No such channel, too many channels, full, invite only, banned,
+        // wrong key, registered nicks only, channel name not allowed.
+        "403" | "405" | "471" | "473" | "474" | "475" | "477" | "479" if in_channel(1) => {
+            let first = state.join_failed(line.params.get(2).map_or("", |r| r));
+            Some(if first { ChannelUpdate::JoinFailed } else { ChannelUpdate::Quiet })
+        }
+        "332" | "331" if in_channel(1) => {
+            state.set_topic(line.params.get(2).filter(|_| line.command == "332").map_or("", |t| t));
+            Some(ChannelUpdate::Quiet)
+        }
+        "TOPIC" if in_channel(0) => {
+            state.set_topic(line.params.get(1).map_or("", |t| t));
+            Some(ChannelUpdate::Quiet)
+        }
+        "353" if in_channel(2) => {
+            state.add_names(line.params.get(3).map_or("", |n| n), own_nick);
+            Some(ChannelUpdate::Quiet)
+        }
+        "366" if in_channel(1) => Some(match state.names_done() {
+            Some((users, topic)) => ChannelUpdate::Summary(users, topic),
+            None => ChannelUpdate::Quiet,
+        }),
+        _ => None,
+    }
+}
+
+/// Keeps each channel's operators current as nicks change, leave or quit.
+fn track_operators(line: &Message, routes: &[Arc<Route>], casemapping: Casemapping) {
+    let Some(nick) = &line.nick else { return };
+    let in_channel = |route: &Arc<Route>| line.params.first().is_some_and(|c| casemapping.same(c, &route.mapping.channel));
+    for route in routes {
+        let mut state = route.channel.lock().unwrap_or_else(|e| e.into_inner());
+        match line.command.as_str() {
+            "NICK" => state.renamed(nick, line.params.first().map_or("", |n| n)),
+            "QUIT" => state.left(nick),
+            "PART" if in_channel(route) => state.left(nick),
+            "KICK" if in_channel(route) => state.left(line.params.get(1).map_or("", |n| n)),
+            _ => {}
+        }
+    }
+}
+
+/// The route `.roomid` asks about: the channel it was said in, or in a
+/// private message the channel named, or else the first one.
+fn room_id_for<'a>(routes: &'a [Arc<Route>], route: &'a Arc<Route>, target: &str, args: &str, casemapping: Casemapping) -> Option<&'a Arc<Route>> {
+    match args.split_whitespace().collect::<Vec<_>>()[..] {
+        [] => Some(route),
+        [channel] if !is_channel(target) => route_for(routes, channel, casemapping).filter(|_| is_channel(channel)),
+        _ => None,
+    }
+}
+
+/// What a line from the server means for our nick.
+#[derive(Debug, PartialEq, Eq)]
+enum NickNews {
+    /// We are now known by this nick.
+    Changed(String),
+    /// The nick we want is free to take.
+    Free,
+}
+
+/// What `line` means for getting `wanted` back while our nick is `current`.
+fn nick_news(line: &Message, wanted: &str, current: &str) -> Option<NickNews> {
+    match line.command.as_str() {
+        "NICK" if line.nick.as_deref().is_some_and(|n| same_nick(n, current)) => line.params.first().map(|n| NickNews::Changed(n.clone())),
+        _ if same_nick(current, wanted) => None,
+        // MONITOR: targets that went offline, `nick[!user@host],...`.
+        "731" => line.params.last()?.split(',').any(|t| same_nick(t.split('!').next().unwrap_or(t), wanted)).then_some(NickNews::Free),
+        // ISON: which of the nicks asked about are online.
+        "303" => (!line.params.last()?.split_whitespace().any(|n| same_nick(n, wanted))).then_some(NickNews::Free),
+        _ => None,
+    }
+}
+
+/// Notices worth relaying come from users or services, not from the server
+/// itself, the bridge, or as CTCP replies. The bridge never answers a
+/// notice, so relaying them can't start a loop.
+fn is_user_notice(nick: &str, text: &str, own_nick: &str) -> bool {
+    !nick.contains('.') && !same_nick(nick, own_nick) && !text.starts_with('\x01')
+}
+
+/// The command of a CTCP message (`\x01DCC SEND ...\x01`), if `text` is one.
+fn ctcp_command(text: &str) -> Option<&str> {
+    let body = text.strip_prefix('\x01')?;
+    let body = body.strip_suffix('\x01').unwrap_or(body);
+    body.split(' ').next().filter(|command| !command.is_empty())
+}
+
+/// How the server folds case in nicks and channel names, from `CASEMAPPING`
+/// in ISUPPORT.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Casemapping {
+    Ascii,
+    /// `[]\~` are the upper case forms of `{}|^`; also what a server that
+    /// doesn't say uses.
+    #[default]
+    Rfc1459,
+    /// Like rfc1459 without `~` and `^`.
+    StrictRfc1459,
+}
+
+impl Casemapping {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "ascii" => Some(Casemapping::Ascii),
+            "rfc1459" => Some(Casemapping::Rfc1459),
+            "strict-rfc1459" => Some(Casemapping::StrictRfc1459),
+            _ => None,
+        }
+    }
+
+    pub fn same(self, a: &str, b: &str) -> bool {
+        let fold = |c: char| match (self, c) {
+            (Casemapping::Ascii, c) => c.to_ascii_lowercase(),
+            (_, '[') => '{',
+            (_, ']') => '}',
+            (_, '\\') => '|',
+            (Casemapping::Rfc1459, '~') => '^',
+            (_, c) => c.to_ascii_lowercase(),
+        };
+        a.len() == b.len() && a.chars().map(fold).eq(b.chars().map(fold))
+    }
+}
+
+/// Compares nicks under rfc1459 casemapping, where `[]\~` are the upper
+/// case forms of `{}|^`.
+pub fn same_nick(a: &str, b: &str) -> bool {
+    Casemapping::Rfc1459.same(a, b)
+}
+
+pub fn run_bridge(config: BridgeConfig) -> io::Result<Bridge> {
+    Bridge::new(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose;
+    use base64::Engine;
+    use std::io::Write;
+    use crate::encryption::decrypt_data;
+    use crate::irc::client::is_read_timeout;
+    use crate::irc::stream::IrcStream;
+    use crate::mock_amnezichat::MockAmnezichat;
+    use crate::mock_irc::MockIrcServer;
+    use crate::reactions::{message_id, Reaction};
+    use crate::same_person::SamePersonMode;
+    use crate::transform::NoTransform;
+
+    /// A bridge from `#test` to `room1`, keyed with `"0".repeat(64)`, on
+    /// fresh mock servers; `irc` gets their address and the nick `bridge`.
+    fn start_bridge(options: BridgeOptions, irc: IrcSettings) -> (MockIrcServer, MockAmnezichat, Bridge) {
+        let server = MockIrcServer::start();
+        let room = MockAmnezichat::start();
+        let bridge = Bridge::new(BridgeConfig {
+            mappings: vec![Mapping { channel: "#test".into(), room_id: "room1".into(), shared_secret: "0".repeat(64) }],
+            servers: Arc::new(ServerList::single(&room.url())),
+            irc: IrcSettings { server: server.addr(), nick: "bridge".into(), ..irc },
+            options,
+        })
+        .unwrap();
+        (server, room, bridge)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reconnect_irc_restores_a_working_client() {
+        let server = MockIrcServer::start();
+        let addr = server.socket_addr();
+        let settings = IrcSettings {
+            server: server.addr(),
+            nick: "bridge".into(),
+            channels: vec!["#test".into()],
+            sasl_username: Some("bridge".into()),
+            sasl_password: Some("hunter22".into()),
+            ..IrcSettings::default()
+        };
+
+        let (connection, mut incoming) = CustomIrcClient::connect_and_auth(&settings).unwrap().start().unwrap();
+        assert!(server.wait_for(|l| l == "JOIN #test", Duration::from_secs(2)));
+        let link = IrcLink::new(connection);
+        let routes = Routes::default();
+        let mapping = Mapping { channel: "#test".into(), room_id: "room1".into(), shared_secret: "0".repeat(64) };
+        routes.add(Arc::new(Route::new(mapping, &BridgeOptions::default())));
+
+        server.shutdown();
+        let lost = timeout(Duration::from_secs(2), async {
+            while let Some(Ok(_)) = incoming.recv().await {}
+        });
+        assert!(lost.await.is_ok());
+
+        let restarted = tokio::task::spawn_blocking(move || {
+            std::thread::sleep(Duration::from_millis(150));
+            MockIrcServer::start_with(addr, Arc::new(crate::mock_irc::default_responses))
+        });
+
+        let backoff = Backoff { initial: Duration::from_millis(20), max: Duration::from_millis(80) };
+        let mut incoming = timeout(
+            Duration::from_secs(5),
+            reconnect_irc(&link, &settings, &routes, backoff, &RoomStatus::disabled(), &Health::default(), &CancellationToken::new()),
+        )
+        .await
+        .expect("reconnect should finish once the server is back");
+
+        let server = restarted.await.unwrap();
+        let received = server.received();
+        let auth = general_purpose::STANDARD.encode("\0bridge\0hunter22");
+        assert!(received.contains(&format!("AUTHENTICATE {}", auth)));
+        assert!(received.contains(&"NICK bridge".to_string()));
+        assert!(server.wait_for(|l| l == "JOIN #test", Duration::from_secs(2)));
+
+        server.send(":alice!a@host PRIVMSG #test :hello again");
+        let line = loop {
+            match timeout(Duration::from_secs(2), incoming.recv()).await.expect("a line arrives") {
+                Some(Ok(line)) if line.contains("PRIVMSG") => break line,
+                Some(Ok(_)) => {}
+                other => panic!("receive failed: {:?}", other),
+            }
+        };
+        assert_eq!(
+            parse_irc_message(&line),
+            Some(ChatMessage {
+                kind: MessageKind::Privmsg,
+                target: "#test".into(),
+                text: "hello again".into(),
+                nick: "alice".into(),
+            })
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn no_more_than_max_room_sends_are_in_flight() {
+        let irc = MockIrcServer::start();
+        let room = MockAmnezichat::start();
+        room.delay_sends(Duration::from_millis(300));
+        let bridge = Bridge::new(BridgeConfig {
+            mappings: vec![Mapping { channel: "#test".into(), room_id: "room1".into(), shared_secret: "0".repeat(64) }],
+            servers: Arc::new(ServerList::single(&room.url())),
+            irc: IrcSettings { server: irc.addr(), nick: "bridge".into(), ..IrcSettings::default() },
+            options: BridgeOptions { flood_limit: None, max_room_sends: 2, ..BridgeOptions::default() },
+        })
+        .unwrap();
+        assert!(irc.wait_for(|l| l == "JOIN #test", Duration::from_secs(2)));
+
+        for i in 0..8 {
+            irc.send(&format!(":alice!a@host PRIVMSG #test :burst {}", i));
+        }
+        assert!(room.wait_for_sends(8, Duration::from_secs(10)));
+        assert_eq!(room.peak_sends(), 2);
+        bridge.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn messages_replayed_after_a_reconnect_are_not_bridged_twice() {
+        let secret = "0".repeat(64);
+        let (irc, room, bridge) = start_bridge(BridgeOptions { flood_limit: None, ..BridgeOptions::default() }, IrcSettings::default());
+        assert!(irc.wait_for(|l| l == "JOIN #test", Duration::from_secs(2)));
+
+        irc.send(":alice!a@host PRIVMSG #test :hello");
+        assert!(room.wait_for_sends(1, Duration::from_secs(10)));
+
+        irc.disconnect();
+        assert!(irc.wait_for_count(|l| l == "JOIN #test", 2, Duration::from_secs(10)), "bridge should reconnect and rejoin");
+
+        // A bouncer replays the buffer: once as plain lines, once stamped
+        // with the original server-time.
+        irc.send(":alice!a@host PRIVMSG #test :hello");
+        irc.send("@time=2020-01-01T00:00:00.000Z :alice!a@host PRIVMSG #test :hello");
+        irc.send(":alice!a@host PRIVMSG #test :bye");
+        assert!(room.wait_for_sends(2, Duration::from_secs(10)));
+        // Lines are handled in order, so once "bye" is posted the replays
+        // have been dealt with.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let posted: Vec<String> = room.sent().iter().map(|payload| decrypt_data(payload, &secret).unwrap()).collect();
+        assert_eq!(posted, vec!["[IRC]<strong>alice</strong>: hello".to_string(), "[IRC]<strong>alice</strong>: bye".to_string()]);
+        bridge.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn messages_the_server_refuses_as_too_large_are_split() {
+        let irc = MockIrcServer::start();
+        let room = MockAmnezichat::start();
+        room.limit_body(1000);
+        let secret = "0".repeat(64);
+        let bridge = Bridge::new(BridgeConfig {
+            mappings: vec![Mapping { channel: "#test".into(), room_id: "room1".into(), shared_secret: secret.clone() }],
+            servers: Arc::new(ServerList::single(&room.url())),
+            irc: IrcSettings { server: irc.addr(), nick: "bridge".into(), ..IrcSettings::default() },
+            options: BridgeOptions::default(),
+        })
+        .unwrap();
+        assert!(irc.wait_for(|l| l == "JOIN #test", Duration::from_secs(2)));
+
+        let text = "word ".repeat(120);
+        irc.send(&format!(":alice!a@host PRIVMSG #test :{}", text.trim_end()));
+        assert!(room.wait_for_sends(2, Duration::from_secs(10)));
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let posted: Vec<String> = room.sent().iter().map(|payload| decrypt_data(payload, &secret).unwrap()).collect();
+        let parts: Vec<&str> = posted.iter().map(|p| p.strip_prefix("[IRC]<strong>alice</strong>: ").unwrap()).collect();
+        assert_eq!(parts.join(" "), text.trim_end());
+        bridge.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn spooled_messages_are_delivered_after_a_restart() {
+        let dir = std::env::temp_dir().join(format!("amnezichat-spool-{}", rand::random::<u64>()));
+        let irc = MockIrcServer::start();
+        let secret = "0".repeat(64);
+        let mapping = Mapping { channel: "#test".into(), room_id: "room1".into(), shared_secret: secret.clone() };
+        let config = |servers: ServerList| BridgeConfig {
+            mappings: vec![mapping.clone()],
+            servers: Arc::new(servers),
+            irc: IrcSettings { server: irc.addr(), nick: "bridge".into(), ..IrcSettings::default() },
+            options: BridgeOptions { spool: Some(dir.clone()), ..BridgeOptions::default() },
+        };
+
+        // The room server is down, so the post stays in the spool.
+        let bridge = Bridge::new(config(ServerList::single("http://127.0.0.1:9"))).unwrap();
+        assert!(irc.wait_for(|l| l == "JOIN #test", Duration::from_secs(2)));
+        irc.send(":alice!a@host PRIVMSG #test :sent during the outage");
+        let spool = Spool::open(&dir).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while spool.pending(spool::Destination::Room, SystemTime::now()).is_empty() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        bridge.shutdown().await;
+        assert_eq!(spool.pending(spool::Destination::Room, SystemTime::now()).len(), 1);
+        // And a room message was taken from the room but not yet sent.
+        drop(IrcLine::new("#test", "bob > queued for IRC".into(), None).spool(Some(&spool), &mapping));
+        assert!(!std::fs::read_dir(&dir).unwrap().any(|f| std::fs::read_to_string(f.unwrap().path()).unwrap().contains("queued")));
+
+        let room = MockAmnezichat::start();
+        let bridge = Bridge::new(config(ServerList::single(&room.url()))).unwrap();
+        assert!(room.wait_for_sends(1, Duration::from_secs(10)));
+        assert!(irc.wait_for(|l| l == "PRIVMSG #test :bob > queued for IRC", Duration::from_secs(10)));
+        assert_eq!(decrypt_data(&room.sent()[0], &secret).unwrap(), "[IRC]<strong>alice</strong>: sent during the outage");
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        bridge.shutdown().await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn lines_dropped_from_a_full_queue_leave_the_spool() {
+        let dir = std::env::temp_dir().join(format!("amnezichat-spool-{}", rand::random::<u64>()));
+        let spool = Spool::open(&dir).unwrap();
+        let mapping = Mapping { channel: "#test".into(), room_id: "room1".into(), shared_secret: "0".repeat(64) };
+        for policy in [OverflowPolicy::DropOldest, OverflowPolicy::DropNewest] {
+            let queue = OutboundQueue::new(1, policy);
+            for text in ["first", "second"] {
+                enqueue(&queue, Some(&spool), IrcLine::new("#test", text.into(), None).spool(Some(&spool), &mapping)).await;
+            }
+            let kept = queue.pop().await;
+            let pending = spool.pending(spool::Destination::Irc, SystemTime::now());
+            assert_eq!(pending.iter().map(|e| &e.path).collect::<Vec<_>>(), [kept.spooled.as_ref().unwrap()], "{:?}", policy);
+            spool.done(&pending[0].path);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sends_wait_out_the_rejoin_delay_after_a_reconnect() {
+        let secret = "0".repeat(64);
+        let (irc, room, bridge) = start_bridge(BridgeOptions { nick_colors: NickColors::Off, ..BridgeOptions::default() }, IrcSettings {
+            rejoin_delay: Some(Duration::from_secs(2)),
+            rejoin_announce: Some("Bridge back online".into()),
+            ..IrcSettings::default()
+        });
+        assert!(irc.wait_for(|l| l == "JOIN #test", Duration::from_secs(2)));
+        assert!(room.wait_for_polls(1, Duration::from_secs(5)));
+
+        irc.disconnect();
+        assert!(irc.wait_for_count(|l| l == "JOIN #test", 2, Duration::from_secs(10)), "bridge should reconnect and rejoin");
+        let rejoined = Instant::now();
+        room.publish(encrypt_data("alice: sent while rejoining", &secret).unwrap());
+        assert!(irc.wait_for(|l| l.ends_with(" sent while rejoining"), Duration::from_secs(10)));
+        assert!(rejoined.elapsed() >= Duration::from_millis(900), "the wait is at least half the rejoin delay");
+
+        // Only after a reconnect, and before anything queued meanwhile.
+        let privmsgs: Vec<String> = irc.received().into_iter().filter_map(|l| l.strip_prefix("PRIVMSG #test :").map(str::to_string)).collect();
+        assert_eq!(privmsgs, vec!["Bridge back online", "\x02alice >\x02 sent while rejoining"]);
+        bridge.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn mapped_channels_share_one_connection() {
+        let irc = MockIrcServer::start();
+        let room = MockAmnezichat::start();
+        let (secret_a, secret_b) = ("0".repeat(64), "1".repeat(64));
+        let bridge = Bridge::new(BridgeConfig {
+            mappings: vec![
+                Mapping { channel: "#a".into(), room_id: "roomA".into(), shared_secret: secret_a.clone() },
+                Mapping { channel: "#b".into(), room_id: "roomB".into(), shared_secret: secret_b.clone() },
+            ],
+            servers: Arc::new(ServerList::single(&room.url())),
+            irc: IrcSettings { server: irc.addr(), nick: "bridge".into(), ..IrcSettings::default() },
+            options: BridgeOptions { flood_limit: None, ..BridgeOptions::default() },
+        })
+        .unwrap();
+        assert!(irc.wait_for(|l| l == "JOIN #a,#b", Duration::from_secs(2)));
+
+        irc.send(":alice!a@host PRIVMSG #B :to b");
+        irc.send(":alice!a@host PRIVMSG #elsewhere :not mapped");
+        irc.send(":alice!a@host PRIVMSG #a :to a");
+        assert!(room.wait_for_sends(2, Duration::from_secs(10)));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let posted = |room_id, secret| room.sent_to(room_id).iter().map(|p| decrypt_data(p, secret).unwrap()).collect::<Vec<_>>();
+        assert_eq!(posted("roomA", &secret_a), vec!["[IRC]<strong>alice</strong>: to a".to_string()]);
+        assert_eq!(posted("roomB", &secret_b), vec!["[IRC]<strong>alice</strong>: to b".to_string()]);
+        assert_eq!(irc.received().iter().filter(|l| l.starts_with("NICK ")).count(), 1);
+        bridge.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn an_admin_can_bridge_a_channel_at_runtime() {
+        let irc = MockIrcServer::start();
+        let room = MockAmnezichat::start();
+        let (secret_a, secret_b) = ("0".repeat(64), "1".repeat(64));
+        let bridge = Bridge::new(BridgeConfig {
+            mappings: vec![Mapping { channel: "#a".into(), room_id: "roomA".into(), shared_secret: secret_a }],
+            servers: Arc::new(ServerList::single(&room.url())),
+            irc: IrcSettings { server: irc.addr(), nick: "bridge".into(), ..IrcSettings::default() },
+            options: BridgeOptions {
+                flood_limit: None,
+                nick_colors: NickColors::Off,
+                admin_password: Some("hunter2".into()),
+                ..BridgeOptions::default()
+            },
+        })
+        .unwrap();
+        assert!(irc.wait_for(|l| l == "JOIN #a", Duration::from_secs(2)));
+
+        let add = format!("PRIVMSG bridge :.bridge add #b roomB:{}", secret_b);
+        irc.send(&format!(":eve!e@host {}", add));
+        assert!(irc.wait_for(|l| l == "NOTICE eve :Log in first: bridge login <password>", Duration::from_secs(5)));
+        irc.send(":alice!a@host PRIVMSG bridge :.bridge login hunter2");
+        assert!(irc.wait_for(|l| l == "NOTICE alice :Logged in.", Duration::from_secs(5)));
+        irc.send(&format!(":alice!a@host {}", add));
+        assert!(irc.wait_for(|l| l == "JOIN #b", Duration::from_secs(5)));
+        assert!(irc.wait_for(|l| l == "NOTICE alice :Bridging #b to room roomB.", Duration::from_secs(5)));
+
+        // Let the new room's first poll, which only reads history, go by.
+        sleep(Duration::from_millis(1500)).await;
+        room.publish(encrypt_data("carol: hello #b", &secret_b).unwrap());
+        assert!(irc.wait_for(|l| l == "PRIVMSG #b :\x02carol >\x02 hello #b", Duration::from_secs(10)));
+        irc.send(":dave!d@host PRIVMSG #b :hello roomB");
+        assert!(room.wait_for_sends(1, Duration::from_secs(10)));
+        let sent = room.sent_to("roomB");
+        assert_eq!(decrypt_data(&sent[0], &secret_b).unwrap(), "[IRC]<strong>dave</strong>: hello roomB");
+
+        irc.send(":alice!a@host PRIVMSG bridge :.bridge list");
+        assert!(irc.wait_for(|l| l == "NOTICE alice :#b <-> room roomB", Duration::from_secs(5)));
+        irc.send(":alice!a@host PRIVMSG bridge :.bridge remove #B");
+        assert!(irc.wait_for(|l| l == "PART #b :No longer bridged", Duration::from_secs(5)));
+        irc.send(":alice!a@host PRIVMSG bridge :.bridge remove #a");
+        assert!(irc.wait_for(|l| l == "NOTICE alice :The last bridged channel can't be removed.", Duration::from_secs(5)));
+
+        // Nor does it pass to someone else under the nick, though the
+        // bridge never saw alice leave.
+        irc.send(":alice!eve@elsewhere PRIVMSG bridge :.bridge list");
+        assert!(irc.wait_for(|l| l == "NOTICE alice :Log in first: bridge login <password>", Duration::from_secs(5)));
+
+        // A login doesn't follow the nick.
+        irc.send(":alice!a@host NICK alice2");
+        irc.send(":alice2!a@host PRIVMSG bridge :.bridge list");
+        assert!(irc.wait_for(|l| l == "NOTICE alice2 :Log in first: bridge login <password>", Duration::from_secs(5)));
+        bridge.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_refused_join_is_retried_until_the_channel_is_joined() {
+        // Like a network where the first JOIN to a channel nobody is in
+        // yet fails.
+        let joins = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counted = Arc::clone(&joins);
+        let irc = MockIrcServer::start_with(
+            "127.0.0.1:0".parse().unwrap(),
+            Arc::new(move |line: &str| {
+                if line.starts_with("JOIN ") && counted.fetch_add(1, Ordering::SeqCst) == 0 {
+                    vec![":mock 403 bridge #test :No such channel".into()]
+                } else {
+                    crate::mock_irc::default_responses(line)
+                }
+            }),
+        );
+        let room = MockAmnezichat::start();
+        let bridge = Bridge::new(BridgeConfig {
+            mappings: vec![Mapping { channel: "#test".into(), room_id: "room1".into(), shared_secret: "0".repeat(64) }],
+            servers: Arc::new(ServerList::single(&room.url())),
+            irc: IrcSettings { server: irc.addr(), nick: "bridge".into(), ..IrcSettings::default() },
+            options: BridgeOptions::default(),
+        })
+        .unwrap();
+        assert!(irc.wait_for_count(|l| l == "JOIN #test", 2, Duration::from_secs(10)), "the JOIN is sent again");
+        sleep(Duration::from_secs(6)).await;
+        assert_eq!(joins.load(Ordering::SeqCst), 2, "and not once the server confirmed it");
+        bridge.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn capabilities_offered_later_are_requested() {
+        let secret = "0".repeat(64);
+        let (irc, room, bridge) = start_bridge(BridgeOptions { flood_limit: None, ..BridgeOptions::default() }, IrcSettings { relaymsg: true, ..IrcSettings::default() });
+        assert!(irc.wait_for(|l| l == "JOIN #test", Duration::from_secs(2)));
+
+        irc.send(":mock CAP bridge NEW :message-tags draft/relaymsg=/ unwanted");
+        assert!(irc.wait_for(|l| l == "CAP REQ :message-tags draft/relaymsg", Duration::from_secs(5)));
+        irc.send(":mock CAP bridge ACK :message-tags draft/relaymsg");
+        assert!(room.wait_for_polls(1, Duration::from_secs(5)));
+        room.publish(encrypt_data("alice: hi", &secret).unwrap());
+        assert!(irc.wait_for(|l| l == "RELAYMSG #test alice/amz :hi", Duration::from_secs(10)));
+
+        // Withdrawn again, room messages go out as plain PRIVMSGs.
+        irc.send(":mock CAP bridge DEL :draft/relaymsg");
+        sleep(Duration::from_millis(200)).await;
+        room.publish(encrypt_data("alice: again", &secret).unwrap());
+        assert!(irc.wait_for(|l| l.starts_with("PRIVMSG #test :") && l.ends_with(" again"), Duration::from_secs(10)));
+        bridge.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_taken_nick_is_taken_back_once_it_frees_up() {
+        let taken = AtomicBool::new(true);
+        let irc = MockIrcServer::start_with(
+            "127.0.0.1:0".parse().unwrap(),
+            Arc::new(move |line: &str| match line.trim_end() {
+                "NICK bridge" if taken.swap(false, Ordering::SeqCst) => vec![":mock 433 * bridge :Nickname is already in use".into()],
+                "CAP END" => vec![":mock 005 bridge_ MONITOR=100 :are supported by this server".into()],
+                l if l.starts_with("USER ") => vec![":mock 001 bridge_ :Welcome".into(), ":mock 376 bridge_ :End of /MOTD command.".into()],
+                _ => crate::mock_irc::default_responses(line),
+            }),
+        );
+        let room = MockAmnezichat::start();
+        let secret = "0".repeat(64);
+        let bridge = Bridge::new(BridgeConfig {
+            mappings: vec![Mapping { channel: "#test".into(), room_id: "room1".into(), shared_secret: secret.clone() }],
+            servers: Arc::new(ServerList::single(&room.url())),
+            irc: IrcSettings { server: irc.addr(), nick: "bridge".into(), ..IrcSettings::default() },
+            options: BridgeOptions { flood_limit: None, ..BridgeOptions::default() },
+        })
+        .unwrap();
+        assert!(irc.wait_for(|l| l == "NICK bridge_", Duration::from_secs(2)));
+        assert!(irc.wait_for(|l| l == "MONITOR + bridge", Duration::from_secs(5)));
+
+        irc.send(":mock 731 bridge_ :bridge");
+        assert!(irc.wait_for_count(|l| l == "NICK bridge", 2, Duration::from_secs(5)));
+        irc.send(":bridge_!bridge@mock NICK :bridge");
+        assert!(irc.wait_for(|l| l == "MONITOR - bridge", Duration::from_secs(5)));
+
+        // Under the nick it got back, its own lines are still recognized.
+        irc.send(":bridge!bridge@mock PRIVMSG #test :echoed");
+        irc.send(":bob!b@host PRIVMSG #test :from bob");
+        assert!(room.wait_for_sends(1, Duration::from_secs(10)));
+        sleep(Duration::from_millis(200)).await;
+        let posted: Vec<String> = room.sent().iter().map(|payload| decrypt_data(payload, &secret).unwrap()).collect();
+        assert_eq!(posted, vec!["[IRC]<strong>bob</strong>: from bob".to_string()]);
+
+        // Renamed by the server, it follows the new nick and watches for
+        // its own again.
+        irc.send(":bridge!bridge@mock NICK :Guest42");
+        assert!(irc.wait_for_count(|l| l == "MONITOR + bridge", 2, Duration::from_secs(5)));
+        irc.send(":Guest42!bridge@mock PRIVMSG #test :echoed again");
+        irc.send(":bob!b@host PRIVMSG #test :still bob");
+        assert!(room.wait_for_sends(2, Duration::from_secs(10)));
+        sleep(Duration::from_millis(200)).await;
+        let posted: Vec<String> = room.sent().iter().map(|payload| decrypt_data(payload, &secret).unwrap()).collect();
+        assert_eq!(posted[1..], ["[IRC]<strong>bob</strong>: still bob".to_string()]);
+        bridge.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn room_messages_use_relaymsg_when_the_server_offers_it() {
+        let irc = MockIrcServer::start_with(
+            "127.0.0.1:0".parse().unwrap(),
+            Arc::new(|line: &str| {
+                if line.starts_with("CAP LS") {
+                    vec![":mock CAP * LS :sasl draft/relaymsg=/".into()]
+                } else {
+                    crate::mock_irc::default_responses(line)
+                }
+            }),
+        );
+        let room = MockAmnezichat::start();
+        let secret = "0".repeat(64);
+        let bridge = Bridge::new(BridgeConfig {
+            mappings: vec![Mapping { channel: "#test".into(), room_id: "room1".into(), shared_secret: secret.clone() }],
+            servers: Arc::new(ServerList::single(&room.url())),
+            irc: IrcSettings { server: irc.addr(), nick: "bridge".into(), relaymsg: true, ..IrcSettings::default() },
+            options: BridgeOptions { flood_limit: None, ..BridgeOptions::default() },
+        })
+        .unwrap();
+        assert!(irc.wait_for(|l| l == "CAP REQ :draft/relaymsg", Duration::from_secs(2)));
+        assert!(irc.wait_for(|l| l == "JOIN #test", Duration::from_secs(2)));
+        assert!(room.wait_for_polls(1, Duration::from_secs(5)));
+        room.publish(encrypt_data("alice: hi", &secret).unwrap());
+        assert!(irc.wait_for(|l| l == "RELAYMSG #test alice/amz :hi", Duration::from_secs(10)));
+
+        // The server echoes relayed lines back; they are not room messages.
+        irc.send("@draft/relaymsg=bridge :alice/amz!relay@mock PRIVMSG #test :hi");
+        irc.send(":bob!b@host PRIVMSG #test :from bob");
+        assert!(room.wait_for_sends(1, Duration::from_secs(10)));
+        sleep(Duration::from_millis(200)).await;
+        let posted: Vec<String> = room.sent().iter().map(|payload| decrypt_data(payload, &secret).unwrap()).collect();
+        assert_eq!(posted, vec!["[IRC]<strong>bob</strong>: from bob"]);
+        bridge.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_quiet_bridge_leaves_irc_until_the_room_speaks() {
+        let secret = "0".repeat(64);
+        let (irc, room, bridge) = start_bridge(BridgeOptions {
+            nick_colors: NickColors::Off,
+            idle_disconnect: Some(Duration::from_secs(2)),
+            idle_poll_interval: Duration::from_secs(1),
+            room_status: true,
+            ..BridgeOptions::default()
+        }, IrcSettings::default());
+        assert!(irc.wait_for(|l| l == "JOIN #test", Duration::from_secs(2)));
+        assert!(irc.wait_for(|l| l == format!("QUIT :{}", IDLE_QUIT_MESSAGE), Duration::from_secs(10)));
+        sleep(Duration::from_millis(500)).await;
+        assert!(bridge.status_snapshot().await.irc.dormant);
+        assert_eq!(irc.received().iter().filter(|l| *l == "JOIN #test").count(), 1, "no reconnect while dormant");
+
+        room.publish(encrypt_data("alice: wake up", &secret).unwrap());
+        assert!(irc.wait_for(|l| l == "PRIVMSG #test :\x02alice >\x02 wake up", Duration::from_secs(10)));
+        assert_eq!(irc.received().iter().filter(|l| *l == "JOIN #test").count(), 2);
+        assert!(!bridge.status_snapshot().await.irc.dormant);
+        assert!(room.sent().is_empty(), "leaving on purpose is not announced in the room");
+        bridge.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn accounts_are_learned_from_one_who_on_join() {
+        let irc = MockIrcServer::start_with(
+            "127.0.0.1:0".parse().unwrap(),
+            Arc::new(|line: &str| {
+                if line == "CAP END" {
+                    vec![":mock 005 bridge WHOX CHANTYPES=# :are supported by this server".into()]
+                } else {
+                    crate::mock_irc::default_responses(line)
+                }
+            }),
+        );
+        let room = MockAmnezichat::start();
+        let secret = "0".repeat(64);
+        let bridge = Bridge::new(BridgeConfig {
+            mappings: vec![Mapping { channel: "#test".into(), room_id: "room1".into(), shared_secret: secret.clone() }],
+            servers: Arc::new(ServerList::single(&room.url())),
+            irc: IrcSettings { server: irc.addr(), nick: "bridge".into(), ..IrcSettings::default() },
+            options: BridgeOptions { flood_limit: None, identify_policy: IdentifyPolicy::Tag, who_on_join: true, ..BridgeOptions::default() },
+        })
+        .unwrap();
+        assert!(irc.wait_for(|l| l == "WHO #test %na", Duration::from_secs(5)));
+        irc.send(":mock 354 bridge alice alice");
+        irc.send(":mock 354 bridge carol 0");
+        irc.send(":mock 315 bridge #test :End of /WHO list.");
+        // extended-join tells about later arrivals.
+        irc.send(":bob!b@host JOIN #test bob :Bob");
+        irc.send(":alice!a@host PRIVMSG #test :from alice");
+        irc.send(":carol!c@host PRIVMSG #test :from carol");
+        irc.send(":bob!b@host PRIVMSG #test :from bob");
+        assert!(room.wait_for_sends(3, Duration::from_secs(10)));
+        let posted: Vec<String> = room.sent().iter().map(|payload| decrypt_data(payload, &secret).unwrap()).collect();
+        assert_eq!(
+            posted,
+            vec!["[IRC]<strong>alice</strong>: from alice", "[IRC]<strong>carol (unverified)</strong>: from carol", "[IRC]<strong>bob</strong>: from bob"]
+        );
+        assert!(!irc.received().iter().any(|l| l.starts_with("WHOIS")));
+        bridge.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn log_replies_are_paced_and_limited() {
+        let (irc, room, bridge) = start_bridge(BridgeOptions { flood_limit: None, ..BridgeOptions::default() }, IrcSettings::default());
+        assert!(irc.wait_for(|l| l == "JOIN #test", Duration::from_secs(2)));
+        for n in 0..30 {
+            irc.send(&format!(":bob!b@host PRIVMSG #test :message {}", n));
+        }
+        assert!(room.wait_for_sends(30, Duration::from_secs(10)));
+
+        let asked = Instant::now();
+        irc.send(":alice!a@host PRIVMSG #test :.log 50");
+        irc.send(":alice!a@host PRIVMSG #test :.log 50");
+        assert!(irc.wait_for(|l| l == "NOTICE alice :[irc] <bob> message 29", Duration::from_secs(20)));
+        assert!(asked.elapsed() >= HISTORY_PACE * (backlog::MAX_LOG_LINES as u32 - 1), "sent within {:?}", asked.elapsed());
+        sleep(Duration::from_millis(200)).await;
+        let notices = irc.received().into_iter().filter(|l| l.starts_with("NOTICE alice :")).count();
+        assert_eq!(notices, backlog::MAX_LOG_LINES, "one reply of at most MAX_LOG_LINES");
+        bridge.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn the_same_command_is_answered_again_after_the_cooldown() {
+        let (irc, room, bridge) = start_bridge(BridgeOptions { flood_limit: None, ..BridgeOptions::default() }, IrcSettings::default());
+        assert!(irc.wait_for(|l| l == "JOIN #test", Duration::from_secs(2)));
+        irc.send(":bob!b@host PRIVMSG #test :hello");
+        assert!(room.wait_for_sends(1, Duration::from_secs(10)));
+
+        let reply = |l: &str| l == "NOTICE alice :[irc] <bob> hello";
+        irc.send(":alice!a@host PRIVMSG #test :.log 1");
+        assert!(irc.wait_for(reply, Duration::from_secs(5)));
+        sleep(backlog::LOG_COOLDOWN + Duration::from_millis(200)).await;
+        irc.send(":alice!a@host PRIVMSG #test :.log 1");
+        assert!(irc.wait_for_count(reply, 2, Duration::from_secs(5)), "the second .log is answered too");
+
+        irc.send(":alice!a@host PRIVMSG #test :.amnezichat");
+        irc.send(":alice!a@host PRIVMSG #test :.amnezichat");
+        assert!(irc.wait_for_count(|l| l.starts_with("PRIVMSG #test :alice: Anti-forensic"), 2, Duration::from_secs(5)));
+        bridge.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn notices_pass_the_same_checks_as_messages() {
+        let secret = "0".repeat(64);
+        let (irc, room, bridge) = start_bridge(BridgeOptions { flood_limit: None, relay_notices: true, identify_policy: IdentifyPolicy::Drop, ..BridgeOptions::default() }, IrcSettings::default());
+        assert!(irc.wait_for(|l| l == "JOIN #test", Duration::from_secs(5)));
+        irc.send(":NickServ!services@services NOTICE bridge :You are now identified");
+        irc.send(":dave!d@host NOTICE #test :[AMZ] looks like the bridge");
+        irc.send(":carol!c@host NOTICE #test :from carol");
+        irc.send(":alice!a@host NOTICE #test :from alice");
+        assert!(irc.wait_for(|l| l == "WHOIS alice", Duration::from_secs(5)));
+        irc.send(":mock 318 bridge carol :End of /WHOIS list.");
+        irc.send(":mock 330 bridge alice alice :is logged in as");
+        irc.send(":mock 318 bridge alice :End of /WHOIS list.");
+        assert!(room.wait_for_sends(1, Duration::from_secs(10)));
+        sleep(Duration::from_millis(200)).await;
+        let posted: Vec<String> = room.sent().iter().map(|payload| decrypt_data(payload, &secret).unwrap()).collect();
+        assert_eq!(posted, vec!["[IRC]<strong>-alice-</strong>: from alice".to_string()]);
+        let whois: Vec<String> = irc.received().into_iter().filter(|l| l.starts_with("WHOIS")).collect();
+        assert_eq!(whois, ["WHOIS carol", "WHOIS alice"]);
+        bridge.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn senders_are_verified_by_whois_before_relaying() {
+        let secret = "0".repeat(64);
+        let (irc, room, bridge) = start_bridge(BridgeOptions { flood_limit: None, identify_policy: IdentifyPolicy::Tag, ..BridgeOptions::default() }, IrcSettings::default());
+        assert!(irc.wait_for(|l| l == "JOIN #test", Duration::from_secs(5)));
+        irc.send(":alice!a@host PRIVMSG #test :first");
+        irc.send(":alice!a@host PRIVMSG #test :second");
+        irc.send(":carol!c@host PRIVMSG #test :from carol");
+        assert!(irc.wait_for(|l| l == "WHOIS carol", Duration::from_secs(5)));
+        sleep(Duration::from_millis(200)).await;
+        assert!(room.sent().is_empty(), "held until the WHOIS is answered");
+
+        irc.send(":mock 330 bridge alice alice_acct :is logged in as");
+        irc.send(":mock 318 bridge alice :End of /WHOIS list.");
+        irc.send(":mock 318 bridge carol :End of /WHOIS list.");
+        assert!(room.wait_for_sends(3, Duration::from_secs(10)));
+        // Known now, so relayed without asking again.
+        irc.send(":alice!a@host PRIVMSG #test :third");
+        assert!(room.wait_for_sends(4, Duration::from_secs(10)));
+        let posted: Vec<String> = room.sent().iter().map(|payload| decrypt_data(payload, &secret).unwrap()).collect();
+        assert_eq!(
+            posted,
+            [
+                "[IRC]<strong>alice</strong>: first",
+                "[IRC]<strong>alice</strong>: second",
+                "[IRC]<strong>carol (unverified)</strong>: from carol",
+                "[IRC]<strong>alice</strong>: third",
+            ]
+        );
+        let whois: Vec<String> = irc.received().into_iter().filter(|l| l.starts_with("WHOIS")).collect();
+        assert_eq!(whois, ["WHOIS alice", "WHOIS carol"]);
+        bridge.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dcc_offers_are_declined_and_not_bridged() {
+        let secret = "0".repeat(64);
+        let (irc, room, bridge) = start_bridge(BridgeOptions { flood_limit: None, ..BridgeOptions::default() }, IrcSettings::default());
+        assert!(irc.wait_for(|l| l == "JOIN #test", Duration::from_secs(2)));
+        irc.send(":alice!a@host PRIVMSG bridge :\x01DCC SEND photo.jpg 3232235777 5000 1024\x01");
+        assert!(irc.wait_for(|l| l == format!("NOTICE alice :{}", DCC_DECLINE), Duration::from_secs(5)));
+        irc.send(":alice!a@host PRIVMSG #test :\x01DCC SEND photo.jpg 3232235777 5000 1024\x01");
+        irc.send(":alice!a@host PRIVMSG #test :see the link instead");
+        assert!(room.wait_for_sends(1, Duration::from_secs(10)));
+        sleep(Duration::from_millis(200)).await;
+        let posted: Vec<String> = room.sent().iter().map(|payload| decrypt_data(payload, &secret).unwrap()).collect();
+        assert_eq!(posted, vec!["[IRC]<strong>alice</strong>: see the link instead"]);
+        assert_eq!(irc.received().iter().filter(|l| l.starts_with("NOTICE alice")).count(), 1, "only offers to the bridge are answered");
+        bridge.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn people_also_in_the_room_are_not_relayed_twice() {
+        for (mode, expected) in [
+            (SamePersonMode::Suppress, vec!["[IRC]<strong>bob</strong>: from bob"]),
+            (SamePersonMode::Annotate, vec!["[IRC]<strong>alice_ (as Alice)</strong>: from alice", "[IRC]<strong>bob</strong>: from bob"]),
+        ] {
+            let irc = MockIrcServer::start();
+            let room = MockAmnezichat::start();
+            let secret = "0".repeat(64);
+            let mut same_person = SamePerson::default();
+            same_person.mode = mode;
+            assert!(same_person.add("alice_=Alice"));
+            let bridge = Bridge::new(BridgeConfig {
+                mappings: vec![Mapping { channel: "#test".into(), room_id: "room1".into(), shared_secret: secret.clone() }],
+                servers: Arc::new(ServerList::single(&room.url())),
+                irc: IrcSettings { server: irc.addr(), nick: "bridge".into(), ..IrcSettings::default() },
+                options: BridgeOptions { flood_limit: None, same_person, ..BridgeOptions::default() },
+            })
+            .unwrap();
+            assert!(irc.wait_for(|l| l == "JOIN #test", Duration::from_secs(2)));
+            irc.send(":alice_!a@host PRIVMSG #test :from alice");
+            irc.send(":bob!b@host PRIVMSG #test :from bob");
+            assert!(room.wait_for_sends(expected.len(), Duration::from_secs(10)));
+            sleep(Duration::from_millis(200)).await;
+            let posted: Vec<String> = room.sent().iter().map(|payload| decrypt_data(payload, &secret).unwrap()).collect();
+            assert_eq!(posted, expected, "{:?}", mode);
+            bridge.shutdown().await;
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn only_operators_and_admins_are_told_the_room_id() {
+        let (irc, room, bridge) = start_bridge(BridgeOptions { flood_limit: None, admin_password: Some("hunter2".into()), ..BridgeOptions::default() }, IrcSettings::default());
+        assert!(irc.wait_for(|l| l == "JOIN #test", Duration::from_secs(2)));
+        irc.send(":server 353 bridge = #test :bridge @op alice");
+        irc.send(":server 366 bridge #test :End of /NAMES list.");
+
+        irc.send(":alice!a@host PRIVMSG #test :.roomid");
+        assert!(irc.wait_for(|l| l == "NOTICE alice :Only operators of #test and bridge admins can see its room id.", Duration::from_secs(5)));
+        irc.send(":op!o@host PRIVMSG #test :.roomid");
+        assert!(irc.wait_for(|l| l == "NOTICE op :#test is bridged to room room1", Duration::from_secs(5)));
+
+        irc.send(":server MODE #test -o op");
+        irc.send(":op!o@host PRIVMSG bridge :.roomid #test");
+        assert!(irc.wait_for(|l| l == "NOTICE op :Only operators of #test and bridge admins can see its room id.", Duration::from_secs(5)));
+        irc.send(":alice!a@host PRIVMSG bridge :.bridge login hunter2");
+        irc.send(":alice!a@host PRIVMSG bridge :.roomid");
+        assert!(irc.wait_for(|l| l == "NOTICE alice :#test is bridged to room room1", Duration::from_secs(5)));
+
+        sleep(Duration::from_millis(200)).await;
+        assert!(irc.received().iter().all(|l| !l.starts_with("PRIVMSG #test") || !l.contains("room1")));
+        assert!(room.sent().is_empty(), "commands aren't relayed to the room");
+        bridge.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn room_history_from_before_startup_stays_out_of_irc() {
+        for (replay_history, expected) in [(0, vec!["\x02carol >\x02 new"]), (1, vec!["[history] \x02bob >\x02 old two", "\x02carol >\x02 new"])] {
+            let irc = MockIrcServer::start();
+            let room = MockAmnezichat::start();
+            let secret = "0".repeat(64);
+            for content in ["alice: old one", "bob: old two"] {
+                room.publish(encrypt_data(content, &secret).unwrap());
+            }
+            let bridge = Bridge::new(BridgeConfig {
+                mappings: vec![Mapping { channel: "#test".into(), room_id: "room1".into(), shared_secret: secret.clone() }],
+                servers: Arc::new(ServerList::single(&room.url())),
+                irc: IrcSettings { server: irc.addr(), nick: "bridge".into(), ..IrcSettings::default() },
+                options: BridgeOptions { nick_colors: NickColors::Off, replay_history, ..BridgeOptions::default() },
+            })
+            .unwrap();
+            assert!(irc.wait_for(|l| l == "JOIN #test", Duration::from_secs(2)));
+            assert!(room.wait_for_polls(1, Duration::from_secs(5)));
+
+            room.publish(encrypt_data("carol: new", &secret).unwrap());
+            assert!(irc.wait_for(|l| l.ends_with(" new"), Duration::from_secs(10)));
+            let privmsgs: Vec<String> = irc.received().into_iter().filter_map(|l| l.strip_prefix("PRIVMSG #test :").map(str::to_string)).collect();
+            assert_eq!(privmsgs, expected, "replay_history {}", replay_history);
+            bridge.shutdown().await;
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_burst_of_room_messages_reaches_irc_in_order() {
+        let secret = "0".repeat(64);
+        let (irc, room, bridge) = start_bridge(BridgeOptions { nick_colors: NickColors::Off, multiline: MultilineMode::Split, ..BridgeOptions::default() }, IrcSettings::default());
+        assert!(irc.wait_for(|l| l == "JOIN #test", Duration::from_secs(2)));
+        assert!(room.wait_for_polls(1, Duration::from_secs(5)));
+
+        // All in one poll; identical texts from different senders too.
+        for content in ["alice: one", "bob: two\nthree", "alice: four", "bob: four", "alice: five"] {
+            room.publish(encrypt_data(content, &secret).unwrap());
+        }
+        assert!(irc.wait_for(|l| l.ends_with(" five"), Duration::from_secs(30)));
+        let privmsgs: Vec<String> = irc.received().into_iter().filter_map(|l| l.strip_prefix("PRIVMSG #test :").map(str::to_string)).collect();
+        assert_eq!(
+            privmsgs,
+            vec![
+                "\x02alice >\x02 one",
+                "\x02bob >\x02 two",
+                "\x02bob >\x02 three",
+                "\x02alice >\x02 four",
+                "\x02bob >\x02 four",
+                "\x02alice >\x02 five",
+            ]
+        );
+        bridge.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn channels_match_however_the_server_cases_them() {
+        let irc = MockIrcServer::start();
+        let room = MockAmnezichat::start();
+        let secret = "0".repeat(64);
+        let bridge = Bridge::new(BridgeConfig {
+            mappings: vec![Mapping { channel: "#My[Channel]".into(), room_id: "room1".into(), shared_secret: secret.clone() }],
+            servers: Arc::new(ServerList::single(&room.url())),
+            irc: IrcSettings { server: irc.addr(), nick: "bridge".into(), ..IrcSettings::default() },
+            options: BridgeOptions { flood_limit: None, ..BridgeOptions::default() },
+        })
+        .unwrap();
+        assert!(irc.wait_for(|l| l == "JOIN #My[Channel]", Duration::from_secs(2)));
+
+        irc.send(":alice!a@host PRIVMSG #my{channel} :hi");
+        assert!(room.wait_for_sends(1, Duration::from_secs(10)));
+        assert_eq!(decrypt_data(&room.sent()[0], &secret).unwrap(), "[IRC]<strong>alice</strong>: hi");
+        bridge.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn lines_for_channels_that_are_not_bridged_are_dropped() {
+        let (irc, _room, bridge) = start_bridge(BridgeOptions::default(), IrcSettings::default());
+        assert!(irc.wait_for(|l| l == "JOIN #test", Duration::from_secs(2)));
+
+        assert!(bridge.queue.push(IrcLine::new("#elsewhere", "misrouted".into(), None)).await.is_none());
+        assert!(bridge.queue.push(IrcLine::new("alice", "misrouted".into(), None)).await.is_none());
+        assert!(bridge.queue.push(IrcLine::new("#TEST", "bridged".into(), None)).await.is_none());
+        assert!(irc.wait_for(|l| l == "PRIVMSG #TEST :bridged", Duration::from_secs(5)));
+        assert!(!irc.received().iter().any(|l| l.contains("misrouted")));
+        bridge.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_line_whose_write_fails_is_sent_again_after_reconnecting() {
+        let (irc, _room, bridge) = start_bridge(BridgeOptions::default(), IrcSettings::default());
+        assert!(irc.wait_for(|l| l == "JOIN #test", Duration::from_secs(2)));
+
+        // The line is taken on while the connection still looks fine, and
+        // only its write fails.
+        let broken = bridge.irc.current();
+        broken.state().sends_held = true;
+        assert!(bridge.queue.push(IrcLine::new("#test", "redelivered".into(), None)).await.is_none());
+        sleep(Duration::from_millis(100)).await;
+        let IrcStream::Tcp(socket) = &*broken.socket else { panic!("connected over TCP") };
+        socket.shutdown(std::net::Shutdown::Write).unwrap();
+        broken.state().sends_held = false;
+
+        assert!(irc.wait_for_count(|l| l == "JOIN #test", 2, Duration::from_secs(10)));
+        assert!(irc.wait_for(|l| l == "PRIVMSG #test :redelivered", Duration::from_secs(10)));
+        bridge.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn messages_are_spaced_by_the_send_delay() {
+        let (irc, _room, bridge) = start_bridge(BridgeOptions::default(), IrcSettings { send_delay: Some(Duration::from_millis(300)), ..IrcSettings::default() });
+        assert!(irc.wait_for(|l| l == "JOIN #test", Duration::from_secs(2)));
+
+        let started = Instant::now();
+        for text in ["one", "two", "three"] {
+            assert!(bridge.queue.push(IrcLine::new("#test", text.into(), None)).await.is_none());
+        }
+        // Command replies wait their turn as well.
+        irc.send(":alice!a@host PRIVMSG #test :.amnezichat");
+        assert!(irc.wait_for(|l| l == "PRIVMSG #test :three", Duration::from_secs(5)));
+        assert!(irc.wait_for(|l| l.starts_with("PRIVMSG #test :alice: "), Duration::from_secs(5)));
+        assert!(started.elapsed() >= Duration::from_millis(900), "sent within {:?}", started.elapsed());
+        bridge.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn threads_are_flattened_into_labels() {
+        let secret = "0".repeat(64);
+        let (irc, room, bridge) = start_bridge(BridgeOptions { nick_colors: NickColors::Off, ..BridgeOptions::default() }, IrcSettings::default());
+        assert!(irc.wait_for(|l| l == "JOIN #test", Duration::from_secs(2)));
+        assert!(room.wait_for_polls(1, Duration::from_secs(5)));
+
+        room.publish(encrypt_data("alice: <thread name=\"lunch\">noon?</thread>", &secret).unwrap());
+        assert!(irc.wait_for(|l| l == "PRIVMSG #test :\x02alice >\x02 [thread: lunch] noon?", Duration::from_secs(10)));
+        bridge.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn markers_are_filtered_exactly() {
+        let secret = "0".repeat(64);
+        let (irc, room, bridge) = start_bridge(BridgeOptions { flood_limit: None, nick_colors: NickColors::Off, ..BridgeOptions::default() }, IrcSettings::default());
+        assert!(irc.wait_for(|l| l == "JOIN #test", Duration::from_secs(2)));
+        // Anything there at the first poll is history and isn't sent.
+        assert!(room.wait_for_polls(1, Duration::from_secs(5)));
+
+        for content in [
+            "[DUMMY_DATA]: 8f2a91c0",
+            "<padding>xxxx</padding>[DUMMY_DATA]: 77e1",
+            "alice: dummies look like [DUMMY_DATA]: abc",
+            "[IRC]<strong>bob</strong>: relayed by this bridge",
+            // Without --network, a tagged relay is from another network.
+            "[IRC:libera]<strong>bob</strong>: relayed by a bridge on another network",
+            "alice: bridges tag messages with [IRC] and [AMZ]",
+            "[AMZ]carol: from an older bridge",
+            "alice: done",
+        ] {
+            room.publish(encrypt_data(content, &secret).unwrap());
+        }
+        let privmsgs = || irc.received().into_iter().filter_map(|l| l.strip_prefix("PRIVMSG #test :").map(str::to_string)).collect::<Vec<_>>();
+        assert!(irc.wait_for(|l| l.ends_with(" done"), Duration::from_secs(30)));
+        assert_eq!(
+            privmsgs(),
+            vec![
+                "\x02alice >\x02 dummies look like [DUMMY_DATA]: abc",
+                "\x02[libera] bob >\x02 relayed by a bridge on another network",
+                "\x02alice >\x02 bridges tag messages with [IRC] and [AMZ]",
+                "\x02carol >\x02 from an older bridge",
+                "\x02alice >\x02 done",
+            ]
+        );
+
+        irc.send(":dave!d@host PRIVMSG #test :[AMZ]alice: echoed by another bridge");
+        irc.send(":dave!d@host PRIVMSG #test :quoting [AMZ] and [IRC] is fine");
+        irc.send(":dave!d@host PRIVMSG #test :[DUMMY_DATA]: only means something in a room");
+        assert!(room.wait_for_sends(2, Duration::from_secs(10)));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let posted: Vec<String> = room.sent().iter().map(|payload| decrypt_data(payload, &secret).unwrap()).collect();
+        assert_eq!(
+            posted,
+            vec![
+                "[IRC]<strong>dave</strong>: quoting [AMZ] and [IRC] is fine".to_string(),
+                "[IRC]<strong>dave</strong>: [DUMMY_DATA]: only means something in a room".to_string(),
+            ]
+        );
+        bridge.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reactions_are_relayed_only_when_enabled() {
+        for relay_reactions in [false, true] {
+            let irc = MockIrcServer::start();
+            let room = MockAmnezichat::start();
+            let secret = "0".repeat(64);
+            let before = "bob: lunch at noon?";
+            room.publish(encrypt_data(before, &secret).unwrap());
+            let bridge = Bridge::new(BridgeConfig {
+                mappings: vec![Mapping { channel: "#test".into(), room_id: "room1".into(), shared_secret: secret.clone() }],
+                servers: Arc::new(ServerList::single(&room.url())),
+                irc: IrcSettings { server: irc.addr(), nick: "bridge".into(), ..IrcSettings::default() },
+                options: BridgeOptions { nick_colors: NickColors::Off, relay_reactions, ..BridgeOptions::default() },
+            })
+            .unwrap();
+            assert!(irc.wait_for(|l| l == "JOIN #test", Duration::from_secs(2)));
+            assert!(room.wait_for_polls(1, Duration::from_secs(5)));
+
+            let react = |target: &str| Reaction { user: "alice".into(), emoji: "👍".into(), target: message_id(target) }.to_string();
+            for content in [react(before), "carol: ok".into(), react("carol: ok"), react("never seen"), "alice: done".into()] {
+                room.publish(encrypt_data(&content, &secret).unwrap());
+            }
+            assert!(irc.wait_for(|l| l.ends_with(" done"), Duration::from_secs(30)));
+            let privmsgs: Vec<String> = irc.received().into_iter().filter_map(|l| l.strip_prefix("PRIVMSG #test :").map(str::to_string)).collect();
+            let expected = if relay_reactions {
+                vec![
+                    "alice reacted 👍 to bob's message \"lunch at noon?\"",
+                    "\x02carol >\x02 ok",
+                    "alice reacted 👍 to carol's message \"ok\"",
+                    "alice reacted 👍 to an earlier message",
+                    "\x02alice >\x02 done",
+                ]
+            } else {
+                vec!["\x02carol >\x02 ok", "\x02alice >\x02 done"]
+            };
+            assert_eq!(privmsgs, expected, "relay_reactions {}", relay_reactions);
+            bridge.shutdown().await;
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reactions_only_quote_relays_with_a_valid_signature() {
+        let irc = MockIrcServer::start();
+        let room = MockAmnezichat::start();
+        let secret = "0".repeat(64);
+        let key = [7u8; 32];
+        let bridge = Bridge::new(BridgeConfig {
+            mappings: vec![Mapping { channel: "#test".into(), room_id: "room1".into(), shared_secret: secret.clone() }],
+            servers: Arc::new(ServerList::single(&room.url())),
+            irc: IrcSettings { server: irc.addr(), nick: "bridge".into(), ..IrcSettings::default() },
+            options: BridgeOptions { nick_colors: NickColors::Off, relay_reactions: true, signing_key: Some(key), ..BridgeOptions::default() },
+        })
+        .unwrap();
+        assert!(irc.wait_for(|l| l == "JOIN #test", Duration::from_secs(2)));
+        assert!(room.wait_for_polls(1, Duration::from_secs(5)));
+
+        let forged = "[IRC:libera]<strong>eve</strong>: forged";
+        let signed = sign_relay(&key, "room1", "[IRC:libera]<strong>bob</strong>: signed");
+        let react = |target: &str| Reaction { user: "alice".into(), emoji: "👍".into(), target: message_id(target) }.to_string();
+        for content in [forged.to_string(), react(forged), signed.clone(), react(&signed), "alice: done".into()] {
+            room.publish(encrypt_data(&content, &secret).unwrap());
+        }
+        assert!(irc.wait_for(|l| l.ends_with(" done"), Duration::from_secs(30)));
+        let reactions: Vec<String> = irc.received().into_iter().filter(|l| l.contains(" reacted ")).collect();
+        assert_eq!(
+            reactions,
+            ["PRIVMSG #test :alice reacted 👍 to an earlier message", "PRIVMSG #test :alice reacted 👍 to bob's message \"signed\""]
+        );
+        bridge.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn shutdown_stops_every_task() {
+        let (irc, room, bridge) = start_bridge(BridgeOptions::default(), IrcSettings::default());
+        assert!(irc.wait_for(|l| l == "JOIN #test", Duration::from_secs(2)));
+        // Leaves a rejoin pending, which must not outlive the bridge either.
+        irc.send(":op!o@host KICK #test bridge :spam");
+        sleep(Duration::from_millis(200)).await;
+
+        timeout(Duration::from_secs(5), bridge.shutdown()).await.expect("shutdown waits for every task and returns");
+        assert!(irc.wait_for(|l| l.starts_with("QUIT"), Duration::from_secs(2)));
+        assert!(bridge.tasks.lock().unwrap().is_empty());
+        assert!(bridge.routes.snapshot().is_empty());
+        let polls = room.polls();
+        sleep(Duration::from_millis(5500)).await;
+        assert_eq!(room.polls(), polls, "nothing polls after shutdown");
+        assert_eq!(irc.received().iter().filter(|l| *l == "JOIN #test").count(), 1, "nothing rejoins after shutdown");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stopping_a_mapping_leaves_the_others_running() {
+        let irc = MockIrcServer::start();
+        let room = MockAmnezichat::start();
+        let bridge = Bridge::new(BridgeConfig {
+            mappings: vec![
+                Mapping { channel: "#a".into(), room_id: "roomA".into(), shared_secret: "0".repeat(64) },
+                Mapping { channel: "#b".into(), room_id: "roomB".into(), shared_secret: "1".repeat(64) },
+            ],
+            servers: Arc::new(ServerList::single(&room.url())),
+            irc: IrcSettings { server: irc.addr(), nick: "bridge".into(), ..IrcSettings::default() },
+            options: BridgeOptions { flood_limit: None, ..BridgeOptions::default() },
+        })
+        .unwrap();
+        assert!(irc.wait_for(|l| l == "JOIN #a,#b", Duration::from_secs(2)));
+        let routes = bridge.routes.snapshot();
+        let finished = |route: &Route| route.poll.lock().unwrap().as_ref().unwrap().is_finished();
+
+        assert!(bridge.stop_mapping("roomB"));
+        assert!(!bridge.stop_mapping("roomB"));
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while !finished(&routes[1]) && Instant::now() < deadline {
+            sleep(Duration::from_millis(20)).await;
+        }
+        assert!(finished(&routes[1]), "the poll task stops within a second");
+        assert!(!finished(&routes[0]));
+
+        irc.send(":alice!a@host PRIVMSG #b :no longer bridged");
+        irc.send(":alice!a@host PRIVMSG #a :still bridged");
+        assert!(room.wait_for_sends(1, Duration::from_secs(10)));
+        sleep(Duration::from_millis(200)).await;
+        assert!(room.sent_to("roomB").is_empty());
+        assert_eq!(room.sent_to("roomA").len(), 1);
+        bridge.shutdown().await;
+    }
+
+    #[test]
+    fn the_same_channel_cannot_be_mapped_twice() {
+        let mapping = |channel: &str| Mapping { channel: channel.into(), room_id: "room".into(), shared_secret: "0".repeat(64) };
+        let err = Bridge::new(BridgeConfig {
+            mappings: vec![mapping("#a"), mapping("#A")],
+            servers: Arc::new(ServerList::single("http://127.0.0.1:9")),
+            irc: IrcSettings::default(),
+            options: BridgeOptions::default(),
+        })
+        .err()
+        .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn server_password_is_sent_before_registration() {
+        let server = MockIrcServer::start();
+        let settings = IrcSettings {
+            server: server.addr(),
+            nick: "bridge".into(),
+            channels: vec!["#test".into()],
+            server_password: Some("bridge/libera:secret".into()),
+            ..IrcSettings::default()
+        };
+
+        CustomIrcClient::connect_and_auth(&settings).unwrap();
+        assert!(server.wait_for(|l| l == "JOIN #test", Duration::from_secs(2)));
+        let received = server.received();
+        assert_eq!(received[0], "PASS :bridge/libera:secret");
+        assert!(received[1..].contains(&"NICK bridge".to_string()));
+        assert!(received.contains(&format!("USER bridge 0 * :{}", DEFAULT_REALNAME)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn connects_through_a_unix_socket() {
+        use std::os::unix::net::UnixListener;
+
+        let server = MockIrcServer::start();
+        let path = std::env::temp_dir().join(format!("amz-bridge-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        // Stands in for a bouncer listening locally.
+        let tcp_addr = server.socket_addr();
+        std::thread::spawn(move || {
+            let (unix, _) = listener.accept().unwrap();
+            let tcp = std::net::TcpStream::connect(tcp_addr).unwrap();
+            let (mut unix_read, mut tcp_write) = (unix.try_clone().unwrap(), tcp.try_clone().unwrap());
+            std::thread::spawn(move || std::io::copy(&mut unix_read, &mut tcp_write));
+            let (mut tcp_read, mut unix_write) = (tcp, unix);
+            let _ = std::io::copy(&mut tcp_read, &mut unix_write);
+        });
+
+        let settings = IrcSettings {
+            server: format!("unix:{}", path.display()),
+            nick: "bridge".into(),
+            channels: vec!["#test".into()],
+            ..IrcSettings::default()
+        };
+        CustomIrcClient::connect_and_auth(&settings).unwrap();
+        assert!(server.wait_for(|l| l == "JOIN #test", Duration::from_secs(2)));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn sent_lines_never_contain_line_breaks() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let raw = std::thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut bytes = Vec::new();
+            std::io::Read::read_to_end(&mut socket, &mut bytes).unwrap();
+            bytes
+        });
+
+        let texts = [
+            "cr\rJOIN #evil",
+            "lf\nJOIN #evil",
+            "crlf\r\nJOIN #evil",
+            "\r\n\r\n",
+            "nul\0QUIT",
+            // Right at the length limit, so truncation meets the CRLF.
+            &format!("{}\r\nQUIT", "a".repeat(MAX_MESSAGE_CHARS - 1)),
+        ];
+        let (connection, _incoming) = CustomIrcClient::new(&addr, DEFAULT_CONNECT_TIMEOUT).unwrap().start().unwrap();
+        for text in texts {
+            connection.send_message("#test", text).unwrap();
+            connection.send(Command::Notice { target: "alice", text }).unwrap();
+        }
+        assert!(connection.flush().await);
+        connection.close();
+
+        let raw = raw.join().unwrap();
+        let lines: Vec<&[u8]> = raw.strip_suffix(b"\n").expect("ends with a line break").split(|b| *b == b'\n').collect();
+        assert_eq!(lines.len(), texts.len() * 2, "one CRLF per message");
+        for line in lines {
+            let line = line.strip_suffix(b"\r").expect("every line ends in CRLF");
+            assert!(!line.iter().any(|b| matches!(b, b'\r' | b'\n' | b'\0')), "{:?}", String::from_utf8_lossy(line));
+            assert!(line.starts_with(b"PRIVMSG #test :") || line.starts_with(b"NOTICE alice :"));
+        }
+    }
+
+    #[tokio::test]
+    async fn a_stalled_write_does_not_hold_up_reading() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (connection, mut incoming) = CustomIrcClient::new(&addr, DEFAULT_CONNECT_TIMEOUT).unwrap().start().unwrap();
+        // The server never reads, so the writer soon blocks on a full
+        // socket buffer, and then the write queue fills.
+        let (mut server, _) = listener.accept().unwrap();
+        let text = "x".repeat(MAX_MESSAGE_CHARS);
+        let refused = (0..50_000).find_map(|_| connection.send_message("#test", &text).err()).expect("the write queue is bounded");
+        assert_eq!(refused.kind(), io::ErrorKind::WouldBlock);
+
+        server.write_all(b":alice!a@host PRIVMSG #test :still reading\r\n").unwrap();
+        let line = timeout(Duration::from_secs(2), incoming.recv()).await.expect("read while the write is stuck");
+        assert!(line.unwrap().unwrap().contains("still reading"));
+        connection.close();
+    }
+
+    #[test]
+    fn registration_ends_on_the_end_of_motd_numeric_only() {
+        let server = MockIrcServer::start_with(
+            "127.0.0.1:0".parse().unwrap(),
+            Arc::new(|line: &str| {
+                // Sent ahead of the 001 and 376 the mock holds back until
+                // CAP END.
+                if line == "CAP END" {
+                    vec![
+                        ":mock 372 bridge :- Meetups in room 376, or see 422.example".into(),
+                        ":mock 005 bridge MONITOR=100 :are supported by this server".into(),
+                    ]
+                } else {
+                    crate::mock_irc::default_responses(line)
+                }
+            }),
+        );
+        let settings = IrcSettings { server: server.addr(), nick: "bridge".into(), ..IrcSettings::default() };
+        let client = CustomIrcClient::connect_and_auth(&settings).unwrap();
+        assert!(client.monitor, "the 005 after the MOTD line was read");
+    }
+
+    #[test]
+    fn rejected_server_password_is_reported() {
+        let server = MockIrcServer::start_with(
+            "127.0.0.1:0".parse().unwrap(),
+            Arc::new(|line: &str| {
+                if line.starts_with("USER ") {
+                    vec![":mock 464 * :Password incorrect".into()]
+                } else {
+                    Vec::new()
+                }
+            }),
+        );
+        let settings = IrcSettings {
+            server: server.addr(),
+            nick: "bridge".into(),
+            channels: vec!["#test".into()],
+            server_password: Some("wrong".into()),
+            ..IrcSettings::default()
+        };
+
+        let err = CustomIrcClient::connect_and_auth(&settings).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn stalled_sasl_handshake_times_out() {
+        let server = MockIrcServer::start_with(
+            "127.0.0.1:0".parse().unwrap(),
+            Arc::new(|line: &str| {
+                // Accepts the CAP request, then never answers AUTHENTICATE.
+                if line.starts_with("AUTHENTICATE") {
+                    Vec::new()
+                } else {
+                    crate::mock_irc::default_responses(line)
+                }
+            }),
+        );
+        let settings = IrcSettings {
+            server: server.addr(),
+            nick: "bridge".into(),
+            channels: vec!["#test".into()],
+            sasl_username: Some("bridge".into()),
+            sasl_password: Some("hunter22".into()),
+            registration_timeout: Some(Duration::from_millis(300)),
+            ..IrcSettings::default()
+        };
+
+        let started = Instant::now();
+        let err = CustomIrcClient::connect_and_auth(&settings).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(err.to_string().contains("AUTHENTICATE +"), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn sasl_password_file_is_read_at_every_connection() {
+        let server = MockIrcServer::start();
+        let path = std::env::temp_dir().join(format!("amz-bridge-sasl-{}", std::process::id()));
+        let settings = IrcSettings {
+            server: server.addr(),
+            nick: "bridge".into(),
+            channels: vec!["#test".into()],
+            sasl_username: Some("bridge".into()),
+            sasl_password_file: Some(path.clone()),
+            ..IrcSettings::default()
+        };
+
+        std::fs::write(&path, "hunter22\n").unwrap();
+        drop(CustomIrcClient::connect_and_auth(&settings).unwrap());
+        std::fs::write(&path, "rotated2\n").unwrap();
+        drop(CustomIrcClient::connect_and_auth(&settings).unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        let auths: Vec<String> = server.received().into_iter().filter(|l| l.starts_with("AUTHENTICATE ") && l != "AUTHENTICATE PLAIN").collect();
+        assert_eq!(
+            auths,
+            vec![
+                format!("AUTHENTICATE {}", general_purpose::STANDARD.encode("\0bridge\0hunter22")),
+                format!("AUTHENTICATE {}", general_purpose::STANDARD.encode("\0bridge\0rotated2")),
+            ]
+        );
+        assert_eq!(CustomIrcClient::connect_and_auth(&settings).err().unwrap().kind(), io::ErrorKind::NotFound);
+    }
+
+    /// Replies that replace `default_responses` for the lines it answers.
+    type Override = fn(&str) -> Option<Vec<String>>;
+
+    /// Registers with SASL against a mock that answers like
+    /// `default_responses` except where `respond` has a reply.
+    fn sasl_attempt(respond: Override) -> (io::Result<CustomIrcClient>, Vec<String>) {
+        let server = MockIrcServer::start_with(
+            "127.0.0.1:0".parse().unwrap(),
+            Arc::new(move |line: &str| respond(line).unwrap_or_else(|| crate::mock_irc::default_responses(line))),
+        );
+        let settings = IrcSettings {
+            server: server.addr(),
+            nick: "bridge".into(),
+            channels: vec!["#test".into()],
+            sasl_username: Some("bridge".into()),
+            sasl_password: Some("hunter22".into()),
+            registration_timeout: Some(Duration::from_secs(2)),
+            ..IrcSettings::default()
+        };
+        let result = CustomIrcClient::connect_and_auth(&settings);
+        (result, server.received())
+    }
+
+    #[test]
+    fn sasl_plain_authenticates_before_cap_end() {
+        let (result, received) = sasl_attempt(|line| {
+            line.starts_with("AUTHENTICATE ").then_some(()).filter(|_| line != "AUTHENTICATE PLAIN").map(|_| {
+                vec![
+                    ":mock 900 bridge bridge!bridge@user-904.example bridge :You are now logged in as bridge".into(),
+                    ":mock 903 bridge :SASL authentication successful".into(),
+                ]
+            })
+        });
+        let client = result.unwrap();
+        assert!(client.caps.contains("sasl"));
+        let handshake: Vec<&str> = received.iter().map(String::as_str).filter(|l| l.starts_with("CAP") || l.starts_with("AUTHENTICATE")).collect();
+        let payload = format!("AUTHENTICATE {}", general_purpose::STANDARD.encode("\0bridge\0hunter22"));
+        assert_eq!(handshake, vec!["CAP LS 302", "CAP REQ :sasl server-time batch", "AUTHENTICATE PLAIN", payload.as_str(), "CAP END"]);
+        assert_eq!(payload, "AUTHENTICATE AGJyaWRnZQBodW50ZXIyMg==");
+    }
+
+    #[test]
+    fn sasl_failures_stop_registration() {
+        let cases: [(Override, io::ErrorKind, &str); 5] = [
+            (
+                |line| (line.starts_with("AUTHENTICATE ") && line != "AUTHENTICATE PLAIN").then(|| vec![":mock 904 bridge :SASL authentication failed".into()]),
+                io::ErrorKind::PermissionDenied,
+                "SASL authentication failed",
+            ),
+            (
+                |line| (line.starts_with("AUTHENTICATE ") && line != "AUTHENTICATE PLAIN").then(|| vec![":mock 905 bridge :SASL message too long".into()]),
+                io::ErrorKind::PermissionDenied,
+                "SASL authentication failed",
+            ),
+            (|line| line.starts_with("CAP LS").then(|| vec![":mock CAP * LS :server-time batch".into()]), io::ErrorKind::Unsupported, "does not offer SASL"),
+            (
+                |line| line.starts_with("CAP REQ").then(|| vec![format!(":mock CAP * NAK :{}", &line["CAP REQ :".len()..])]),
+                io::ErrorKind::Unsupported,
+                "refused the SASL capability",
+            ),
+            (
+                // Predates CAP.
+                |line| line.starts_with("CAP LS").then(|| vec![":mock 421 bridge CAP :Unknown command".into()]),
+                io::ErrorKind::Unsupported,
+                "capability negotiation",
+            ),
+        ];
+        for (respond, kind, message) in cases {
+            let (result, received) = sasl_attempt(respond);
+            let err = result.err().unwrap();
+            assert_eq!((err.kind(), message), (kind, message), "{}", err);
+            assert!(err.to_string().contains(message), "{}", err);
+            assert!(!received.iter().any(|l| l == "CAP END" || l.starts_with("JOIN")), "{:?}", received);
+        }
+    }
+
+    #[test]
+    fn a_refused_cap_request_does_not_cost_sasl() {
+        // Refuses any request with more than sasl in it.
+        let (result, received) = sasl_attempt(|line| (line.starts_with("CAP REQ") && line != "CAP REQ :sasl").then(|| vec![format!(":mock CAP * NAK :{}", &line["CAP REQ :".len()..])]));
+        let client = result.unwrap();
+        assert_eq!(client.caps, HashSet::from(["sasl".to_string()]));
+        assert!(received.contains(&"CAP REQ :sasl".to_string()));
+        assert!(received.contains(&"CAP END".to_string()));
+
+        // Without SASL, registration just goes on without the caps.
+        let server = MockIrcServer::start_with(
+            "127.0.0.1:0".parse().unwrap(),
+            Arc::new(|line: &str| {
+                if let Some(caps) = line.strip_prefix("CAP REQ :") {
+                    vec![format!(":mock CAP * NAK :{}", caps)]
+                } else {
+                    crate::mock_irc::default_responses(line)
+                }
+            }),
+        );
+        let settings = IrcSettings { server: server.addr(), nick: "bridge".into(), channels: vec!["#test".into()], ..IrcSettings::default() };
+        assert!(CustomIrcClient::connect_and_auth(&settings).unwrap().caps.is_empty());
+        assert!(server.wait_for(|l| l == "JOIN #test", Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn sasl_needs_plain_among_the_offered_mechanisms() {
+        let server = MockIrcServer::start_with(
+            "127.0.0.1:0".parse().unwrap(),
+            Arc::new(|line: &str| {
+                if line.starts_with("CAP LS") {
+                    vec![":mock CAP * LS :sasl=EXTERNAL server-time".into()]
+                } else {
+                    crate::mock_irc::default_responses(line)
+                }
+            }),
+        );
+        let settings = IrcSettings {
+            server: server.addr(),
+            nick: "bridge".into(),
+            sasl_username: Some("bridge".into()),
+            sasl_password: Some("hunter22".into()),
+            ..IrcSettings::default()
+        };
+        let err = CustomIrcClient::connect_and_auth(&settings).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert!(err.to_string().contains("only EXTERNAL"), "{}", err);
+    }
+
+    #[test]
+    fn error_during_registration_carries_the_reason() {
+        let server = MockIrcServer::start_with(
+            "127.0.0.1:0".parse().unwrap(),
+            Arc::new(|line: &str| {
+                if line.starts_with("USER ") {
+                    vec!["ERROR :Closing Link: 127.0.0.1 (K-Lined: spam)".into()]
+                } else {
+                    Vec::new()
+                }
+            }),
+        );
+        let settings = IrcSettings { server: server.addr(), nick: "bridge".into(), channels: vec!["#test".into()], ..IrcSettings::default() };
+
+        let err = CustomIrcClient::connect_and_auth(&settings).err().unwrap();
+        assert_eq!(server_error(&err), Some("Closing Link: 127.0.0.1 (K-Lined: spam)"));
+        assert_eq!(CloseKind::classify(server_error(&err).unwrap()), CloseKind::Banned);
+    }
+
+    #[test]
+    fn classifies_closing_reasons() {
+        assert_eq!(CloseKind::classify("Closing Link: host (G-Lined)"), CloseKind::Banned);
+        assert_eq!(CloseKind::classify("Closing Link: host (You are banned from this server)"), CloseKind::Banned);
+        assert_eq!(CloseKind::classify("Closing Link: host (Throttled: Reconnecting too fast)"), CloseKind::Throttled);
+        assert_eq!(CloseKind::classify("Closing Link: host (Killed (oper (go away)))"), CloseKind::Throttled);
+        assert_eq!(CloseKind::classify("Closing Link: host (Ping timeout: 240 seconds)"), CloseKind::Transient);
+        assert!(CloseKind::Transient.backoff().is_none());
+        assert!(CloseKind::Banned.backoff().unwrap().initial > CloseKind::Throttled.backoff().unwrap().initial);
+    }
+
+    #[test]
+    fn invalid_utf8_is_decoded_instead_of_dropping_the_connection() {
+        let server = MockIrcServer::start();
+        let settings = IrcSettings { server: server.addr(), nick: "bridge".into(), channels: vec!["#test".into()], ..IrcSettings::default() };
+        let mut client = CustomIrcClient::connect_and_auth(&settings).unwrap();
+        assert!(server.wait_for(|l| l == "JOIN #test", Duration::from_secs(2)));
+
+        server.send_bytes(b":alice!a@host PRIVMSG #test :caf\xe9 \xff\x00ok\r\n");
+        server.send(":bob!b@host PRIVMSG #test :still here");
+
+        let mut chat = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while chat.len() < 2 && Instant::now() < deadline {
+            match client.receive_message() {
+                Ok(line) => chat.extend(parse_irc_message(&line)),
+                Err(e) if is_read_timeout(&e) => {}
+                Err(e) => panic!("read failed: {}", e),
+            }
+        }
+
+        assert_eq!(chat.len(), 2);
+        assert_eq!(sanitize(Direction::IrcToAmnezichat, &chat[0].text, UnicodeFilter::Strip), "caf\u{e9} \u{ff}ok");
+        assert_eq!(chat[1].text, "still here");
+    }
+
+    #[test]
+    fn describes_presence_changes() {
+        let update = |raw: &str| Message::parse(raw).as_ref().and_then(presence_update);
+        assert_eq!(update(":alice!a@host AWAY :lunch").as_deref(), Some("alice is now away: lunch"));
+        assert_eq!(update(":alice!a@host AWAY").as_deref(), Some("alice is back"));
+        assert_eq!(update(":alice!a@host ACCOUNT alice_acct").as_deref(), Some("alice identified as alice_acct"));
+        assert_eq!(update(":alice!a@host ACCOUNT *").as_deref(), Some("alice logged out"));
+        assert_eq!(update(":alice!a@host PRIVMSG #test :hi"), None);
+    }
+
+    #[test]
+    fn kicks_and_moderation_of_the_bridge_are_noticed() {
+        let mut state = ChannelState::new();
+        let mut update = |raw: &str| {
+            let line = Message::parse(raw).unwrap();
+            channel_update(&line, &mut state, "#test", "bridge", Casemapping::default()).map(|u| (u.notice(&line, "#test"), u))
+        };
+
+        assert_eq!(update(":op!o@host KICK #other bridge :bye"), None);
+        assert_eq!(update(":op!o@host KICK #test alice :bye"), None);
+        assert_eq!(
+            update(":op!o@host KICK #Test Bridge :spam"),
+            Some((Some("The bridge was kicked from #test by op (spam); rejoining in 5s".into()), ChannelUpdate::Rejoin(Duration::from_secs(5))))
+        );
+        assert_eq!(update(":bridge!b@host JOIN #test"), Some((Some("The bridge rejoined #test".into()), ChannelUpdate::Rejoined)));
+        assert_eq!(update(":bridge!b@host JOIN #test"), Some((None, ChannelUpdate::Quiet)));
+        assert_eq!(
+            update(":op!o@host MODE #test +m"),
+            Some((
+                Some("#test is moderated and the bridge has no voice; room messages are not reaching IRC".into()),
+                ChannelUpdate::Mode(MuteChange::Muted)
+            ))
+        );
+        assert_eq!(
+            update(":mock 404 bridge #test :Cannot send to channel"),
+            Some((Some("The bridge cannot send to #test (Cannot send to channel)".into()), ChannelUpdate::CannotSend))
+        );
+        assert_eq!(update(":mock 404 bridge #test :Cannot send to channel"), Some((None, ChannelUpdate::Quiet)));
+        assert_eq!(update(":alice!a@host PRIVMSG #test :hi"), None);
+    }
+
+    #[test]
+    fn requested_summaries_report_users_and_topic() {
+        let mut state = ChannelState::new();
+        let mut update = |raw: &str| {
+            let line = Message::parse(raw).unwrap();
+            channel_update(&line, &mut state, "#test", "bridge", Casemapping::default()).and_then(|u| u.notice(&line, "#test"))
+        };
+
+        assert_eq!(update(":mock 332 bridge #test :Welcome to #test"), None);
+        assert_eq!(update(":mock 353 bridge = #test :bridge @op alice"), None);
+        assert_eq!(update(":mock 366 bridge #test :End of /NAMES list."), None);
+
+        state.request_summary();
+        let mut update = |raw: &str| {
+            let line = Message::parse(raw).unwrap();
+            channel_update(&line, &mut state, "#test", "bridge", Casemapping::default()).and_then(|u| u.notice(&line, "#test"))
+        };
+        assert_eq!(update(":op!o@host TOPIC #test :Release on Friday"), None);
+        assert_eq!(update(":mock 353 bridge = #other :carol"), None);
+        assert_eq!(update(":mock 353 bridge = #test :bridge @op"), None);
+        assert_eq!(update(":mock 353 bridge = #test :alice bob"), None);
+        assert_eq!(update(":mock 366 bridge #test :End of /NAMES list.").as_deref(), Some("#test: 4 users, topic: Release on Friday"));
+    }
+
+    #[test]
+    fn relayed_nicks_keep_what_irc_allows() {
+        assert_eq!(relay_nick("alice", '/').as_deref(), Some("alice/amz"));
+        assert_eq!(relay_nick("Zoë K.", '/').as_deref(), Some("ZoK/amz"));
+        assert_eq!(relay_nick("42-bob", '|').as_deref(), Some("bob|amz"));
+        assert_eq!(relay_nick("a_very_long_room_member_name", '/').as_deref(), Some("a_very_long_room/amz"));
+        assert_eq!(relay_nick("名前", '/'), None);
+        assert!(is_relay_nick("alice/amz", '/'));
+        assert!(!is_relay_nick("alice", '/'));
+    }
+
+    #[test]
+    fn the_wanted_nick_is_noticed_when_free() {
+        let news = |raw: &str, current: &str| nick_news(&Message::parse(raw).unwrap(), "bridge", current);
+        assert_eq!(news(":mock 731 bridge_ :other,bridge!b@host", "bridge_"), Some(NickNews::Free));
+        assert_eq!(news(":mock 731 bridge_ :other", "bridge_"), None);
+        assert_eq!(news(":mock 303 bridge_ :", "bridge_"), Some(NickNews::Free));
+        assert_eq!(news(":mock 303 bridge_ :Bridge", "bridge_"), None);
+        assert_eq!(news(":mock 303 bridge :", "bridge"), None, "already ours");
+        assert_eq!(news(":bridge_!b@host NICK :bridge", "bridge_"), Some(NickNews::Changed("bridge".into())));
+        assert_eq!(news(":alice!a@host NICK :bridge", "bridge_"), None);
+    }
+
+    #[test]
+    fn ctcp_commands_are_picked_out() {
+        assert_eq!(ctcp_command("\x01DCC SEND photo.jpg 3232235777 5000 1024\x01"), Some("DCC"));
+        assert_eq!(ctcp_command("\x01VERSION\x01"), Some("VERSION"));
+        assert_eq!(ctcp_command("\x01dcc CHAT chat 3232235777 5001"), Some("dcc"));
+        assert_eq!(ctcp_command("DCC SEND photo.jpg"), None);
+        assert_eq!(ctcp_command("\x01\x01"), None);
+    }
+
+    #[test]
+    fn channels_compare_under_the_server_casemapping() {
+        assert!(Casemapping::Ascii.same("#MyChannel", "#mychannel"));
+        assert!(!Casemapping::Ascii.same("#dev[1]", "#dev{1}"));
+        assert!(Casemapping::Rfc1459.same("#Dev[1]~", "#dev{1}^"));
+        assert!(Casemapping::StrictRfc1459.same("#Dev[1]", "#dev{1}"));
+        assert!(!Casemapping::StrictRfc1459.same("#a~", "#a^"));
+        assert_eq!(Casemapping::parse("STRICT-RFC1459"), Some(Casemapping::StrictRfc1459));
+        assert_eq!(Casemapping::parse("rfc7613"), None);
+    }
+
+    #[test]
+    fn own_nick_matches_under_rfc1459_casemapping() {
+        assert!(same_nick("Bridge[1]", "bridge{1}"));
+        assert!(same_nick("a\\b~", "A|B^"));
+        assert!(!same_nick("bridge", "bridge_"));
+    }
+
+    #[test]
+    fn room_messages_from_other_networks_are_relayed() {
+        assert_eq!(room_message_for_irc("[IRC]alice: hi", Some("libera")), None);
+        assert_eq!(room_message_for_irc("[IRC:libera]alice: hi", Some("Libera")), None);
+        assert_eq!(room_message_for_irc("[IRC:oftc]bob: hey", Some("libera")).as_deref(), Some("[oftc] bob: hey"));
+        assert_eq!(room_message_for_irc("[IRC:oftc]bob: hey", None).as_deref(), Some("[oftc] bob: hey"));
+        assert_eq!(room_message_for_irc("carol: hello", Some("libera")).as_deref(), Some("carol: hello"));
+        assert_eq!(origin_tag(Some("libera")), "[IRC:libera]");
+        assert_eq!(room_prefix(None, Some("[dev]")), "[IRC][dev] ");
+        // A labelled relay is still recognized as coming from IRC.
+        assert_eq!(room_message_for_irc("[IRC:libera][dev] alice: hi", Some("libera")), None);
+    }
+
+    #[test]
+    fn only_the_requested_history_is_replayed() {
+        let msgs: Vec<RoomEvent> = ["a", "b", "b", "c", "d"].iter().map(|m| RoomEvent::Message(m.to_string())).collect();
+        assert_eq!(history_to_skip(&msgs, 0), 4);
+        assert_eq!(history_to_skip(&msgs, 2), 2);
+        assert_eq!(history_to_skip(&msgs, 10), 0);
+    }
+
+    #[test]
+    fn nick_colors_are_stable_per_nick() {
+        assert_eq!(NickColors::default().label("alice"), "\x02\x0311alice >\x02\x03");
+        assert_eq!(NickColors::Single(4).label("alice"), "\x02\x0304alice >\x02\x03");
+        assert_eq!(NickColors::Off.label("alice"), "\x02alice >\x02");
+
+        let hashed = NickColors::Hashed;
+        assert_eq!(hashed.color_for("alice"), hashed.color_for("Alice"));
+        let colors: HashSet<_> = ["alice", "bob", "carol", "dave", "erin", "frank"].iter().map(|n| hashed.color_for(n)).collect();
+        assert!(colors.len() > 1);
+        assert!(colors.iter().all(|c| c.is_some_and(|c| NICK_PALETTE.contains(&c))));
+
+        assert_eq!(NickColors::parse("hash"), Some(NickColors::Hashed));
+        assert_eq!(NickColors::parse("7"), Some(NickColors::Single(7)));
+        assert_eq!(NickColors::parse("16"), None);
+    }
+
+    #[test]
+    fn multiline_room_messages_collapse_or_split() {
+        let segments = |content: &str, multiline| irc_segments(content, multiline, MarkupMode::Irc, UnicodeFilter::Strip, &NoTransform);
+        let alice = Some("alice".to_string());
+        let content = "alice: roses are red\n\nviolets are blue";
+        assert_eq!(segments(content, MultilineMode::Collapse), (alice.clone(), vec!["roses are red violets are blue".to_string()]));
+        assert_eq!(
+            segments(content, MultilineMode::Split),
+            (alice.clone(), vec!["roses are red".to_string(), "violets are blue".to_string()])
+        );
+
+        assert_eq!(
+            segments("<strong>alice</strong>: <em>so</em> <blink>cool</blink>", MultilineMode::Collapse),
+            (alice.clone(), vec!["\x1dso\x1d cool".to_string()])
+        );
+        // Formatting codes typed by a room member are still removed.
+        assert_eq!(segments("alice: \x02loud\x02", MultilineMode::Collapse), (alice, vec!["loud".to_string()]));
+
+        let long = (1..=12).map(|n| n.to_string()).collect::<Vec<_>>().join("\n");
+        let (user, lines) = segments(&long, MultilineMode::Split);
+        assert_eq!(user, None);
+        assert_eq!(lines.len(), MAX_SPLIT_LINES);
+        assert_eq!(lines.last().unwrap(), "8 9 10 11 12");
     }
-    let prefix = if t.starts_with(':') { &t[1..] } else { t };
-    let nick = prefix.split('!').next()?.to_string();
-    let target = parts[2].to_string();
-    let msg = parts[3].trim_start_matches(':').to_string();
-    Some((target, msg, nick))
-}
-
-pub fn run_bridge(
-    shared_secret: String,
-    amnezichat_url: String,
-    irc_url: String,
-    room_id: String,
-    irc_nick: String,
-    irc_channel: String,
-    sasl_username: Option<String>,
-    sasl_password: Option<String>,
-) -> io::Result<Bridge> {
-    Bridge::new(
-        shared_secret,
-        amnezichat_url,
-        irc_url,
-        room_id,
-        irc_nick,
-        irc_channel,
-        sasl_username,
-        sasl_password,
-    )
 }
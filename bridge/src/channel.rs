@@ -0,0 +1,377 @@
+use std::time::{Duration, Instant};
+
+use crate::bridge::same_nick;
+
+/// First rejoin delay after a kick; doubles with every further kick until
+/// `KICK_FORGET` passes without one.
+const REJOIN_DELAY: Duration = Duration::from_secs(5);
+const REJOIN_DELAY_MAX: Duration = Duration::from_secs(10 * 60);
+const KICK_FORGET: Duration = Duration::from_secs(30 * 60);
+/// A JOIN the server hasn't confirmed after this long is sent again, up to
+/// `JOIN_ATTEMPTS` times in all; some networks drop or delay the JOIN
+/// that creates an empty channel.
+const JOIN_RETRY: Duration = Duration::from_secs(5);
+const JOIN_ATTEMPTS: u32 = 4;
+
+/// What to do about a JOIN the server hasn't confirmed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum JoinRetry {
+    Again,
+    /// Every attempt failed; the reason the server gave for the last, if any.
+    GiveUp(Option<String>),
+}
+
+/// Whether a mode change stopped or restarted the bridge's messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MuteChange {
+    Muted,
+    Unmuted,
+}
+
+/// The bridge's standing in the bridged channel, as learned from KICK,
+/// MODE and 404 (cannot send to channel) lines, the topic and head count
+/// for the periodic summary, and who the channel operators are.
+#[derive(Default)]
+pub struct ChannelState {
+    moderated: bool,
+    /// Channel modes (`v`, `h`, `o`, ...) the bridge holds; any of them
+    /// lets it speak in a moderated channel.
+    privileges: Vec<char>,
+    kicks: u32,
+    last_kick: Option<Instant>,
+    /// Set while waiting to rejoin after a kick.
+    kicked: bool,
+    cannot_send_reported: bool,
+    topic: Option<String>,
+    users: usize,
+    /// Nicks counted so far from a NAMES reply that hasn't ended yet.
+    counting: Option<usize>,
+    summary_due: bool,
+    /// Nicks holding `o`, `a` or `q`, from NAMES and MODE lines.
+    operators: Vec<String>,
+    /// When the last JOIN went out, until the server confirms it.
+    join_sent: Option<Instant>,
+    join_attempts: u32,
+    join_error: Option<String>,
+}
+
+impl ChannelState {
+    /// For a channel whose JOIN has just been sent.
+    pub fn new() -> Self {
+        let mut state = Self::default();
+        state.join_sent(Instant::now());
+        state
+    }
+
+    /// Forgets modes after a reconnect, which sent a new JOIN; the kick
+    /// history is kept so a reconnect can't be used to skip the rejoin
+    /// backoff.
+    pub fn clear(&mut self) {
+        self.join_attempts = 0;
+        self.join_error = None;
+        self.join_sent(Instant::now());
+        self.moderated = false;
+        self.privileges.clear();
+        self.kicked = false;
+        self.cannot_send_reported = false;
+        self.topic = None;
+        self.users = 0;
+        self.counting = None;
+        self.summary_due = false;
+        self.operators.clear();
+    }
+
+    pub fn muted(&self) -> bool {
+        self.moderated && self.privileges.is_empty()
+    }
+
+    /// Records a kick and returns how long to wait before rejoining, so a
+    /// channel that keeps kicking the bridge doesn't get a kick-rejoin loop.
+    pub fn kicked(&mut self, now: Instant) -> Duration {
+        if self.last_kick.is_some_and(|t| now.duration_since(t) >= KICK_FORGET) {
+            self.kicks = 0;
+        }
+        self.kicks += 1;
+        self.last_kick = Some(now);
+        self.kicked = true;
+        self.privileges.clear();
+        REJOIN_DELAY.saturating_mul(1 << (self.kicks - 1).min(16)).min(REJOIN_DELAY_MAX)
+    }
+
+    /// Records our own JOIN; true when it ends a kick.
+    pub fn joined(&mut self) -> bool {
+        self.cannot_send_reported = false;
+        self.join_sent = None;
+        self.join_attempts = 0;
+        self.join_error = None;
+        std::mem::take(&mut self.kicked)
+    }
+
+    fn join_sent(&mut self, now: Instant) {
+        self.join_sent = Some(now);
+        self.join_attempts += 1;
+    }
+
+    /// Records a numeric refusing the JOIN (banned, invite only, ...); true
+    /// for the first since the JOIN was sent, so the room is told once.
+    pub fn join_failed(&mut self, reason: &str) -> bool {
+        self.join_error.replace(reason.to_string()).is_none()
+    }
+
+    /// Whether an unconfirmed JOIN should be sent again now. Giving up is
+    /// reported once.
+    pub fn join_retry(&mut self, now: Instant) -> Option<JoinRetry> {
+        if now.duration_since(self.join_sent?) < JOIN_RETRY {
+            return None;
+        }
+        if self.join_attempts >= JOIN_ATTEMPTS {
+            self.join_sent = None;
+            return Some(JoinRetry::GiveUp(self.join_error.take()));
+        }
+        self.join_sent(now);
+        Some(JoinRetry::Again)
+    }
+
+    /// Applies a channel MODE line (`modes` followed by its arguments).
+    /// Modes taking an argument are assumed to be the usual ones, since
+    /// ISUPPORT CHANMODES isn't tracked.
+    pub fn apply_mode(&mut self, modes: &str, args: &[String], own_nick: &str) -> Option<MuteChange> {
+        let was_muted = self.muted();
+        let mut args = args.iter();
+        let mut adding = true;
+        for mode in modes.chars() {
+            match mode {
+                '+' => adding = true,
+                '-' => adding = false,
+                'm' => self.moderated = adding,
+                'v' | 'h' | 'o' | 'a' | 'q' => {
+                    let Some(nick) = args.next() else { continue };
+                    if matches!(mode, 'o' | 'a' | 'q') {
+                        self.set_operator(nick, adding);
+                    }
+                    if same_nick(nick, own_nick) {
+                        self.privileges.retain(|&m| m != mode);
+                        if adding {
+                            self.privileges.push(mode);
+                        }
+                    }
+                }
+                'b' | 'e' | 'I' | 'k' => {
+                    args.next();
+                }
+                'l' if adding => {
+                    args.next();
+                }
+                _ => {}
+            }
+        }
+        if self.muted() != was_muted {
+            self.cannot_send_reported = false;
+        }
+        match (was_muted, self.muted()) {
+            (false, true) => Some(MuteChange::Muted),
+            (true, false) => Some(MuteChange::Unmuted),
+            _ => None,
+        }
+    }
+
+    /// Records a 404; true the first time since the bridge last joined or
+    /// its standing changed, so the room is told once.
+    pub fn cannot_send(&mut self) -> bool {
+        !std::mem::replace(&mut self.cannot_send_reported, true)
+    }
+
+    /// Records the topic from a 332 or TOPIC line; an empty one clears it.
+    pub fn set_topic(&mut self, topic: &str) {
+        self.topic = Some(topic.to_string()).filter(|t| !t.is_empty());
+    }
+
+    /// Marks that the next complete NAMES reply should be summarized.
+    pub fn request_summary(&mut self) {
+        self.summary_due = true;
+    }
+
+    /// Counts the nicks of one 353 line and notes the operators among
+    /// them, and the modes the bridge (`own_nick`) holds, since a server
+    /// that voices it on JOIN says so only here. The first line of a reply
+    /// replaces the operators known so far.
+    pub fn add_names(&mut self, names: &str, own_nick: &str) {
+        if self.counting.is_none() {
+            self.operators.clear();
+        }
+        *self.counting.get_or_insert(0) += names.split_whitespace().count();
+        for name in names.split_whitespace() {
+            // With multi-prefix a nick can carry several, e.g. `@+alice`.
+            let nick = name.trim_start_matches(['~', '&', '@', '%', '+']);
+            let prefixes = &name[..name.len() - nick.len()];
+            if prefixes.contains(['~', '&', '@']) {
+                self.set_operator(nick, true);
+            }
+            if same_nick(nick, own_nick) {
+                self.privileges = prefixes.chars().filter_map(prefix_mode).collect();
+            }
+        }
+    }
+
+    fn set_operator(&mut self, nick: &str, operator: bool) {
+        self.operators.retain(|n| !same_nick(n, nick));
+        if operator {
+            self.operators.push(nick.to_string());
+        }
+    }
+
+    pub fn is_operator(&self, nick: &str) -> bool {
+        self.operators.iter().any(|n| same_nick(n, nick))
+    }
+
+    /// Follows a nick change, so operator status goes with the nick.
+    pub fn renamed(&mut self, old: &str, new: &str) {
+        if self.is_operator(old) {
+            self.set_operator(old, false);
+            self.set_operator(new, true);
+        }
+    }
+
+    /// Forgets `nick` once it left the channel.
+    pub fn left(&mut self, nick: &str) {
+        self.set_operator(nick, false);
+    }
+
+    /// Ends a NAMES reply (366); once the summary is due, returns the user
+    /// count and topic to post.
+    pub fn names_done(&mut self) -> Option<(usize, Option<String>)> {
+        self.users = self.counting.take().unwrap_or(0);
+        std::mem::take(&mut self.summary_due).then(|| (self.users, self.topic.clone()))
+    }
+}
+
+/// The channel mode a NAMES prefix stands for.
+fn prefix_mode(prefix: char) -> Option<char> {
+    match prefix {
+        '~' => Some('q'),
+        '&' => Some('a'),
+        '@' => Some('o'),
+        '%' => Some('h'),
+        '+' => Some('v'),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn moderation_without_voice_mutes_the_bridge() {
+        let mut state = ChannelState::new();
+        assert_eq!(state.apply_mode("+mb", &args(&["*!*@spam"]), "bridge"), Some(MuteChange::Muted));
+        assert_eq!(state.apply_mode("+vo", &args(&["alice", "Bridge"]), "bridge"), Some(MuteChange::Unmuted));
+        assert_eq!(state.apply_mode("-o", &args(&["bridge"]), "bridge"), Some(MuteChange::Muted));
+        assert_eq!(state.apply_mode("+l-m", &args(&["50"]), "bridge"), Some(MuteChange::Unmuted));
+        assert_eq!(state.apply_mode("+v", &args(&["bridge"]), "bridge"), None);
+        assert!(!state.muted());
+    }
+
+    #[test]
+    fn repeated_kicks_back_off_the_rejoin() {
+        let mut state = ChannelState::new();
+        let start = Instant::now();
+        assert_eq!(state.kicked(start), Duration::from_secs(5));
+        assert!(state.joined());
+        assert!(!state.joined());
+        assert_eq!(state.kicked(start + Duration::from_secs(10)), Duration::from_secs(10));
+        assert_eq!(state.kicked(start + Duration::from_secs(20)), Duration::from_secs(20));
+        for n in 0..10 {
+            state.kicked(start + Duration::from_secs(30 + n));
+        }
+        assert_eq!(state.kicked(start + Duration::from_secs(60)), REJOIN_DELAY_MAX);
+        assert_eq!(state.kicked(start + Duration::from_secs(60) + KICK_FORGET), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn an_unconfirmed_join_is_retried_then_given_up() {
+        let mut state = ChannelState::new();
+        let start = Instant::now();
+        assert_eq!(state.join_retry(start), None);
+        assert!(state.join_failed("Cannot join channel (+i)"));
+        assert!(!state.join_failed("Cannot join channel (+i)"));
+        let mut at = start;
+        for _ in 1..JOIN_ATTEMPTS {
+            at += JOIN_RETRY;
+            assert_eq!(state.join_retry(at), Some(JoinRetry::Again));
+        }
+        at += JOIN_RETRY;
+        assert_eq!(state.join_retry(at), Some(JoinRetry::GiveUp(Some("Cannot join channel (+i)".into()))));
+        assert_eq!(state.join_retry(at + JOIN_RETRY), None);
+
+        let mut state = ChannelState::new();
+        state.joined();
+        assert_eq!(state.join_retry(Instant::now() + JOIN_RETRY), None);
+    }
+
+    #[test]
+    fn cannot_send_is_reported_once() {
+        let mut state = ChannelState::new();
+        assert!(state.cannot_send());
+        assert!(!state.cannot_send());
+        state.joined();
+        assert!(state.cannot_send());
+    }
+
+    #[test]
+    fn names_are_summarized_only_when_asked() {
+        let mut state = ChannelState::new();
+        state.set_topic("Welcome");
+        state.add_names("@op alice bob", "bridge");
+        assert_eq!(state.names_done(), None);
+
+        state.request_summary();
+        state.add_names("@op alice", "bridge");
+        state.add_names("carol", "bridge");
+        assert_eq!(state.names_done(), Some((3, Some("Welcome".into()))));
+        state.set_topic("");
+        state.request_summary();
+        assert_eq!(state.names_done(), Some((0, None)));
+    }
+
+    #[test]
+    fn operators_are_tracked_through_names_modes_and_nick_changes() {
+        let mut state = ChannelState::new();
+        state.add_names("@op +voiced @+both ~owner alice", "bridge");
+        state.names_done();
+        for nick in ["op", "both", "owner"] {
+            assert!(state.is_operator(nick), "{}", nick);
+        }
+        assert!(!state.is_operator("voiced"));
+        assert!(!state.is_operator("alice"));
+
+        state.apply_mode("+o-o", &args(&["alice", "OP"]), "bridge");
+        assert!(state.is_operator("alice"));
+        assert!(!state.is_operator("op"));
+        state.renamed("alice", "alice2");
+        assert!(state.is_operator("alice2"));
+        assert!(!state.is_operator("alice"));
+        state.left("alice2");
+        assert!(!state.is_operator("alice2"));
+
+        state.add_names("carol", "bridge");
+        assert!(!state.is_operator("both"));
+    }
+
+    #[test]
+    fn a_voice_given_on_join_counts() {
+        let mut state = ChannelState::new();
+        state.add_names("@op +Bridge alice", "bridge");
+        state.names_done();
+        assert_eq!(state.apply_mode("+m", &[], "bridge"), None);
+        assert!(!state.muted());
+
+        state.add_names("@op bridge", "bridge");
+        state.names_done();
+        assert!(state.muted());
+    }
+}
@@ -0,0 +1,384 @@
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::bridge::{Mapping, MultilineMode, NickColors, NickRegain};
+use crate::commands::BUILTIN_COMMANDS;
+use crate::encryption::ROOM_KEY_LEN;
+use crate::flood::FloodLimit;
+use crate::identity::IdentifyPolicy;
+use crate::logging::LogFormat;
+use crate::markup::MarkupMode;
+use crate::network_operations::{RedirectPolicy, WrongPassword};
+use crate::oversize::{OversizePolicy, MIN_PART_BYTES};
+use crate::pin;
+use crate::queue::OverflowPolicy;
+use crate::same_person::SamePersonMode;
+use crate::sanitize::UnicodeFilter;
+use crate::transform::StripUrls;
+use crate::{AppState, MIN_ROOM_ID_LENGTH};
+
+/// Applies `--option value` (or `--option=value`) command line flags on top
+/// of the defaults. Everything not covered here is still asked for
+/// interactively.
+pub fn apply_args<I>(state: &mut AppState, args: I) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+            None => (arg, None),
+        };
+        let mut value = || -> Result<String, Box<dyn Error + Send + Sync>> {
+            match inline.clone() {
+                Some(v) => Ok(v),
+                None => args.next().ok_or_else(|| format!("{} expects a value", flag).into()),
+            }
+        };
+
+        match flag.as_str() {
+            "--verify-identified" => {
+                state.options.identify_policy = IdentifyPolicy::parse(&value()?)
+                    .ok_or("--verify-identified expects off, drop or tag")?;
+            }
+            "--who-on-join" => state.options.who_on_join = true,
+            "--unicode-filter" => {
+                state.options.unicode_filter = UnicodeFilter::parse(&value()?)
+                    .ok_or("--unicode-filter expects off, strip or flag")?;
+            }
+            "--command-prefix" => {
+                let prefix = value()?;
+                if prefix.trim().is_empty() {
+                    return Err("--command-prefix must not be empty".into());
+                }
+                state.options.command_prefix = prefix;
+            }
+            "--disable-command" => {
+                let name = value()?.trim().to_lowercase();
+                if name != "all" && !BUILTIN_COMMANDS.contains(&name.as_str()) {
+                    return Err(format!("--disable-command expects all or one of: {}", BUILTIN_COMMANDS.join(", ")).into());
+                }
+                state.options.disabled_commands.push(name);
+            }
+            "--relay-notices" => state.options.relay_notices = true,
+            "--idle-timeout" => {
+                let secs: u64 = value()?.parse().map_err(|_| "--idle-timeout expects a number of seconds")?;
+                if secs < 30 {
+                    return Err("--idle-timeout must be at least 30 seconds".into());
+                }
+                state.options.idle_timeout = Duration::from_secs(secs);
+            }
+            "--max-missed-pongs" => {
+                state.options.max_missed_pongs = value()?
+                    .parse()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .ok_or("--max-missed-pongs expects a positive number")?;
+            }
+            "--multiline" => {
+                state.options.multiline = MultilineMode::parse(&value()?)
+                    .ok_or("--multiline expects collapse or split")?;
+            }
+            "--markup" => {
+                state.options.markup = MarkupMode::parse(&value()?).ok_or("--markup expects irc or strip")?;
+            }
+            "--nick-colors" => {
+                state.options.nick_colors = NickColors::parse(&value()?)
+                    .ok_or("--nick-colors expects off, hash or a colour number 0-15")?;
+            }
+            "--label-to-room" => state.options.label_to_room = Some(parse_label("--label-to-room", &value()?)?),
+            "--label-to-irc" => state.options.label_to_irc = Some(parse_label("--label-to-irc", &value()?)?),
+            "--user-agent" => state.http.user_agent = value()?,
+            "--redirects" => {
+                state.http.redirects = RedirectPolicy::parse(&value()?).ok_or("--redirects expects none or same-origin")?;
+            }
+            "--envelope-begin" => state.envelope.begin = parse_marker("--envelope-begin", &value()?)?,
+            "--envelope-end" => state.envelope.end = parse_marker("--envelope-end", &value()?)?,
+            "--pool-max-idle" => {
+                state.http.pool_max_idle = value()?.parse().map_err(|_| "--pool-max-idle expects a number of connections")?;
+            }
+            "--pool-idle-timeout" => {
+                let timeout = value()?;
+                state.http.pool_idle_timeout = if timeout.trim().eq_ignore_ascii_case("off") {
+                    None
+                } else {
+                    Some(parse_duration(&timeout).ok_or("--pool-idle-timeout expects a duration such as 90 or 5m, or off")?)
+                };
+            }
+            "--pin-pubkey" => {
+                state.http.pinned_key = Some(pin::parse(&value()?).ok_or("--pin-pubkey expects sha256// followed by the base64 SHA-256 of the key")?);
+            }
+            "--header" => {
+                let header = value()?;
+                let (name, val) = header.split_once(':').ok_or("--header expects name:value")?;
+                state.http.headers.push((name.trim().to_string(), val.trim().to_string()));
+            }
+            "--quit-message" => state.options.quit_message = value()?,
+            "--part-on-quit" => state.options.part_on_quit = true,
+            "--network" => {
+                let name = value()?;
+                if name.is_empty() || name.contains(|c: char| c == ']' || c.is_whitespace()) {
+                    return Err("--network expects a name without spaces or ']'".into());
+                }
+                state.options.network = Some(name);
+            }
+            "--connect-timeout" => {
+                let secs: u64 = value()?
+                    .parse()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .ok_or("--connect-timeout expects a positive number of seconds")?;
+                state.connect_timeout = Some(Duration::from_secs(secs));
+            }
+            "--flood-limit" => {
+                let limit = value()?;
+                state.options.flood_limit = if limit.trim().eq_ignore_ascii_case("off") {
+                    None
+                } else {
+                    Some(FloodLimit::parse(&limit).ok_or("--flood-limit expects N/SECS or off")?)
+                };
+            }
+            "--flood-notice" => state.options.flood_notice = true,
+            "--room-status" => state.options.room_status = true,
+            "--strip-urls" => state.options.transform = Arc::new(StripUrls),
+            "--strip-irc-formatting" => state.options.strip_irc_formatting = true,
+            "--room-id-length" => {
+                let length: usize = value()?.parse().map_err(|_| "--room-id-length expects a number")?;
+                if length < MIN_ROOM_ID_LENGTH {
+                    return Err(format!("--room-id-length must be at least {}", MIN_ROOM_ID_LENGTH).into());
+                }
+                state.room_id_format.length = length;
+            }
+            "--room-id-file" => state.room_id_file = Some(value()?.into()),
+            "--room-id-charset" => {
+                let mut charset = value()?.into_bytes();
+                charset.sort_unstable();
+                charset.dedup();
+                if charset.len() < 16 || !charset.iter().all(|b| b.is_ascii_graphic()) {
+                    return Err("--room-id-charset expects at least 16 distinct printable ASCII characters".into());
+                }
+                state.room_id_format.charset = charset;
+            }
+            "--room-key" => {
+                // Stored the way derive_key's output is encoded, since the
+                // hex string itself is what messages are encrypted with.
+                let key = value()?.trim().to_ascii_lowercase();
+                match hex::decode(&key) {
+                    Ok(bytes) if bytes.len() == ROOM_KEY_LEN => state.room_key = Some(key),
+                    _ => return Err("--room-key expects 64 hex characters (a 32-byte key)".into()),
+                }
+            }
+            "--keyring" => {
+                let entry = value()?;
+                match entry.split_once(':') {
+                    Some((service, account)) if !service.is_empty() && !account.is_empty() => {
+                        state.keyring = Some((service.to_string(), account.to_string()));
+                    }
+                    _ => return Err("--keyring expects service:account".into()),
+                }
+            }
+            "--admin-password" => {
+                let password = value()?;
+                if password.len() < 8 || password.contains(char::is_whitespace) {
+                    return Err("--admin-password expects at least 8 characters without spaces".into());
+                }
+                state.options.admin_password = Some(password);
+            }
+            "--on-wrong-password" => {
+                state.wrong_password = WrongPassword::parse(&value()?).ok_or("--on-wrong-password expects fail or warn")?;
+            }
+            "--sign-key" => {
+                let key = hex::decode(value()?.trim()).ok().and_then(|bytes| <[u8; 32]>::try_from(bytes).ok());
+                state.options.signing_key = Some(key.ok_or("--sign-key expects 64 hex characters (a 32-byte key)")?);
+            }
+            "--sasl-password-file" => state.sasl_password_file = Some(value()?.into()),
+            "--config" => state.config = Some(value()?.into()),
+            "--seal-config" => state.seal_config = Some(value()?.into()),
+            "--trace-irc" => state.trace_irc = true,
+            "--log-format" => state.log_format = LogFormat::parse(&value()?).ok_or("--log-format expects text or json")?,
+            "--mirror" => state.mirrors.push(value()?.trim().to_string()),
+            "--map" => state.extra_mappings.push(parse_mapping(&value()?)?),
+            "--no-irc-to-amnezichat" => state.options.relay_irc_to_amnezichat = false,
+            "--no-amnezichat-to-irc" => state.options.relay_amnezichat_to_irc = false,
+            "--status-addr" => state.status_addr = Some(value()?),
+            "--liveness-file" => state.options.liveness_file = Some(value()?.into()),
+            "--spool" => state.options.spool = Some(value()?.into()),
+            "--idle-disconnect" => {
+                let after = parse_duration(&value()?).ok_or("--idle-disconnect expects a duration such as 3600 or 1h")?;
+                if after < Duration::from_secs(60) {
+                    return Err("--idle-disconnect must be at least a minute".into());
+                }
+                state.options.idle_disconnect = Some(after);
+            }
+            "--idle-poll-interval" => {
+                let interval = parse_duration(&value()?).ok_or("--idle-poll-interval expects a duration such as 30 or 2m")?;
+                if interval.is_zero() {
+                    return Err("--idle-poll-interval must be more than zero".into());
+                }
+                state.options.idle_poll_interval = interval;
+            }
+            "--queue-size" => {
+                state.options.queue_size = value()?
+                    .parse()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .ok_or("--queue-size expects a positive number")?;
+            }
+            "--max-room-sends" => {
+                state.options.max_room_sends = value()?
+                    .parse()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .ok_or("--max-room-sends expects a positive number")?;
+            }
+            "--room-max-bytes" => {
+                let max_bytes: usize = value()?.parse().map_err(|_| "--room-max-bytes expects a number of bytes")?;
+                if max_bytes < MIN_PART_BYTES {
+                    return Err(format!("--room-max-bytes must be at least {}", MIN_PART_BYTES).into());
+                }
+                state.options.room_limit.max_bytes = Some(max_bytes);
+            }
+            "--room-oversize" => {
+                state.options.room_limit.policy = OversizePolicy::parse(&value()?).ok_or("--room-oversize expects split or truncate")?;
+            }
+            "--queue-overflow" => {
+                state.options.queue_overflow = OverflowPolicy::parse(&value()?)
+                    .ok_or("--queue-overflow expects block, drop-oldest or drop-newest")?;
+            }
+            "--presence" => state.presence = true,
+            "--relaymsg" => state.relaymsg = true,
+            "--irc-send-delay" => {
+                let delay = parse_duration(&value()?).ok_or("--irc-send-delay expects a duration such as 750ms or 2s")?;
+                state.send_delay = Some(delay).filter(|d| !d.is_zero());
+            }
+            "--regain-nick" => state.regain_nick = NickRegain::parse(&value()?).ok_or("--regain-nick expects monitor, ison or off")?,
+            "--channel-summary" => {
+                let interval = parse_duration(&value()?).ok_or("--channel-summary expects a duration such as 600 or 10m")?;
+                if interval < Duration::from_secs(60) {
+                    return Err("--channel-summary must be at least a minute".into());
+                }
+                state.options.channel_summary = Some(interval);
+            }
+            "--registration-timeout" => {
+                let secs: u64 = value()?
+                    .parse()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .ok_or("--registration-timeout expects a positive number of seconds")?;
+                state.registration_timeout = Some(Duration::from_secs(secs));
+            }
+            "--ident" => {
+                let ident = value()?;
+                if ident.is_empty() || ident.contains(|c: char| c.is_whitespace() || c == '@') {
+                    return Err("--ident expects a single word".into());
+                }
+                state.ident = Some(ident);
+            }
+            "--realname" => {
+                let realname = value()?.replace(['\r', '\n'], " ");
+                if realname.trim().is_empty() {
+                    return Err("--realname must not be empty".into());
+                }
+                state.realname = Some(realname);
+            }
+            "--rejoin-delay" => {
+                let delay = parse_duration(&value()?).ok_or("--rejoin-delay expects a duration such as 30 or 2m")?;
+                state.rejoin_delay = Some(delay).filter(|d| !d.is_zero());
+            }
+            "--rejoin-announce" => {
+                let text = value()?.replace(['\r', '\n'], " ");
+                if text.trim().is_empty() {
+                    return Err("--rejoin-announce must not be empty".into());
+                }
+                state.rejoin_announce = Some(text);
+            }
+            "--log-size" => state.options.log_size = value()?.parse().map_err(|_| "--log-size expects a number")?,
+            "--replay-history" => {
+                state.options.replay_history = value()?.parse().map_err(|_| "--replay-history expects a number of messages")?;
+            }
+            "--quote-replies" => state.options.quote_replies = true,
+            "--relay-reactions" => state.options.relay_reactions = true,
+            "--same-person" => {
+                if !state.options.same_person.add(&value()?) {
+                    return Err("--same-person expects irc-nick=amnezichat-name".into());
+                }
+            }
+            "--same-person-mode" => {
+                state.options.same_person.mode = SamePersonMode::parse(&value()?).ok_or("--same-person-mode expects suppress or annotate")?;
+            }
+            "--max-uptime" => {
+                let uptime = parse_duration(&value()?).ok_or("--max-uptime expects a duration such as 3600, 90m, 24h or 7d")?;
+                if uptime < Duration::from_secs(60) {
+                    return Err("--max-uptime must be at least a minute".into());
+                }
+                state.max_uptime = Some(uptime);
+            }
+            other => return Err(format!("Unknown option: {}", other).into()),
+        }
+    }
+    Ok(())
+}
+
+/// Parses a number of seconds, or of milliseconds, minutes, hours or days
+/// with an `ms`, `m`, `h` or `d` suffix.
+fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Some(millis) = value.strip_suffix("ms") {
+        return millis.parse().ok().map(Duration::from_millis);
+    }
+    let (number, unit) = match value.char_indices().last()? {
+        (i, c) if c.is_ascii_alphabetic() => (&value[..i], c.to_ascii_lowercase()),
+        _ => (value, 's'),
+    };
+    let scale = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(scale).map(Duration::from_secs)
+}
+
+/// Envelope markers are matched literally; one that could occur inside the
+/// hex payload would cut it.
+fn parse_marker(flag: &str, marker: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
+    if marker.len() < 3 || marker.chars().all(|c| c.is_ascii_hexdigit() || c == ':') {
+        return Err(format!("{} expects a marker that can't be part of a payload", flag).into());
+    }
+    Ok(marker.to_string())
+}
+
+/// Parses `#channel=room-id:hex-key`, a further channel bridged to its own
+/// room over the same IRC connection. The key is given like `--room-key`.
+pub fn parse_mapping(spec: &str) -> Result<Mapping, Box<dyn Error + Send + Sync>> {
+    const USAGE: &str = "--map expects #channel=room-id:key, with the key as 64 hex characters";
+    let (channel, room) = spec.trim().split_once('=').ok_or(USAGE)?;
+    let (room_id, key) = room.split_once(':').ok_or(USAGE)?;
+    let key = key.to_ascii_lowercase();
+    if !channel.starts_with(['#', '&']) || channel.contains([' ', ',']) || room_id.is_empty() {
+        return Err(USAGE.into());
+    }
+    match hex::decode(&key) {
+        Ok(bytes) if bytes.len() == ROOM_KEY_LEN => {
+            Ok(Mapping { channel: channel.to_string(), room_id: room_id.to_string(), shared_secret: key })
+        }
+        _ => Err(USAGE.into()),
+    }
+}
+
+/// A reader-facing label must not be mistaken for the `[IRC]`/`[AMZ]` loop
+/// markers, or relayed messages would be dropped as echoes.
+fn parse_label(flag: &str, label: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let label = label.trim();
+    if label.is_empty() || label.contains(['\r', '\n']) {
+        return Err(format!("{} expects a single-line label", flag).into());
+    }
+    let upper = label.to_ascii_uppercase();
+    if upper.starts_with("[IRC") || upper.starts_with("[AMZ]") {
+        return Err(format!("{} must not start with [IRC or [AMZ], which mark relayed messages", flag).into());
+    }
+    Ok(label.to_string())
+}
@@ -0,0 +1,60 @@
+/// Commands the bridge answers by itself.
+pub const BUILTIN_COMMANDS: &[&str] = &["amnezichat", "log", "bridge", "roomid"];
+
+/// Whether `command` was turned off with `--disable-command`, by name or
+/// with `all`. A disabled command is relayed like any other message.
+pub fn is_disabled(command: &str, disabled: &[String]) -> bool {
+    disabled.iter().any(|d| d == "all" || d == command)
+}
+
+/// Splits a bridge command off a message. `prefix` may contain `{nick}`,
+/// which stands for the bridge's current nick, so `{nick}:` accepts
+/// `bridge: amnezichat`. Returns the lowercased command name and the rest of
+/// the line.
+pub fn parse_command(msg: &str, prefix: &str, nick: &str) -> Option<(String, String)> {
+    let prefix = prefix.replace("{nick}", nick);
+    if prefix.is_empty() {
+        return None;
+    }
+    let msg = msg.trim();
+    let head = msg.get(..prefix.len())?;
+    if !head.eq_ignore_ascii_case(&prefix) {
+        return None;
+    }
+
+    let rest = msg[prefix.len()..].trim_start();
+    let (name, args) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    if name.is_empty() {
+        return None;
+    }
+    Some((name.to_lowercase(), args.trim().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_default_prefix() {
+        assert_eq!(parse_command(".amnezichat", ".", "bridge"), Some(("amnezichat".into(), "".into())));
+        assert_eq!(parse_command("  .LOG 10 ", ".", "bridge"), Some(("log".into(), "10".into())));
+        assert_eq!(parse_command("amnezichat", ".", "bridge"), None);
+        assert_eq!(parse_command(".", ".", "bridge"), None);
+    }
+
+    #[test]
+    fn commands_can_be_disabled_by_name_or_all_at_once() {
+        assert!(!is_disabled("amnezichat", &[]));
+        assert!(is_disabled("amnezichat", &["amnezichat".into()]));
+        assert!(!is_disabled("log", &["amnezichat".into()]));
+        assert!(is_disabled("log", &["all".into()]));
+    }
+
+    #[test]
+    fn parses_custom_and_nick_prefixes() {
+        assert_eq!(parse_command("!amnezichat", "!", "bridge"), Some(("amnezichat".into(), "".into())));
+        assert_eq!(parse_command(".amnezichat", "!", "bridge"), None);
+        assert_eq!(parse_command("Bridge: amnezichat", "{nick}:", "bridge"), Some(("amnezichat".into(), "".into())));
+        assert_eq!(parse_command("alice: amnezichat", "{nick}:", "bridge"), None);
+    }
+}
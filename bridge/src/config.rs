@@ -0,0 +1,203 @@
+//! `--config`: the startup answers and flags kept in a passphrase-encrypted
+//! file, so the room password and SASL credentials never sit on disk in
+//! the clear. The file is sealed with the same Argon2 and ChaCha20-Poly1305
+//! construction as room messages, and only ever decrypted in memory.
+//!
+//! Decrypted, it holds one `name = value` per line; `#` starts a comment.
+//! The names below answer the startup prompts, and any other name is taken
+//! as a command line flag (`map = #dev=room:key`, or just `part-on-quit`).
+//!
+//! A `[network name]` line starts a further IRC network, bridged over its
+//! own connection in the same process. Its section takes the connection
+//! settings (`irc-url`, `nick`, `server-password`, `sasl-username`,
+//! `sasl-password`) and one or more `map` lines; everything else is shared.
+
+use std::error::Error;
+use std::path::Path;
+
+use zeroize::Zeroize;
+
+use crate::bridge::Mapping;
+use crate::encryption::{decrypt_data, encrypt_data};
+use crate::{cli, AppState, DEFAULT_NETWORK};
+
+/// An IRC network from a `[network name]` section.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Network {
+    /// Also the tag its messages carry in the rooms, as with `--network`.
+    pub name: String,
+    pub irc_url: String,
+    pub nick: String,
+    pub server_password: Option<String>,
+    pub sasl_username: Option<String>,
+    pub sasl_password: Option<String>,
+    pub mappings: Vec<Mapping>,
+}
+
+/// Encrypts `text` for `--config`.
+pub fn seal(text: &str, passphrase: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
+    encrypt_data(text, passphrase)
+}
+
+/// Decrypts the config at `path` and applies it to `state`.
+pub fn load(state: &mut AppState, path: &Path, passphrase: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let sealed = std::fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    let mut text = decrypt_data(sealed.trim(), passphrase)
+        .map_err(|_| format!("Cannot decrypt {}: wrong passphrase, or not a sealed config", path.display()))?;
+    let result = apply(state, &text);
+    text.zeroize();
+    result
+}
+
+/// Applies decrypted config lines on top of `state`.
+pub fn apply(state: &mut AppState, text: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut network: Option<Network> = None;
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            let name = section.strip_prefix("network").map(str::trim).filter(|n| !n.is_empty() && !n.contains(char::is_whitespace));
+            let Some(name) = name else {
+                return Err(format!("Config line {}: expected [network name]", number + 1).into());
+            };
+            if name.eq_ignore_ascii_case(DEFAULT_NETWORK) {
+                return Err(format!("Config line {}: the network name {} is taken by the first connection", number + 1, DEFAULT_NETWORK).into());
+            }
+            if state.networks.iter().chain(&network).any(|n| n.name.eq_ignore_ascii_case(name)) {
+                return Err(format!("Config line {}: network {} is described twice", number + 1, name).into());
+            }
+            if let Some(done) = network.replace(Network { name: name.to_string(), ..Network::default() }) {
+                state.networks.push(check_network(done)?);
+            }
+            continue;
+        }
+        let (name, value) = match line.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().to_string())),
+            None => (line, None),
+        };
+        let at = |e: Box<dyn Error + Send + Sync>| format!("Config line {}: {}", number + 1, e);
+        let required = || value.clone().filter(|v| !v.is_empty()).ok_or_else(|| format!("Config line {}: {} expects a value", number + 1, name));
+        if let Some(network) = &mut network {
+            match name {
+                "irc-url" => network.irc_url = required()?,
+                "nick" => network.nick = required()?,
+                "server-password" => network.server_password = Some(required()?),
+                "sasl-username" => network.sasl_username = Some(required()?),
+                "sasl-password" => network.sasl_password = Some(required()?),
+                "map" => network.mappings.push(cli::parse_mapping(&required()?).map_err(at)?),
+                _ => return Err(format!("Config line {}: {} can't be set per network; put it before the first [network]", number + 1, name).into()),
+            }
+            continue;
+        }
+        match name {
+            "amnezichat-url" => state.amnezichat_url = required()?,
+            "irc-url" => state.irc_url = required()?,
+            "nick" => state.username = required()?,
+            "room-password" => state.room_password = required()?,
+            "room-id" => state.room_id_input = required()?,
+            "channel" => state.irc_channel = required()?,
+            "server-password" => state.server_password = Some(required()?),
+            "sasl-username" => state.sasl_username = Some(required()?),
+            "sasl-password" => state.sasl_password = Some(required()?),
+            "config" | "seal-config" => return Err(format!("Config line {}: {} can't be set from a config file", number + 1, name).into()),
+            flag => {
+                let args = std::iter::once(format!("--{}", flag)).chain(value);
+                cli::apply_args(state, args).map_err(at)?;
+            }
+        }
+    }
+    if let Some(done) = network {
+        state.networks.push(check_network(done)?);
+    }
+    // The name tags the network's messages in the room, so the first
+    // connection's `--network` can't be reused by a section.
+    if let Some(own) = &state.options.network {
+        if let Some(clash) = state.networks.iter().find(|n| n.name.eq_ignore_ascii_case(own)) {
+            return Err(format!("Network {} is also the name given by --network", clash.name).into());
+        }
+    }
+    Ok(())
+}
+
+fn check_network(network: Network) -> Result<Network, Box<dyn Error + Send + Sync>> {
+    if network.irc_url.is_empty() || network.nick.is_empty() || network.mappings.is_empty() {
+        return Err(format!("Network {} needs irc-url, nick and at least one map", network.name).into());
+    }
+    if network.sasl_username.is_some() != network.sasl_password.is_some() {
+        return Err(format!("Network {} needs both sasl-username and sasl-password, or neither", network.name).into());
+    }
+    Ok(network)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "\
+# Answers to the prompts
+amnezichat-url = https://amnezichat.example
+nick = bridge
+room-password = correct horse battery
+sasl-password = hunter22=still the password
+part-on-quit
+log-size = 10
+";
+
+    #[test]
+    fn answers_and_flags_are_applied() {
+        let mut state = AppState::default();
+        apply(&mut state, CONFIG).unwrap();
+        assert_eq!(state.amnezichat_url, "https://amnezichat.example");
+        assert_eq!(state.username, "bridge");
+        assert_eq!(state.room_password, "correct horse battery");
+        assert_eq!(state.sasl_password.as_deref(), Some("hunter22=still the password"));
+        assert!(state.options.part_on_quit);
+        assert_eq!(state.options.log_size, 10);
+
+        let error = apply(&mut AppState::default(), "nick = bridge\nlog-size = many").unwrap_err();
+        assert!(error.to_string().starts_with("Config line 2:"), "{}", error);
+        assert!(apply(&mut AppState::default(), "config = other.age").is_err());
+        assert!(apply(&mut AppState::default(), "nick =").is_err());
+    }
+
+    #[test]
+    fn network_sections_describe_further_connections() {
+        let key = "ab".repeat(32);
+        let text = format!(
+            "nick = bridge\n[network oftc]\nirc-url = irc.oftc.net:6667\nnick = amz\nsasl-username = amz\nsasl-password = secret\nmap = #dev=room2:{key}\n\n[network tilde]\nirc-url = irc.tilde.chat:6667\nnick = amz\nmap = #meta=room3:{key}\n"
+        );
+        let mut state = AppState::default();
+        apply(&mut state, &text).unwrap();
+        assert_eq!(state.username, "bridge");
+        let names: Vec<&str> = state.networks.iter().map(|n| n.name.as_str()).collect();
+        assert_eq!(names, ["oftc", "tilde"]);
+        assert_eq!(state.networks[0].sasl_password.as_deref(), Some("secret"));
+        assert_eq!(state.networks[1].mappings[0].room_id, "room3");
+
+        let error = |text: &str| apply(&mut AppState::default(), text).unwrap_err().to_string();
+        assert!(error("[network oftc]\nirc-url = irc.oftc.net:6667\nnick = amz").contains("at least one map"));
+        assert!(error("[network oftc]\npart-on-quit").contains("can't be set per network"));
+        assert!(error("[oftc]").contains("expected [network name]"));
+        assert!(error("[network Default]").contains("taken by the first connection"));
+        let twice = format!("[network a]\nirc-url = x:1\nnick = n\nmap = #a=r:{key}\n[network A]\n");
+        assert!(error(&twice).contains("described twice"));
+        let clash = format!("network = oftc\n[network oftc]\nirc-url = x:1\nnick = n\nmap = #a=r:{key}\n");
+        assert!(error(&clash).contains("also the name given by --network"));
+    }
+
+    #[test]
+    fn a_sealed_config_only_opens_with_its_passphrase() {
+        let path = std::env::temp_dir().join(format!("amnezichat-config-{}", rand::random::<u64>()));
+        std::fs::write(&path, seal(CONFIG, "passphrase").unwrap()).unwrap();
+        assert!(!std::fs::read_to_string(&path).unwrap().contains("hunter22"));
+
+        let mut state = AppState::default();
+        let error = load(&mut state, &path, "wrong").unwrap_err();
+        assert!(error.to_string().contains("wrong passphrase"), "{}", error);
+        load(&mut state, &path, "passphrase").unwrap();
+        assert_eq!(state.room_password, "correct horse battery");
+        std::fs::remove_file(path).unwrap();
+    }
+}
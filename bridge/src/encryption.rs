@@ -1,5 +1,5 @@
 use rand::RngCore;
-use sha3::{Sha3_512, Digest};
+use sha3::{Sha3_256, Sha3_512, Digest};
 use zeroize::Zeroize;
 use chacha20poly1305::{
     aead::{Aead, KeyInit, OsRng},
@@ -30,12 +30,27 @@ pub fn derive_key(password: &str, salt: &[u8]) -> [u8; 32] {
     key
 }
 
+/// Size of the room key (`derive_key`'s output, or `--room-key`), which is
+/// also ChaCha20-Poly1305's key size.
+pub const ROOM_KEY_LEN: usize = 32;
+
+/// Checks that a room secret is the hex encoding of a `ROOM_KEY_LEN`-byte
+/// key, so a key derivation change that produced another size fails at
+/// startup rather than as an opaque error on the first message.
+pub fn check_room_secret(secret: &str) -> Result<(), String> {
+    match hex::decode(secret) {
+        Ok(key) if key.len() == ROOM_KEY_LEN => Ok(()),
+        Ok(key) => Err(format!("Room key is {} bytes, but the cipher needs {}", key.len(), ROOM_KEY_LEN)),
+        Err(_) => Err("Room key is not valid hex".to_string()),
+    }
+}
+
 pub fn encrypt_data(plain_text: &str, password: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
     let mut salt = [0u8; 16];
     OsRng.fill_bytes(&mut salt);
 
     let mut key = derive_key(password, &salt);
-    let cipher = ChaCha20Poly1305::new(&Key::from_slice(&key));
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
 
     let mut nonce_bytes = [0u8; 12];
     OsRng.fill_bytes(&mut nonce_bytes);
@@ -64,9 +79,14 @@ pub fn decrypt_data(encrypted_text: &str, password: &str) -> Result<String, Box<
     let salt = hex::decode(parts[0]).map_err(|_| "Decryption error: Invalid salt format")?;
     let nonce_bytes = hex::decode(parts[1]).map_err(|_| "Decryption error: Invalid nonce format")?;
     let encrypted_data = hex::decode(parts[2]).map_err(|_| "Decryption error: Invalid encrypted data format")?;
+    // Anything else would panic in derive_key or Nonce::from_slice; the
+    // payload comes from the server and can't be trusted.
+    if salt.len() != 16 || nonce_bytes.len() != 12 {
+        return Err("Decryption error: Invalid salt or nonce length".into());
+    }
 
     let mut key = derive_key(password, &salt);
-    let cipher = ChaCha20Poly1305::new(&Key::from_slice(&key));
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
 
     let nonce = Nonce::from_slice(&nonce_bytes);
 
@@ -78,3 +98,82 @@ pub fn decrypt_data(encrypted_text: &str, password: &str) -> Result<String, Box<
 
     Ok(String::from_utf8(decrypted_data).map_err(|_| "Decryption error: Invalid UTF-8 data")?)
 }
+
+const SIGNATURE_OPEN: &str = "<sig>";
+const SIGNATURE_CLOSE: &str = "</sig>";
+/// Bytes of the SHA3-256 output kept in the marker.
+const SIGNATURE_LEN: usize = 16;
+
+/// Keyed SHA3-256 over the room id and the text. SHA3 isn't open to length
+/// extension, so prefixing the key is a sound MAC without HMAC's extra pass.
+fn relay_mac(key: &[u8; 32], room_id: &str, text: &str) -> [u8; SIGNATURE_LEN] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(key);
+    hasher.update(room_id.as_bytes());
+    hasher.update([0u8]);
+    hasher.update(text.as_bytes());
+    let mut mac = [0u8; SIGNATURE_LEN];
+    mac.copy_from_slice(&hasher.finalize()[..SIGNATURE_LEN]);
+    mac
+}
+
+/// Appends `<sig>HEX</sig>` to a message the bridge posts, proving it came
+/// from a bridge holding `key` rather than a room member typing
+/// `[IRC]nick: ...` by hand.
+pub fn sign_relay(key: &[u8; 32], room_id: &str, formatted: &str) -> String {
+    format!("{}{}{}{}", formatted, SIGNATURE_OPEN, hex::encode(relay_mac(key, room_id, formatted)), SIGNATURE_CLOSE)
+}
+
+/// Checks the marker added by `sign_relay`, returning the text without it.
+/// `None` when the marker is missing or doesn't match.
+pub fn verify_relay<'a>(key: &[u8; 32], room_id: &str, message: &'a str) -> Option<&'a str> {
+    let (text, marker) = message.strip_suffix(SIGNATURE_CLOSE)?.rsplit_once(SIGNATURE_OPEN)?;
+    let claimed = hex::decode(marker).ok()?;
+    let expected = relay_mac(key, room_id, text);
+    // Compared without an early exit so timing doesn't reveal how much of
+    // a forged marker was right.
+    let diff = claimed.iter().zip(expected.iter()).fold(0u8, |acc, (a, b)| acc | (a ^ b));
+    (claimed.len() == SIGNATURE_LEN && diff == 0).then_some(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    #[test]
+    fn malformed_payloads_are_errors_not_panics() {
+        for payload in ["aa:bbbb:cc", &format!("{}:bbbb:cc", "00".repeat(16)), &format!("00:{}:cc", "00".repeat(12)), "::", "zz:zz:zz"] {
+            assert!(decrypt_data(payload, "00").is_err(), "{}", payload);
+        }
+    }
+
+    #[test]
+    fn room_keys_match_the_cipher_key_size() {
+        assert_eq!(ROOM_KEY_LEN, Key::default().len());
+        let salt = derive_salt_from_password("correct horse");
+        assert_eq!(check_room_secret(&hex::encode(derive_key("correct horse", &salt))), Ok(()));
+        assert!(check_room_secret(&"ab".repeat(16)).unwrap_err().contains("16 bytes"));
+        assert!(check_room_secret("not hex").is_err());
+    }
+
+    #[test]
+    fn signed_relays_verify() {
+        let signed = sign_relay(&KEY, "room1", "[IRC]<strong>alice</strong>: hi");
+        assert!(signed.starts_with("[IRC]<strong>alice</strong>: hi<sig>"));
+        assert_eq!(verify_relay(&KEY, "room1", &signed), Some("[IRC]<strong>alice</strong>: hi"));
+    }
+
+    #[test]
+    fn tampered_or_unsigned_relays_are_rejected() {
+        let shown = sign_relay(&KEY, "room1", "[IRC]alice: hi");
+        assert_eq!(verify_relay(&KEY, "room1", &shown.replace("hi", "ho")), None);
+        assert_eq!(verify_relay(&KEY, "room2", &shown), None);
+        assert_eq!(verify_relay(&[8; 32], "room1", &shown), None);
+        assert_eq!(verify_relay(&KEY, "room1", "[IRC]alice: hi"), None);
+        assert_eq!(verify_relay(&KEY, "room1", "[IRC]alice: hi<sig>00</sig>"), None);
+        let (text, _) = shown.rsplit_once("<sig>").unwrap();
+        assert_eq!(verify_relay(&KEY, "room1", &format!("{}<sig>{}</sig>", text, "0".repeat(32))), None);
+    }
+}
@@ -0,0 +1,125 @@
+//! The markers around every encrypted payload stored on the Amnezichat
+//! server. Sending and receiving both go through here so the two can't
+//! drift apart.
+
+use std::sync::OnceLock;
+
+pub const DEFAULT_BEGIN: &str = "-----BEGIN ENCRYPTED MESSAGE-----";
+pub const DEFAULT_END: &str = "-----END ENCRYPTED MESSAGE-----";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Markers {
+    pub begin: String,
+    pub end: String,
+}
+
+impl Default for Markers {
+    fn default() -> Self {
+        Markers { begin: DEFAULT_BEGIN.to_string(), end: DEFAULT_END.to_string() }
+    }
+}
+
+impl Markers {
+    pub fn wrap(&self, payload: &str) -> String {
+        format!("{}{}{}", self.begin, payload, self.end)
+    }
+
+    /// Every wrapped payload in `body`, in order and trimmed. A begin marker
+    /// without a matching end is ignored, and only the last begin before an
+    /// end counts, so a truncated or junk block can't swallow the valid one
+    /// after it.
+    pub fn extract<'a>(&self, body: &'a str) -> Vec<&'a str> {
+        let mut payloads = Vec::new();
+        let mut rest = body;
+        while let Some(start) = rest.find(&self.begin) {
+            rest = &rest[start + self.begin.len()..];
+            let Some(end) = rest.find(&self.end) else { break };
+            let block = &rest[..end];
+            let payload = match block.rfind(&self.begin) {
+                Some(stray) => &block[stray + self.begin.len()..],
+                None => block,
+            };
+            payloads.push(payload.trim());
+            rest = &rest[end + self.end.len()..];
+        }
+        payloads
+    }
+}
+
+static MARKERS: OnceLock<Markers> = OnceLock::new();
+
+/// Replaces the default markers, for servers of a variant protocol. Must be
+/// called before the first message is sent or read.
+pub fn set_markers(markers: Markers) -> Result<(), &'static str> {
+    if markers.begin.is_empty() || markers.end.is_empty() {
+        return Err("Envelope markers must not be empty");
+    }
+    MARKERS.set(markers).map_err(|_| "Envelope markers already set")
+}
+
+pub fn markers() -> &'static Markers {
+    MARKERS.get_or_init(Markers::default)
+}
+
+pub fn wrap(payload: &str) -> String {
+    markers().wrap(payload)
+}
+
+pub fn extract(body: &str) -> Vec<&str> {
+    markers().extract(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrapped_payloads_are_extracted_again() {
+        let markers = Markers::default();
+        let body = format!("[\"{}\",\"{}\"]", markers.wrap("aa:bb:cc"), markers.wrap(" dd:ee:ff\n"));
+        assert_eq!(markers.extract(&body), vec!["aa:bb:cc", "dd:ee:ff"]);
+        assert_eq!(markers.extract(&format!("{}unterminated", DEFAULT_BEGIN)), Vec::<&str>::new());
+
+        let custom = Markers { begin: "<<".into(), end: ">>".into() };
+        assert_eq!(custom.extract(&custom.wrap("payload")), vec!["payload"]);
+        assert!(markers.extract(&custom.wrap("payload")).is_empty());
+    }
+
+    #[test]
+    fn a_truncated_final_block_is_ignored() {
+        let markers = Markers::default();
+        let body = format!("[\"{}\",\"{}aa:b", markers.wrap("aa:bb:cc"), DEFAULT_BEGIN);
+        assert_eq!(markers.extract(&body), vec!["aa:bb:cc"]);
+    }
+
+    #[test]
+    fn blocks_among_junk_are_extracted_in_order() {
+        let markers = Markers::default();
+        let body = format!(
+            "junk{}\n{} trailing {}{}",
+            markers.wrap("one"),
+            markers.wrap("two"),
+            DEFAULT_END,
+            markers.wrap("three")
+        );
+        assert_eq!(markers.extract(&body), vec!["one", "two", "three"]);
+    }
+
+    #[test]
+    fn a_stray_begin_does_not_swallow_the_next_block() {
+        let markers = Markers::default();
+        let body = format!("{}truncated,{}", DEFAULT_BEGIN, markers.wrap("aa:bb:cc"));
+        assert_eq!(markers.extract(&body), vec!["aa:bb:cc"]);
+    }
+
+    #[test]
+    fn markers_inside_a_message_survive_the_round_trip() {
+        let markers = Markers::default();
+        let text = format!("look: {} not a block {}", DEFAULT_BEGIN, DEFAULT_END);
+        let encrypted = crate::encryption::encrypt_data(&text, "secret").unwrap();
+        let body = format!("[\"{}\"]", markers.wrap(&encrypted));
+        let payloads = markers.extract(&body);
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(crate::encryption::decrypt_data(payloads[0], "secret").unwrap(), text);
+    }
+}
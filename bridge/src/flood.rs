@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// At most `messages` per `window` from one nick are relayed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FloodLimit {
+    pub messages: u32,
+    pub window: Duration,
+}
+
+impl Default for FloodLimit {
+    fn default() -> Self {
+        FloodLimit { messages: 10, window: Duration::from_secs(30) }
+    }
+}
+
+impl FloodLimit {
+    /// Parses `N/SECS`, e.g. `10/30`.
+    pub fn parse(value: &str) -> Option<Self> {
+        let (messages, secs) = value.trim().split_once('/')?;
+        let messages: u32 = messages.trim().parse().ok().filter(|n| *n > 0)?;
+        let secs: u64 = secs.trim().parse().ok().filter(|n| *n > 0)?;
+        Some(FloodLimit { messages, window: Duration::from_secs(secs) })
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum FloodVerdict {
+    Allow,
+    /// Over the limit; the first drop in a window says so, so the nick can
+    /// be told once.
+    Drop { first: bool },
+}
+
+struct Window {
+    start: Instant,
+    count: u32,
+}
+
+/// Fixed-window message counter per IRC nick, for the IRC to Amnezichat
+/// direction.
+pub struct FloodLimiter {
+    limit: FloodLimit,
+    nicks: HashMap<String, Window>,
+}
+
+/// Above this many tracked nicks, expired windows are pruned.
+const PRUNE_THRESHOLD: usize = 256;
+
+impl FloodLimiter {
+    pub fn new(limit: FloodLimit) -> Self {
+        FloodLimiter { limit, nicks: HashMap::new() }
+    }
+
+    pub fn check(&mut self, nick: &str, now: Instant) -> FloodVerdict {
+        let window = self.limit.window;
+        if self.nicks.len() > PRUNE_THRESHOLD {
+            self.nicks.retain(|_, w| now.duration_since(w.start) < window);
+        }
+
+        let w = self.nicks.entry(nick.to_lowercase()).or_insert(Window { start: now, count: 0 });
+        if now.duration_since(w.start) >= window {
+            w.start = now;
+            w.count = 0;
+        }
+        w.count += 1;
+        if w.count <= self.limit.messages {
+            FloodVerdict::Allow
+        } else {
+            FloodVerdict::Drop { first: w.count == self.limit.messages + 1 }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_messages_over_the_limit_until_the_window_passes() {
+        let mut limiter = FloodLimiter::new(FloodLimit { messages: 2, window: Duration::from_secs(10) });
+        let start = Instant::now();
+        assert_eq!(limiter.check("alice", start), FloodVerdict::Allow);
+        assert_eq!(limiter.check("Alice", start), FloodVerdict::Allow);
+        assert_eq!(limiter.check("alice", start), FloodVerdict::Drop { first: true });
+        assert_eq!(limiter.check("alice", start), FloodVerdict::Drop { first: false });
+        assert_eq!(limiter.check("bob", start), FloodVerdict::Allow);
+        assert_eq!(limiter.check("alice", start + Duration::from_secs(10)), FloodVerdict::Allow);
+    }
+
+    #[test]
+    fn parses_limits() {
+        assert_eq!(FloodLimit::parse("5/20"), Some(FloodLimit { messages: 5, window: Duration::from_secs(20) }));
+        assert_eq!(FloodLimit::parse("0/20"), None);
+        assert_eq!(FloodLimit::parse("5"), None);
+    }
+}
@@ -0,0 +1,69 @@
+//! Cutting text without splitting what renders as one character:
+//! combining marks, skin tone modifiers, ZWJ emoji sequences, flags and the
+//! rest of what Unicode counts as one extended grapheme cluster.
+
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Byte offsets where a new grapheme cluster starts, `text.len()` included.
+fn boundaries(text: &str) -> impl Iterator<Item = usize> + '_ {
+    text.grapheme_indices(true).map(|(i, _)| i).skip(1).chain(std::iter::once(text.len()))
+}
+
+/// The longest prefix of `text` of at most `max_chars` characters that ends
+/// on a grapheme cluster boundary. A single cluster longer than the limit
+/// is dropped entirely rather than cut.
+pub fn truncate(text: &str, max_chars: usize) -> &str {
+    let mut end = 0;
+    let mut chars = 0;
+    for boundary in boundaries(text) {
+        chars += text[end..boundary].chars().count();
+        if chars > max_chars {
+            break;
+        }
+        end = boundary;
+    }
+    &text[..end]
+}
+
+/// Like `truncate`, but counting UTF-8 bytes instead of characters.
+pub fn truncate_bytes(text: &str, max_bytes: usize) -> &str {
+    let end = boundaries(text).take_while(|&b| b <= max_bytes).last().unwrap_or(0);
+    &text[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FLAG: &str = "\u{1f1fa}\u{1f1e6}";
+    const FAMILY: &str = "\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}\u{200d}\u{1f466}";
+
+    #[test]
+    fn never_splits_a_flag_or_a_zwj_family() {
+        let text = format!("ab{}{}", FLAG, FAMILY);
+        assert_eq!(truncate(&text, 3), "ab");
+        assert_eq!(truncate(&text, 4), format!("ab{}", FLAG));
+        for limit in 4..10 {
+            assert_eq!(truncate(&text, limit), format!("ab{}", FLAG), "limit {}", limit);
+        }
+        assert_eq!(truncate(&text, 11), text);
+
+        let flags = format!("{}{}{}", FLAG, FLAG, FLAG);
+        assert_eq!(truncate(&flags, 5), format!("{}{}", FLAG, FLAG));
+    }
+
+    #[test]
+    fn keeps_combining_marks_and_modifiers_with_their_base() {
+        assert_eq!(truncate("cafe\u{301}!", 4), "caf");
+        assert_eq!(truncate("cafe\u{301}!", 5), "cafe\u{301}");
+        assert_eq!(truncate("hi \u{1f44b}\u{1f3fd}", 4), "hi ");
+        assert_eq!(truncate("plain ascii", 5), "plain");
+        assert_eq!(truncate("", 5), "");
+        assert_eq!(truncate_bytes("cafe\u{301}!", 5), "caf");
+        assert_eq!(truncate_bytes("cafe\u{301}!", 6), "cafe\u{301}");
+        // A Hangul syllable spelled out in jamo, and a Tamil letter with its
+        // spacing vowel sign.
+        assert_eq!(truncate("\u{1100}\u{1161}\u{11a8}x", 2), "");
+        assert_eq!(truncate("\u{0b95}\u{0bbe}x", 2), "\u{0b95}\u{0bbe}");
+    }
+}
@@ -0,0 +1,262 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::bridge::Bridge;
+use crate::logging;
+
+/// The liveness file is rewritten at most this often, however busy the
+/// bridge is.
+const LIVENESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Connection state the bridge tasks report as they go, for `/status`.
+pub struct Health {
+    pub irc_connected: AtomicBool,
+    pub irc_reconnects: AtomicU64,
+    last_amnezichat_poll: Mutex<Option<Instant>>,
+    /// Rewritten with the time as the bridge makes progress
+    /// (`--liveness-file`), for an external supervisor to watch.
+    liveness_file: Option<PathBuf>,
+    last_alive: Mutex<Option<Instant>>,
+    /// With `--idle-disconnect`, how long nothing may be bridged before the
+    /// bridge leaves IRC; it is dormant until a room message wakes it.
+    idle_disconnect: Option<Duration>,
+    last_bridged: Mutex<Instant>,
+    dormant: AtomicBool,
+    wake: Notify,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Health::new(None, None)
+    }
+}
+
+impl Health {
+    pub fn new(liveness_file: Option<PathBuf>, idle_disconnect: Option<Duration>) -> Self {
+        Health {
+            irc_connected: AtomicBool::new(true),
+            irc_reconnects: AtomicU64::new(0),
+            last_amnezichat_poll: Mutex::new(None),
+            liveness_file,
+            last_alive: Mutex::new(None),
+            idle_disconnect,
+            last_bridged: Mutex::new(Instant::now()),
+            dormant: AtomicBool::new(false),
+            wake: Notify::new(),
+        }
+    }
+
+    /// Called when a poll succeeds or a line arrives from IRC. Refreshes
+    /// the liveness file, but not while IRC is down, so that a bridge
+    /// stuck on either side lets it go stale. Being dormant is not down.
+    pub fn alive(&self) {
+        let Some(path) = &self.liveness_file else { return };
+        if !self.irc_connected.load(Ordering::SeqCst) && !self.is_dormant() {
+            return;
+        }
+        {
+            let mut last = self.last_alive.lock().unwrap_or_else(|e| e.into_inner());
+            if last.is_some_and(|t| t.elapsed() < LIVENESS_INTERVAL) {
+                return;
+            }
+            *last = Some(Instant::now());
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        if let Err(e) = std::fs::write(path, format!("{}\n", now)) {
+            logging::warn("liveness", format!("Cannot update {}: {}", path.display(), e));
+        }
+    }
+
+    pub fn polled_amnezichat(&self) {
+        *self.last_amnezichat_poll.lock().unwrap_or_else(|e| e.into_inner()) = Some(Instant::now());
+    }
+
+    pub fn last_amnezichat_poll(&self) -> Option<Instant> {
+        *self.last_amnezichat_poll.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn reconnecting(&self) {
+        self.irc_connected.store(false, Ordering::SeqCst);
+    }
+
+    pub fn reconnected(&self) {
+        self.irc_connected.store(true, Ordering::SeqCst);
+        self.irc_reconnects.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Called for every message bridged from IRC to the room.
+    pub fn bridged(&self) {
+        *self.last_bridged.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+    }
+
+    /// Whether it is time to leave IRC; the first caller to get `true`
+    /// makes the bridge dormant.
+    pub fn doze(&self) -> bool {
+        let Some(after) = self.idle_disconnect else { return false };
+        if self.last_bridged.lock().unwrap_or_else(|e| e.into_inner()).elapsed() < after {
+            return false;
+        }
+        !self.dormant.swap(true, Ordering::SeqCst)
+    }
+
+    pub fn is_dormant(&self) -> bool {
+        self.dormant.load(Ordering::SeqCst)
+    }
+
+    /// Called for every room message on its way to IRC; ends dormancy.
+    pub fn wake(&self) {
+        self.bridged();
+        if self.dormant.swap(false, Ordering::SeqCst) {
+            self.wake.notify_one();
+        }
+    }
+
+    /// Returns once the bridge is not dormant.
+    pub async fn woken(&self) {
+        while self.is_dormant() {
+            self.wake.notified().await;
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct StatusSnapshot {
+    pub irc: IrcStatus,
+    pub amnezichat: AmnezichatStatus,
+    pub dedup: DedupStatus,
+    pub queued_to_irc: usize,
+    /// Room messages dropped because the IRC queue was full.
+    pub dropped_to_irc: u64,
+}
+
+#[derive(Serialize)]
+pub struct IrcStatus {
+    pub connected: bool,
+    pub last_received_secs_ago: u64,
+    pub reconnects: u64,
+    pub latency_ms: Option<u128>,
+    /// Disconnected on purpose by `--idle-disconnect`.
+    pub dormant: bool,
+}
+
+#[derive(Serialize)]
+pub struct AmnezichatStatus {
+    /// `None` until the first successful poll.
+    pub last_poll_secs_ago: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct DedupStatus {
+    pub amnezichat: usize,
+    pub irc: usize,
+}
+
+/// Answers `GET /status` with a JSON snapshot of the bridge, or with one
+/// per network keyed by its name when there are several. Meant for a
+/// local dashboard or `curl`; bind it to localhost. Aborting the returned
+/// task closes the listener.
+pub async fn serve_status(addr: &str, bridges: Vec<(String, Arc<Bridge>)>) -> std::io::Result<JoinHandle<()>> {
+    let bridges = Arc::new(bridges);
+    let listener = TcpListener::bind(addr).await?;
+    logging::info("startup", format!("[bridge] status endpoint on http://{}/status", listener.local_addr()?));
+    Ok(tokio::spawn(async move {
+        loop {
+            let Ok((mut socket, _)) = listener.accept().await else { continue };
+            let bridges = Arc::clone(&bridges);
+            tokio::spawn(async move {
+                let mut buf = [0u8; 2048];
+                let Ok(n) = socket.read(&mut buf).await else { return };
+                let request = String::from_utf8_lossy(&buf[..n]);
+                let request_line = request.lines().next().unwrap_or("");
+                let response = if is_status_request(request_line) {
+                    let body = status_body(&bridges).await;
+                    http_response("200 OK", "application/json", &body)
+                } else {
+                    http_response("404 Not Found", "text/plain", "not found\n")
+                };
+                let _ = socket.write_all(response.as_bytes()).await;
+                let _ = socket.shutdown().await;
+            });
+        }
+    }))
+}
+
+async fn status_body(bridges: &[(String, Arc<Bridge>)]) -> String {
+    if let [(_, bridge)] = bridges {
+        return serde_json::to_string_pretty(&bridge.status_snapshot().await).unwrap_or_default();
+    }
+    let mut networks = serde_json::Map::new();
+    for (name, bridge) in bridges {
+        networks.insert(name.clone(), serde_json::to_value(bridge.status_snapshot().await).unwrap_or_default());
+    }
+    serde_json::to_string_pretty(&networks).unwrap_or_default()
+}
+
+fn is_status_request(request_line: &str) -> bool {
+    let mut parts = request_line.split_whitespace();
+    parts.next() == Some("GET") && parts.next().is_some_and(|path| path == "/status" || path.starts_with("/status?"))
+}
+
+fn http_response(status: &str, content_type: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_only_get_status() {
+        assert!(is_status_request("GET /status HTTP/1.1"));
+        assert!(is_status_request("GET /status?pretty HTTP/1.1"));
+        assert!(!is_status_request("POST /status HTTP/1.1"));
+        assert!(!is_status_request("GET /statusx HTTP/1.1"));
+        assert!(!is_status_request(""));
+    }
+
+    #[test]
+    fn the_liveness_file_is_only_refreshed_while_irc_is_up() {
+        let path = std::env::temp_dir().join(format!("amnezichat-alive-{}", rand::random::<u64>()));
+        let health = Health::new(Some(path.clone()), None);
+        health.reconnecting();
+        health.alive();
+        assert!(!path.exists());
+
+        health.reconnected();
+        health.alive();
+        assert!(std::fs::read_to_string(&path).unwrap().trim().parse::<u64>().is_ok());
+        std::fs::remove_file(&path).unwrap();
+        // Not again within LIVENESS_INTERVAL.
+        health.alive();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn a_quiet_bridge_dozes_until_woken() {
+        let health = Health::new(None, Some(Duration::from_millis(50)));
+        assert!(!health.doze());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(health.doze());
+        assert!(!health.doze(), "only once");
+        assert!(health.is_dormant());
+
+        health.wake();
+        tokio::time::timeout(Duration::from_secs(1), health.woken()).await.unwrap();
+        assert!(!health.doze(), "waking counts as activity");
+        assert!(!Health::default().doze());
+    }
+}
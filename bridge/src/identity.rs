@@ -0,0 +1,191 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Most messages held for one nick; older ones are dropped first.
+const MAX_HELD: usize = 20;
+/// A WHOIS unanswered for this long is given up on, with what it held;
+/// the nick's next message asks again.
+pub const WHOIS_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IdentifyPolicy {
+    #[default]
+    Off,
+    Drop,
+    Tag,
+}
+
+impl IdentifyPolicy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "off" => Some(IdentifyPolicy::Off),
+            "drop" => Some(IdentifyPolicy::Drop),
+            "tag" => Some(IdentifyPolicy::Tag),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AuthStatus {
+    Identified(String),
+    Unidentified,
+}
+
+/// Caches the services account of IRC nicks as reported by WHOIS (330).
+/// Messages from nicks without a known status are held until the WHOIS
+/// completes (318).
+#[derive(Default)]
+pub struct IdentityCache {
+    known: HashMap<String, AuthStatus>,
+    pending: HashMap<String, PendingWhois>,
+}
+
+struct PendingWhois {
+    asked: Instant,
+    account: Option<String>,
+    messages: VecDeque<Held>,
+}
+
+/// A message waiting on its sender's WHOIS.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Held {
+    pub target: String,
+    pub text: String,
+    pub notice: bool,
+}
+
+impl IdentityCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn status(&self, nick: &str) -> Option<&AuthStatus> {
+        self.known.get(&nick.to_lowercase())
+    }
+
+    /// Queues a message until the nick's WHOIS finishes. Returns true if a
+    /// WHOIS has to be sent, false if one is already in flight. WHOIS
+    /// requests that went unanswered for `WHOIS_TIMEOUT` are forgotten
+    /// first, so a server that never replies can't make messages pile up.
+    pub fn hold(&mut self, nick: &str, target: &str, msg: &str, notice: bool, now: Instant) -> bool {
+        self.pending.retain(|_, p| now.duration_since(p.asked) < WHOIS_TIMEOUT);
+        let key = nick.to_lowercase();
+        let is_new = !self.pending.contains_key(&key);
+        let pending = self.pending.entry(key).or_insert_with(|| PendingWhois { asked: now, account: None, messages: VecDeque::new() });
+        if pending.messages.len() == MAX_HELD {
+            pending.messages.pop_front();
+        }
+        pending.messages.push_back(Held { target: target.to_string(), text: msg.to_string(), notice });
+        is_new
+    }
+
+    pub fn record_account(&mut self, nick: &str, account: &str) {
+        if let Some(p) = self.pending.get_mut(&nick.to_lowercase()) {
+            p.account = Some(account.to_string());
+        }
+    }
+
+    /// Finishes a WHOIS and hands back the messages that were waiting on it.
+    pub fn complete(&mut self, nick: &str) -> (AuthStatus, Vec<Held>) {
+        let key = nick.to_lowercase();
+        let (account, messages) = match self.pending.remove(&key) {
+            Some(pending) => (pending.account, pending.messages.into()),
+            None => (None, Vec::new()),
+        };
+        let status = match account {
+            Some(account) => AuthStatus::Identified(account),
+            None => AuthStatus::Unidentified,
+        };
+        self.known.insert(key, status.clone());
+        (status, messages)
+    }
+
+    /// Applies an `ACCOUNT` notification: `None` means the nick logged out.
+    pub fn set_account(&mut self, nick: &str, account: Option<&str>) {
+        let status = match account {
+            Some(account) => AuthStatus::Identified(account.to_string()),
+            None => AuthStatus::Unidentified,
+        };
+        self.known.insert(nick.to_lowercase(), status);
+    }
+
+    pub fn invalidate(&mut self, nick: &str) {
+        self.known.remove(&nick.to_lowercase());
+    }
+
+    pub fn clear(&mut self) {
+        self.known.clear();
+        self.pending.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(held: &[Held]) -> Vec<&str> {
+        held.iter().map(|h| h.text.as_str()).collect()
+    }
+
+    #[test]
+    fn held_messages_are_released_with_the_whois_result() {
+        let mut cache = IdentityCache::new();
+        let now = Instant::now();
+        assert_eq!(cache.status("alice"), None);
+        assert!(cache.hold("alice", "#test", "one", false, now));
+        assert!(!cache.hold("Alice", "#test", "two", true, now));
+        cache.record_account("ALICE", "alice_acct");
+        let (status, held) = cache.complete("alice");
+        assert_eq!(status, AuthStatus::Identified("alice_acct".into()));
+        assert_eq!(held, [Held { target: "#test".into(), text: "one".into(), notice: false }, Held { target: "#test".into(), text: "two".into(), notice: true }]);
+        assert_eq!(cache.status("alice"), Some(&AuthStatus::Identified("alice_acct".into())));
+
+        // No 330 before the 318: not identified.
+        assert!(cache.hold("carol", "#test", "hi", false, now));
+        assert_eq!(cache.complete("carol"), (AuthStatus::Unidentified, vec![Held { target: "#test".into(), text: "hi".into(), notice: false }]));
+        // An account line for a nick nobody asked about changes nothing.
+        cache.record_account("dave", "dave_acct");
+        assert_eq!(cache.complete("dave"), (AuthStatus::Unidentified, Vec::new()));
+    }
+
+    #[test]
+    fn statuses_follow_account_changes_and_invalidation() {
+        let mut cache = IdentityCache::new();
+        cache.set_account("bob", Some("bob_acct"));
+        assert_eq!(cache.status("BOB"), Some(&AuthStatus::Identified("bob_acct".into())));
+        cache.set_account("bob", None);
+        assert_eq!(cache.status("bob"), Some(&AuthStatus::Unidentified));
+        cache.invalidate("bob");
+        assert_eq!(cache.status("bob"), None);
+
+        cache.set_account("bob", Some("bob_acct"));
+        cache.hold("carol", "#test", "hi", false, Instant::now());
+        cache.clear();
+        assert_eq!(cache.status("bob"), None);
+        assert_eq!(cache.complete("carol").1, Vec::new());
+    }
+
+    #[test]
+    fn held_messages_are_capped_and_expire() {
+        let mut cache = IdentityCache::new();
+        let now = Instant::now();
+        for n in 0..MAX_HELD + 5 {
+            cache.hold("alice", "#test", &n.to_string(), false, now);
+        }
+        let (_, held) = cache.complete("alice");
+        assert_eq!(held.len(), MAX_HELD);
+        assert_eq!(texts(&held)[0], "5", "the oldest are dropped");
+
+        assert!(cache.hold("carol", "#test", "lost", false, now));
+        assert!(!cache.hold("carol", "#test", "still waiting", false, now + WHOIS_TIMEOUT / 2));
+        // The WHOIS was never answered: ask again, without the old messages.
+        assert!(cache.hold("carol", "#test", "fresh", false, now + WHOIS_TIMEOUT));
+        assert_eq!(texts(&cache.complete("carol").1), ["fresh"]);
+
+        // Someone else's message sweeps out a stale entry too.
+        cache.hold("dave", "#test", "gone", false, now);
+        cache.hold("erin", "#test", "hi", false, now + WHOIS_TIMEOUT);
+        assert_eq!(cache.pending.len(), 1);
+    }
+}
@@ -0,0 +1,732 @@
+//! A connection to the IRC server: registration, including capability
+//! negotiation and SASL, and once registered the reader and writer threads
+//! the bridge's tasks share it through.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use base64::engine::general_purpose;
+use base64::Engine;
+use tokio::sync::mpsc::error::TrySendError;
+
+use crate::bridge::{is_channel, Casemapping, IrcSettings, DEFAULT_CONNECT_TIMEOUT, DEFAULT_REALNAME, DEFAULT_REGISTRATION_TIMEOUT, MAX_MESSAGE_CHARS};
+use crate::graphemes;
+use crate::irc::proto::{Command, Message};
+use crate::irc::stream::IrcStream;
+use crate::logging::{self, Context};
+
+/// Underscores tried after a taken nick before registration gives up.
+const MAX_NICK_FALLBACKS: u32 = 3;
+/// How long a PING sent to a silent connection has to be answered.
+const PONG_WAIT: Duration = Duration::from_secs(15);
+
+/// Capabilities requested whenever the server offers them. `server-time` and
+/// `batch` let us recognize bouncer playback; `znc.in/playback` stops ZNC
+/// from replaying its buffer on its own; `account-notify` and
+/// `extended-join` keep the identity cache current without repeated WHOIS;
+/// `cap-notify` has the server announce capabilities it adds or withdraws
+/// later, and `message-tags` carries the tags RELAYMSG echoes are marked by.
+const OPTIONAL_CAPS: &[&str] = &["server-time", "batch", "znc.in/playback", "account-notify", "extended-join", "cap-notify", "message-tags"];
+
+/// Only requested with `--presence`, since it adds an AWAY line for every
+/// status change in shared channels.
+const PRESENCE_CAPS: &[&str] = &["away-notify"];
+
+/// Only requested with `--relaymsg`. Its value is the character a relayed
+/// nick must contain, `/` if the server doesn't say.
+const RELAYMSG_CAP: &str = "draft/relaymsg";
+
+/// An `ERROR` line received while registering.
+#[derive(Debug)]
+pub struct ServerError(pub String);
+
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "IRC server closed the link: {}", self.0)
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+/// The capabilities besides `sasl` the bridge asks for when offered, at
+/// registration or later with `CAP NEW`.
+pub fn wanted_caps(settings: &IrcSettings) -> Vec<&'static str> {
+    let presence_caps = if settings.presence { PRESENCE_CAPS } else { &[] };
+    let relaymsg_caps: &[&str] = if settings.relaymsg { &[RELAYMSG_CAP] } else { &[] };
+    OPTIONAL_CAPS.iter().chain(presence_caps).chain(relaymsg_caps).copied().collect()
+}
+
+/// Keeps `state` current with a `CAP NEW`, `ACK` or `DEL` from a registered
+/// connection, and returns the newly offered capabilities in `wanted` to
+/// request.
+pub fn cap_change(line: &Message, wanted: &[&str], state: &mut ConnectionState) -> Option<String> {
+    let caps = line.params.last()?.split_whitespace();
+    match line.params.get(1)?.as_str() {
+        "NEW" => {
+            let mut request = Vec::new();
+            for cap in caps {
+                let (name, value) = cap.split_once('=').unwrap_or((cap, ""));
+                state.offered.insert(name.to_string(), value.to_string());
+                if wanted.contains(&name) && !state.caps.contains(name) {
+                    request.push(name);
+                }
+            }
+            (!request.is_empty()).then(|| request.join(" "))
+        }
+        "ACK" => {
+            for cap in caps {
+                match cap.strip_prefix('-') {
+                    Some(removed) => state.caps.remove(removed),
+                    None => state.caps.insert(cap.to_string()),
+                };
+            }
+            None
+        }
+        "DEL" => {
+            let withdrawn: Vec<&str> = caps.collect();
+            logging::info("irc-cap", format!("Server withdrew the capabilities {}", withdrawn.join(" ")));
+            for cap in withdrawn {
+                state.caps.remove(cap);
+                state.offered.remove(cap);
+            }
+            None
+        }
+        "NAK" => {
+            logging::warn("irc-cap", format!("Server refused the capabilities {}", caps.collect::<Vec<_>>().join(" ")));
+            None
+        }
+        _ => None,
+    }
+}
+
+/// A connection during registration, which is a strict exchange of lines.
+/// Once registered, `start` hands the socket to a reader and a writer
+/// thread.
+pub struct CustomIrcClient {
+    stream: IrcStream,
+    reader: BufReader<IrcStream>,
+    pending: Vec<u8>,
+    pub caps: HashSet<String>,
+    /// What `CAP LS` offered, with each capability's value.
+    pub offered: HashMap<String, String>,
+    /// The server announced WHOX (extended WHO) in ISUPPORT.
+    pub whox: bool,
+    /// And MONITOR, which tells us when a nick signs off.
+    pub monitor: bool,
+    pub casemapping: Casemapping,
+    /// The nick registered with; the configured one with `_` appended when
+    /// that was taken.
+    pub nick: String,
+    nick_fallbacks: u32,
+    pub connected_at: SystemTime,
+    /// Log every raw line sent and received (`--trace-irc`).
+    pub trace: bool,
+    /// Spacing of messages once registered (`IrcSettings::send_delay`).
+    send_delay: Option<Duration>,
+}
+
+/// Lines read from a registered connection, in order. The last item is the
+/// error that ended it; after that the channel is closed.
+pub type Incoming = tokio::sync::mpsc::UnboundedReceiver<io::Result<String>>;
+
+/// What the writer thread of a connection is asked to do.
+enum Outgoing {
+    Line(String),
+    /// Answered once every line queued before it has been written.
+    Flush(tokio::sync::oneshot::Sender<()>),
+}
+
+/// Lines waiting for the writer thread. Past this a send fails rather than
+/// piling up on a connection that has stopped taking writes.
+const WRITE_QUEUE_LINES: usize = 512;
+
+/// A handle to a registered connection; clones share it. Sends only queue
+/// the line for the connection's writer thread, so a slow write never holds
+/// anyone up, and reading goes on on its own thread meanwhile.
+#[derive(Clone)]
+pub struct IrcConnection {
+    outgoing: tokio::sync::mpsc::Sender<Outgoing>,
+    pub socket: Arc<IrcStream>,
+    state: Arc<std::sync::Mutex<ConnectionState>>,
+    pub connected_at: SystemTime,
+    pub whox: bool,
+    pub monitor: bool,
+    /// How channel names compare on this server.
+    pub casemapping: Casemapping,
+}
+
+/// What the tasks sharing a connection know about it.
+pub struct ConnectionState {
+    /// Our nick on this connection, kept up to date as it changes.
+    pub nick: String,
+    /// Capabilities granted, and those offered with their values; both
+    /// follow `CAP NEW`/`DEL` (cap-notify).
+    pub caps: HashSet<String>,
+    pub offered: HashMap<String, String>,
+    pub last_received: Instant,
+    pub keepalive: Keepalive,
+    /// Reason from the server's `ERROR` line, once it has announced that it
+    /// is closing the link.
+    pub closed: Option<String>,
+    /// Set by a reconnect until its rejoin delay is over; queued messages
+    /// wait meanwhile.
+    pub sends_held: bool,
+}
+
+/// Tracks the token of the keep-alive PING in flight and the round trip of
+/// the last one answered.
+#[derive(Default)]
+pub struct Keepalive {
+    pub outstanding: Option<(String, Instant)>,
+    pub missed: u32,
+    pub last_rtt: Option<Duration>,
+}
+
+/// What the watchdog does about a connection on one of its ticks.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Watchdog {
+    Wait,
+    Ping,
+    /// Presumed dead, for this reason; it is closed and replaced.
+    Dead(String),
+}
+
+impl Keepalive {
+    /// Decides on a tick, given how long nothing has arrived and whether a
+    /// regular PING is due. A connection that has been silent for
+    /// `idle_timeout` is only given up once a PING has gone unanswered for
+    /// `PONG_WAIT`; until then it is just quiet.
+    pub fn check(&mut self, now: Instant, silent_for: Duration, idle_timeout: Duration, ping_due: bool, max_missed_pongs: u32) -> Watchdog {
+        let waited = self.outstanding.as_ref().map(|(_, sent)| now.saturating_duration_since(*sent));
+        if silent_for >= idle_timeout {
+            return match waited {
+                Some(waited) if waited >= PONG_WAIT => Watchdog::Dead(format!(
+                    "No data from IRC for {}s, and no answer to a PING in {}s; connection presumed dead",
+                    silent_for.as_secs(),
+                    waited.as_secs()
+                )),
+                Some(_) => Watchdog::Wait,
+                None => Watchdog::Ping,
+            };
+        }
+        if !ping_due {
+            return Watchdog::Wait;
+        }
+        if waited.is_some() {
+            self.missed += 1;
+            if self.missed >= max_missed_pongs {
+                return Watchdog::Dead(format!("{} keep-alive PINGs went unanswered", self.missed));
+            }
+        }
+        Watchdog::Ping
+    }
+
+    pub fn acknowledge(&mut self, token: &str) {
+        if let Some((expected, sent)) = &self.outstanding {
+            if expected == token {
+                self.last_rtt = Some(sent.elapsed());
+                self.outstanding = None;
+                self.missed = 0;
+            }
+        }
+    }
+}
+
+impl IrcConnection {
+    pub fn send(&self, command: Command) -> io::Result<()> {
+        self.outgoing.try_send(Outgoing::Line(command.encode())).map_err(|e| match e {
+            TrySendError::Full(_) => {
+                logging::warn("irc-send", "IRC write queue full; the connection isn't taking writes");
+                io::Error::new(io::ErrorKind::WouldBlock, "IRC write queue is full")
+            }
+            TrySendError::Closed(_) => io::Error::new(io::ErrorKind::NotConnected, "IRC connection is closed"),
+        })
+    }
+
+    pub fn send_message(&self, tgt: &str, m: &str) -> io::Result<()> {
+        self.send(Command::Privmsg { target: tgt, text: graphemes::truncate(m, MAX_MESSAGE_CHARS) })
+    }
+
+    /// Waits until everything sent so far has been written, or the
+    /// connection is gone; returns false in the latter case, when some of it
+    /// may not have been.
+    pub async fn flush(&self) -> bool {
+        let (done, written) = tokio::sync::oneshot::channel();
+        self.outgoing.send(Outgoing::Flush(done)).await.is_ok() && written.await.is_ok()
+    }
+
+    /// Closes the connection. The reader thread then reports it lost, and
+    /// the receive task reconnects unless the bridge is stopping.
+    pub fn close(&self) {
+        let _ = self.socket.shutdown();
+    }
+
+    pub fn state(&self) -> std::sync::MutexGuard<'_, ConnectionState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn nick(&self) -> String {
+        self.state().nick.clone()
+    }
+
+    /// Separator for RELAYMSG nicks, while `draft/relaymsg` is granted.
+    pub fn relaymsg(&self) -> Option<char> {
+        let state = self.state();
+        state.caps.contains(RELAYMSG_CAP).then(|| state.offered.get(RELAYMSG_CAP).and_then(|v| v.chars().next()).unwrap_or('/'))
+    }
+}
+
+impl CustomIrcClient {
+    pub fn new(server_url: &str, connect_timeout: Duration) -> io::Result<Self> {
+        let stream = IrcStream::connect(server_url, connect_timeout)?;
+        stream.set_read_timeout(Some(Duration::from_secs(60)))?;
+        let reader = BufReader::new(stream.try_clone()?);
+        Ok(Self {
+            stream,
+            reader,
+            pending: Vec::new(),
+            caps: HashSet::new(),
+            offered: HashMap::new(),
+            whox: false,
+            monitor: false,
+            casemapping: Casemapping::default(),
+            nick: String::new(),
+            nick_fallbacks: 0,
+            connected_at: SystemTime::now(),
+            trace: false,
+            send_delay: None,
+        })
+    }
+
+    /// Starts the reader and writer threads of a registered connection.
+    /// Each owns its half of the socket; they end when it is closed.
+    pub fn start(mut self) -> io::Result<(IrcConnection, Incoming)> {
+        self.stream.set_read_timeout(None)?;
+        let socket = Arc::new(self.stream.try_clone()?);
+        let state = Arc::new(std::sync::Mutex::new(ConnectionState {
+            nick: self.nick.clone(),
+            caps: std::mem::take(&mut self.caps),
+            offered: std::mem::take(&mut self.offered),
+            last_received: Instant::now(),
+            keepalive: Keepalive::default(),
+            closed: None,
+            sends_held: false,
+        }));
+        let (outgoing, mut queued) = tokio::sync::mpsc::channel(WRITE_QUEUE_LINES);
+        let (received, incoming) = tokio::sync::mpsc::unbounded_channel();
+
+        let mut writer = self.stream.try_clone()?;
+        let (trace, send_delay, writer_socket) = (self.trace, self.send_delay, Arc::clone(&socket));
+        std::thread::spawn(move || {
+            let mut last_message: Option<Instant> = None;
+            while let Some(item) = queued.blocking_recv() {
+                match item {
+                    Outgoing::Line(line) => {
+                        // Every message waits its turn, whoever sent it; PING,
+                        // PONG and the like never do.
+                        if let Some(delay) = send_delay.filter(|_| is_message_line(&line)) {
+                            if let Some(last) = last_message {
+                                std::thread::sleep(delay.saturating_sub(last.elapsed()));
+                            }
+                            last_message = Some(Instant::now());
+                        }
+                        if let Err(e) = write_line(&mut writer, &line, trace) {
+                            logging::warn("irc-send", format!("Error sending to IRC: {}", e));
+                            // The reader then notices too, and the
+                            // connection is replaced.
+                            let _ = writer_socket.shutdown();
+                            return;
+                        }
+                    }
+                    Outgoing::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+
+        let (connected_at, whox, monitor, casemapping) = (self.connected_at, self.whox, self.monitor, self.casemapping);
+        let reader_state = Arc::clone(&state);
+        std::thread::spawn(move || loop {
+            let line = self.receive_message();
+            if line.is_ok() {
+                reader_state.lock().unwrap_or_else(|e| e.into_inner()).last_received = Instant::now();
+            }
+            let failed = line.is_err();
+            if received.send(line).is_err() || failed {
+                return;
+            }
+        });
+
+        Ok((IrcConnection { outgoing, socket, state, connected_at, whox, monitor, casemapping }, incoming))
+    }
+
+    pub fn connect_and_auth(settings: &IrcSettings) -> io::Result<Self> {
+        let file_password = settings.sasl_password_file.as_deref().map(read_password_file).transpose()?;
+        let mut c = Self::new(&settings.server, settings.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT))?;
+        c.trace = settings.trace;
+        c.send_delay = settings.send_delay;
+        let deadline = Instant::now() + settings.registration_timeout.unwrap_or(DEFAULT_REGISTRATION_TIMEOUT);
+
+        if let Some(password) = settings.server_password.as_deref().filter(|p| !p.is_empty()) {
+            c.send(Command::Pass(password))?;
+        }
+
+        c.send(Command::CapLs)?;
+        c.nick = settings.nick.clone();
+        c.send(Command::Nick(&settings.nick))?;
+        let ident = settings.ident.as_deref().unwrap_or(&settings.nick);
+        let realname = settings.realname.as_deref().unwrap_or(DEFAULT_REALNAME);
+        c.send(Command::User { user: ident, mode: "0", realname })?;
+
+        let sasl = match (settings.sasl_username.as_deref(), file_password.as_deref().or(settings.sasl_password.as_deref())) {
+            (Some(user), Some(pass)) => Some((user, pass)),
+            _ => None,
+        };
+
+        let offered = c.read_cap_ls(deadline)?;
+        if let Some(offered) = offered {
+            let mut wanted: Vec<&str> = wanted_caps(settings).into_iter().filter(|cap| offered.contains_key(*cap)).collect();
+            if sasl.is_some() {
+                let Some(mechanisms) = offered.get("sasl") else {
+                    return Err(io::Error::new(io::ErrorKind::Unsupported, "Server does not offer SASL"));
+                };
+                // Checked at every connection: a server upgrade may have
+                // changed what it accepts.
+                if !offers_plain(mechanisms) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        format!("Server no longer offers SASL PLAIN, only {}", mechanisms),
+                    ));
+                }
+                wanted.insert(0, "sasl");
+            }
+
+            if !wanted.is_empty() && !c.request_caps(&wanted, deadline)? {
+                // A NAK refuses the whole request, so one optional cap the
+                // server won't grant after all would cost us SASL too.
+                let refused = if sasl.is_some() && wanted.len() > 1 {
+                    logging::warn("irc-cap", format!("Server refused the capabilities {}; asking for sasl alone", wanted.join(" ")));
+                    !c.request_caps(&["sasl"], deadline)?
+                } else {
+                    true
+                };
+                if refused && sasl.is_some() {
+                    return Err(io::Error::new(io::ErrorKind::Unsupported, "Server refused the SASL capability (CAP NAK)"));
+                }
+                if refused {
+                    logging::warn("irc-cap", format!("Server refused the capabilities {}; continuing without them", wanted.join(" ")));
+                }
+            }
+            c.offered = offered;
+
+            if let Some((user, pass)) = sasl {
+                c.send(Command::Authenticate("PLAIN"))?;
+                loop {
+                    let line = c.handshake_line(deadline, "AUTHENTICATE +")?;
+                    if line.trim() == "AUTHENTICATE +" {
+                        break;
+                    }
+                }
+
+                let auth_str = format!("\0{}\0{}", user, pass);
+                let auth_base64 = general_purpose::STANDARD.encode(auth_str);
+                c.send(Command::Authenticate(&auth_base64))?;
+
+                loop {
+                    let line = c.handshake_line(deadline, "SASL result (903)")?;
+                    // By numeric only: the 900 before it names our host,
+                    // which may well contain "904".
+                    match Message::parse(&line).map(|l| l.command) {
+                        Some(numeric) if numeric == "903" => break,
+                        Some(numeric) if numeric == "904" || numeric == "905" => {
+                            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "SASL authentication failed"));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
+            c.send(Command::CapEnd)?;
+        } else if sasl.is_some() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "Server does not support capability negotiation (needed for SASL)"));
+        }
+
+        loop {
+            let line = c.handshake_line(deadline, "end of MOTD (376/422)")?;
+            check_registration_error(&line)?;
+            if let Some(l) = Message::parse(&line) {
+                match l.command.as_str() {
+                    // The server's word on what our nick is, which it may
+                    // have cut to its NICKLEN.
+                    "001" => c.nick = l.params.first().cloned().unwrap_or_else(|| c.nick.clone()),
+                    "005" => {
+                        c.whox |= l.params.iter().any(|p| p == "WHOX");
+                        c.monitor |= l.params.iter().any(|p| p == "MONITOR" || p.starts_with("MONITOR="));
+                        if let Some(casemapping) = l.params.iter().find_map(|p| p.strip_prefix("CASEMAPPING=")).and_then(Casemapping::parse) {
+                            c.casemapping = casemapping;
+                        }
+                    }
+                    // By numeric only: a MOTD line, nick or host may
+                    // contain the digits too.
+                    "376" | "422" => break,
+                    _ => {}
+                }
+            }
+        }
+
+        c.connected_at = SystemTime::now();
+        c.send(Command::Join(&settings.channels.join(",")))?;
+        Ok(c)
+    }
+
+    /// Sends `CAP REQ` for `caps` and returns whether the server granted them
+    /// (ACK) or refused them all (NAK).
+    fn request_caps(&mut self, caps: &[&str], deadline: Instant) -> io::Result<bool> {
+        self.send(Command::CapReq(&caps.join(" ")))?;
+        loop {
+            let line = self.handshake_line(deadline, "CAP ACK")?;
+            let Some(l) = Message::parse(&line).filter(|l| l.command == "CAP") else {
+                check_registration_error(&line)?;
+                continue;
+            };
+            match l.params.get(1).map(|s| s.as_str()) {
+                Some("ACK") => {
+                    let acked = l.params.last().map(|s| s.as_str()).unwrap_or("");
+                    self.caps.extend(acked.split_whitespace().map(|cap| cap.to_string()));
+                    return Ok(true);
+                }
+                Some("NAK") => return Ok(false),
+                _ => {}
+            }
+        }
+    }
+
+    /// Reads a line during registration, failing once `deadline` passes so a
+    /// server that stops answering mid-handshake can't hang the bridge.
+    fn handshake_line(&mut self, deadline: Instant, waiting_for: &str) -> io::Result<String> {
+        let timed_out = || io::Error::new(io::ErrorKind::TimedOut, format!("IRC registration timed out waiting for {}", waiting_for));
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(timed_out());
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        match self.receive_message() {
+            Err(e) if is_read_timeout(&e) => Err(timed_out()),
+            Ok(line) => match Message::parse(&line) {
+                Some(l) if l.command == "ERROR" => Err(io::Error::new(io::ErrorKind::ConnectionAborted, ServerError(l.params.last().cloned().unwrap_or_default()))),
+                // Servers may answer NICK at any point of registration.
+                Some(l) if l.command == "433" => {
+                    if self.nick_fallbacks == MAX_NICK_FALLBACKS {
+                        return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("Nick {} is in use (433)", self.nick)));
+                    }
+                    self.nick_fallbacks += 1;
+                    let fallback = format!("{}_", self.nick);
+                    logging::warn("irc-nick", format!("Nick {} is in use; registering as {} for now", self.nick, fallback));
+                    self.send(Command::Nick(&fallback))?;
+                    self.nick = fallback;
+                    Ok(line)
+                }
+                _ => Ok(line),
+            },
+            other => other,
+        }
+    }
+
+    /// Collects the (possibly multi-line) `CAP LS` reply as capabilities and
+    /// their values, e.g. `sasl` => `PLAIN,EXTERNAL`. Returns `None` when the
+    /// server carries on with registration instead, i.e. has no CAP support.
+    fn read_cap_ls(&mut self, deadline: Instant) -> io::Result<Option<HashMap<String, String>>> {
+        let mut offered = HashMap::new();
+        loop {
+            let line = self.handshake_line(deadline, "CAP LS")?;
+            check_registration_error(&line)?;
+            let Some(l) = Message::parse(&line) else { continue };
+            match l.command.as_str() {
+                "CAP" if l.params.get(1).is_some_and(|s| s == "LS") => {
+                    let more = l.params.len() > 3 && l.params[2] == "*";
+                    let caps = l.params.last().map(|s| s.as_str()).unwrap_or("");
+                    offered.extend(caps.split_whitespace().map(|cap| {
+                        let (name, value) = cap.split_once('=').unwrap_or((cap, ""));
+                        (name.to_string(), value.to_string())
+                    }));
+                    if !more {
+                        return Ok(Some(offered));
+                    }
+                }
+                "001" | "421" => return Ok(None),
+                _ => {}
+            }
+        }
+    }
+
+    pub fn send(&mut self, command: Command) -> io::Result<()> {
+        write_line(&mut self.stream, &command.encode(), self.trace)
+    }
+
+    /// Reads one line. A read timeout keeps any partial line buffered for
+    /// the next call.
+    pub fn receive_message(&mut self) -> io::Result<String> {
+        let n = self.reader.read_until(b'\n', &mut self.pending)?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed"));
+        }
+        let line = decode_line(std::mem::take(&mut self.pending));
+        if self.trace {
+            let context = Context { direction: Some("in"), ..Context::default() };
+            logging::log(logging::Level::Debug, "irc-trace", context, format!("[irc] << {}", redact_credentials(line.trim_end())));
+        }
+        Ok(line)
+    }
+}
+
+/// Whether an encoded line is a message someone reads, as `--irc-send-delay`
+/// spaces out.
+fn is_message_line(line: &str) -> bool {
+    ["PRIVMSG ", "NOTICE ", "RELAYMSG "].iter().any(|command| line.starts_with(command))
+}
+
+fn write_line(stream: &mut IrcStream, data: &str, trace: bool) -> io::Result<()> {
+    if trace {
+        for line in data.lines() {
+            let context = Context { direction: Some("out"), ..Context::default() };
+            logging::log(logging::Level::Debug, "irc-trace", context, format!("[irc] >> {}", redact_credentials(line)));
+        }
+    }
+    stream.write_all(data.as_bytes())?;
+    stream.flush()
+}
+
+/// Whether the mechanisms a server lists for `sasl` include PLAIN, the only
+/// one the bridge speaks. Servers that list none accept it by convention.
+fn offers_plain(mechanisms: &str) -> bool {
+    mechanisms.is_empty() || mechanisms.split(',').any(|m| m.eq_ignore_ascii_case("PLAIN"))
+}
+
+/// The first line of a password file, without its line ending.
+pub fn read_password_file(path: &Path) -> io::Result<String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| io::Error::new(e.kind(), format!("Cannot read {}: {}", path.display(), e)))?;
+    match contents.lines().next() {
+        Some(password) if !password.is_empty() => Ok(password.to_string()),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} holds no password", path.display()))),
+    }
+}
+
+/// Hides the server password and SASL payloads in traced lines, and the
+/// arguments of `bridge` admin commands sent to the bridge privately, which
+/// carry the admin password and room keys.
+fn redact_credentials(line: &str) -> Cow<'_, str> {
+    let command = line.split(' ').next().unwrap_or("");
+    if command.eq_ignore_ascii_case("PASS") {
+        return Cow::Borrowed("PASS <redacted>");
+    }
+    if command.eq_ignore_ascii_case("AUTHENTICATE") {
+        let arg = line[command.len()..].trim();
+        if arg != "+" && !arg.eq_ignore_ascii_case("PLAIN") {
+            return Cow::Borrowed("AUTHENTICATE <redacted>");
+        }
+    }
+    let private_text = Message::parse(line)
+        .filter(|m| m.command == "PRIVMSG" && m.params.first().is_some_and(|target| !is_channel(target)))
+        .and_then(|m| m.params.get(1).cloned())
+        .filter(|text| line.ends_with(text.as_str()));
+    if let Some(end) = private_text.as_deref().and_then(admin_word_end) {
+        let start = line.len() - private_text.as_deref().map_or(0, str::len);
+        return Cow::Owned(format!("{} <redacted>", &line[..start + end]));
+    }
+    Cow::Borrowed(line)
+}
+
+/// Where the word `bridge` ends in `text` (as in `.bridge login ...` or
+/// `bridge: bridge add ...`), when anything follows it.
+fn admin_word_end(text: &str) -> Option<usize> {
+    let mut offset = 0;
+    for word in text.split(' ') {
+        let end = offset + word.len();
+        if word.trim_matches(|c: char| !c.is_alphanumeric()).eq_ignore_ascii_case("bridge") && !text[end..].trim().is_empty() {
+            return Some(end);
+        }
+        offset = end + 1;
+    }
+    None
+}
+
+/// IRC doesn't mandate an encoding, so lines that aren't valid UTF-8 are
+/// read as latin-1 (the most common legacy encoding) rather than failing
+/// the whole connection.
+fn decode_line(bytes: Vec<u8>) -> String {
+    String::from_utf8(bytes).unwrap_or_else(|e| e.into_bytes().iter().map(|&b| b as char).collect())
+}
+
+fn check_registration_error(line: &str) -> io::Result<()> {
+    if Message::parse(line).is_some_and(|l| l.command == "464") {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "IRC server password incorrect (464)"));
+    }
+    Ok(())
+}
+
+pub fn is_read_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_quiet_connection_is_probed_before_it_is_dropped() {
+        let idle = Duration::from_secs(30);
+        let start = Instant::now();
+        let mut keepalive = Keepalive::default();
+        assert_eq!(keepalive.check(start, Duration::from_secs(5), idle, false, 2), Watchdog::Wait);
+        // Silent past the idle timeout: a PING first, even if none is due.
+        assert_eq!(keepalive.check(start, idle, idle, false, 2), Watchdog::Ping);
+        keepalive.outstanding = Some(("amz-1".into(), start));
+        assert_eq!(keepalive.check(start + Duration::from_secs(10), idle + Duration::from_secs(10), idle, false, 2), Watchdog::Wait);
+        // Its PONG is the first line in a while, and all is well.
+        keepalive.acknowledge("amz-1");
+        assert_eq!(keepalive.check(start + Duration::from_secs(10), Duration::ZERO, idle, false, 2), Watchdog::Wait);
+
+        keepalive.outstanding = Some(("amz-2".into(), start));
+        let silent = idle + PONG_WAIT;
+        assert!(matches!(keepalive.check(start + PONG_WAIT, silent, idle, false, 2), Watchdog::Dead(reason) if reason.contains("no answer to a PING")));
+    }
+
+    #[test]
+    fn unanswered_keepalives_are_counted() {
+        let start = Instant::now();
+        let idle = Duration::from_secs(120);
+        let mut keepalive = Keepalive { outstanding: Some(("amz-1".into(), start)), ..Keepalive::default() };
+        assert_eq!(keepalive.check(start, Duration::from_secs(60), idle, true, 2), Watchdog::Ping);
+        assert_eq!(keepalive.check(start, Duration::from_secs(60), idle, true, 2), Watchdog::Dead("2 keep-alive PINGs went unanswered".into()));
+    }
+
+    #[test]
+    fn sasl_needs_plain_among_the_offered_mechanisms() {
+        assert!(offers_plain(""));
+        assert!(offers_plain("EXTERNAL,plain"));
+        assert!(!offers_plain("EXTERNAL,SCRAM-SHA-256"));
+    }
+
+    #[test]
+    fn traced_credentials_are_redacted() {
+        assert_eq!(redact_credentials("PASS :hunter22"), "PASS <redacted>");
+        assert_eq!(redact_credentials("AUTHENTICATE AGJyaWRnZQBodW50ZXIyMg=="), "AUTHENTICATE <redacted>");
+        assert_eq!(redact_credentials("AUTHENTICATE PLAIN"), "AUTHENTICATE PLAIN");
+        assert_eq!(redact_credentials("AUTHENTICATE +"), "AUTHENTICATE +");
+        assert_eq!(redact_credentials(":mock 903 bridge :SASL authentication successful"), ":mock 903 bridge :SASL authentication successful");
+
+        assert_eq!(redact_credentials(":alice!a@host PRIVMSG bridge :.bridge login hunter2hunter2"), ":alice!a@host PRIVMSG bridge :.bridge <redacted>");
+        assert_eq!(
+            redact_credentials(&format!("@time=2024-01-01T00:00:00Z :alice!a@host PRIVMSG Bridge :.bridge add #chan room:{}", "ab".repeat(32))),
+            "@time=2024-01-01T00:00:00Z :alice!a@host PRIVMSG Bridge :.bridge <redacted>"
+        );
+        assert_eq!(redact_credentials(":alice!a@host PRIVMSG bridge :bridge: bridge login secret"), ":alice!a@host PRIVMSG bridge :bridge: <redacted>");
+        assert_eq!(redact_credentials(":alice!a@host PRIVMSG bridge :hello there"), ":alice!a@host PRIVMSG bridge :hello there");
+        assert_eq!(redact_credentials(":alice!a@host PRIVMSG #test :the bridge works"), ":alice!a@host PRIVMSG #test :the bridge works");
+    }
+}
@@ -0,0 +1,200 @@
+//! IRC wire format: typed commands for everything the bridge sends and a
+//! parser for received lines.
+
+/// A command the bridge sends. `encode` produces the full line including
+/// the trailing CRLF; CR, LF and NUL inside parameters are replaced so a
+/// parameter can never end the line early.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command<'a> {
+    Pass(&'a str),
+    Nick(&'a str),
+    User { user: &'a str, mode: &'a str, realname: &'a str },
+    Join(&'a str),
+    Part { channel: &'a str, reason: &'a str },
+    Quit(&'a str),
+    Privmsg { target: &'a str, text: &'a str },
+    Notice { target: &'a str, text: &'a str },
+    /// `draft/relaymsg`: a channel message shown as sent by `nick`.
+    Relaymsg { target: &'a str, nick: &'a str, text: &'a str },
+    Ping(&'a str),
+    Pong(&'a str),
+    Whois(&'a str),
+    Names(&'a str),
+    /// WHOX: `fields` is e.g. `%na` for nick and account.
+    Who { mask: &'a str, fields: &'a str },
+    /// `MONITOR +` or `MONITOR -`: be told when `nick` signs on or off.
+    Monitor { add: bool, nick: &'a str },
+    Ison(&'a str),
+    CapLs,
+    /// Space separated capability list.
+    CapReq(&'a str),
+    CapEnd,
+    Authenticate(&'a str),
+}
+
+impl Command<'_> {
+    pub fn encode(&self) -> String {
+        match *self {
+            Command::Pass(password) => line("PASS", &[], Some(password)),
+            Command::Nick(nick) => line("NICK", &[nick], None),
+            Command::User { user, mode, realname } => line("USER", &[user, mode, "*"], Some(realname)),
+            Command::Join(channel) => line("JOIN", &[channel], None),
+            Command::Part { channel, reason } => line("PART", &[channel], Some(reason)),
+            Command::Quit(reason) => line("QUIT", &[], Some(reason)),
+            Command::Privmsg { target, text } => line("PRIVMSG", &[target], Some(text)),
+            Command::Notice { target, text } => line("NOTICE", &[target], Some(text)),
+            Command::Relaymsg { target, nick, text } => line("RELAYMSG", &[target, nick], Some(text)),
+            Command::Ping(token) => line("PING", &[], Some(token)),
+            Command::Pong(token) => line("PONG", &[], Some(token)),
+            Command::Whois(nick) => line("WHOIS", &[nick], None),
+            Command::Names(channel) => line("NAMES", &[channel], None),
+            Command::Who { mask, fields } => line("WHO", &[mask, fields], None),
+            Command::Monitor { add, nick } => line("MONITOR", &[if add { "+" } else { "-" }, nick], None),
+            Command::Ison(nick) => line("ISON", &[nick], None),
+            Command::CapLs => line("CAP", &["LS", "302"], None),
+            Command::CapReq(caps) => line("CAP", &["REQ"], Some(caps)),
+            Command::CapEnd => line("CAP", &["END"], None),
+            Command::Authenticate(payload) => line("AUTHENTICATE", &[payload], None),
+        }
+    }
+}
+
+fn line(command: &str, middle: &[&str], trailing: Option<&str>) -> String {
+    let clean = |s: &str| s.replace(['\r', '\n', '\0'], " ");
+    let mut out = String::from(command);
+    for param in middle {
+        out.push(' ');
+        // Middle parameters can't contain spaces either.
+        out.push_str(&clean(param).replace(' ', ""));
+    }
+    if let Some(trailing) = trailing {
+        out.push_str(" :");
+        out.push_str(&clean(trailing));
+    }
+    out.push_str("\r\n");
+    out
+}
+
+/// A parsed line: IRCv3 tags, the optional `:prefix`, the command
+/// (uppercased) and its parameters, the trailing one included.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub tags: Vec<(String, String)>,
+    pub prefix: Option<String>,
+    pub nick: Option<String>,
+    pub command: String,
+    pub params: Vec<String>,
+}
+
+impl Message {
+    pub fn parse(raw: &str) -> Option<Message> {
+        let mut rest = raw.trim_end_matches(['\r', '\n']);
+
+        let mut tags = Vec::new();
+        if let Some(stripped) = rest.strip_prefix('@') {
+            let (raw_tags, after) = stripped.split_once(' ')?;
+            for tag in raw_tags.split(';').filter(|t| !t.is_empty()) {
+                let (key, value) = tag.split_once('=').unwrap_or((tag, ""));
+                tags.push((key.to_string(), unescape_tag_value(value)));
+            }
+            rest = after.trim_start_matches(' ');
+        }
+
+        let mut prefix = None;
+        if let Some(stripped) = rest.strip_prefix(':') {
+            let (p, after) = stripped.split_once(' ')?;
+            prefix = Some(p.to_string());
+            rest = after;
+        }
+        let nick = prefix.as_deref().and_then(|p| p.split('!').next()).map(|n| n.to_string());
+
+        let (command, mut rest) = rest.split_once(' ').unwrap_or((rest, ""));
+        if command.is_empty() {
+            return None;
+        }
+
+        let mut params = Vec::new();
+        while !rest.is_empty() {
+            if let Some(trailing) = rest.strip_prefix(':') {
+                params.push(trailing.to_string());
+                break;
+            }
+            match rest.split_once(' ') {
+                Some((param, after)) => {
+                    if !param.is_empty() {
+                        params.push(param.to_string());
+                    }
+                    rest = after;
+                }
+                None => {
+                    params.push(rest.to_string());
+                    break;
+                }
+            }
+        }
+
+        Some(Message { tags, prefix, nick, command: command.to_ascii_uppercase(), params })
+    }
+
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+}
+
+fn unescape_tag_value(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => out.push(';'),
+            Some('s') => out.push(' '),
+            Some('r') => out.push('\r'),
+            Some('n') => out.push('\n'),
+            Some(other) => out.push(other),
+            None => {}
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_commands() {
+        assert_eq!(Command::Privmsg { target: "#test", text: "hi there" }.encode(), "PRIVMSG #test :hi there\r\n");
+        assert_eq!(Command::User { user: "bridge", mode: "0", realname: "Bridge bot" }.encode(), "USER bridge 0 * :Bridge bot\r\n");
+        assert_eq!(Command::CapLs.encode(), "CAP LS 302\r\n");
+        assert_eq!(Command::Part { channel: "#test", reason: "bye" }.encode(), "PART #test :bye\r\n");
+        assert_eq!(Command::Who { mask: "#test", fields: "%na" }.encode(), "WHO #test %na\r\n");
+        assert_eq!(Command::Monitor { add: true, nick: "bridge" }.encode(), "MONITOR + bridge\r\n");
+        assert_eq!(Command::Relaymsg { target: "#test", nick: "alice/amz", text: "hi" }.encode(), "RELAYMSG #test alice/amz :hi\r\n");
+    }
+
+    #[test]
+    fn parameters_cannot_inject_lines() {
+        assert_eq!(Command::Privmsg { target: "#test", text: "a\r\nQUIT :x" }.encode(), "PRIVMSG #test :a  QUIT :x\r\n");
+        assert_eq!(Command::Join("#a\r\nQUIT").encode(), "JOIN #aQUIT\r\n");
+        assert_eq!(Command::Nick("evil nick").encode(), "NICK evilnick\r\n");
+    }
+
+    #[test]
+    fn parses_tags_prefix_and_params() {
+        let msg = Message::parse("@time=2024-03-01T12:00:00Z;msgid=a\\sb :alice!a@host privmsg #test :hello world\r\n").unwrap();
+        assert_eq!(msg.tag("time"), Some("2024-03-01T12:00:00Z"));
+        assert_eq!(msg.tag("msgid"), Some("a b"));
+        assert_eq!(msg.prefix.as_deref(), Some("alice!a@host"));
+        assert_eq!(msg.nick.as_deref(), Some("alice"));
+        assert_eq!(msg.command, "PRIVMSG");
+        assert_eq!(msg.params, vec!["#test".to_string(), "hello world".to_string()]);
+
+        let ping = Message::parse("PING :irc.example").unwrap();
+        assert_eq!((ping.prefix, ping.command.as_str(), ping.params), (None, "PING", vec!["irc.example".to_string()]));
+        assert_eq!(Message::parse(""), None);
+    }
+}
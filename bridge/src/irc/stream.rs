@@ -0,0 +1,104 @@
+//! The connection under `CustomIrcClient`: TCP, or a Unix domain socket
+//! for bouncers that only listen locally (`unix:/run/soju/soju.sock`).
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+/// Prefix of a server address that names a Unix domain socket.
+pub const UNIX_PREFIX: &str = "unix:";
+
+pub enum IrcStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl IrcStream {
+    /// Connects to `host:port`, or to the socket at `unix:/path`.
+    pub fn connect(server: &str, timeout: Duration) -> io::Result<Self> {
+        match server.strip_prefix(UNIX_PREFIX) {
+            #[cfg(unix)]
+            Some(path) => Ok(IrcStream::Unix(UnixStream::connect(path)?)),
+            #[cfg(not(unix))]
+            Some(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "Unix domain sockets are not supported on this platform")),
+            None => connect_with_timeout(server, timeout).map(IrcStream::Tcp),
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(match self {
+            IrcStream::Tcp(s) => IrcStream::Tcp(s.try_clone()?),
+            #[cfg(unix)]
+            IrcStream::Unix(s) => IrcStream::Unix(s.try_clone()?),
+        })
+    }
+
+    /// Closes both directions, which also ends a read blocked on another
+    /// handle to the same socket.
+    pub fn shutdown(&self) -> io::Result<()> {
+        match self {
+            IrcStream::Tcp(s) => s.shutdown(Shutdown::Both),
+            #[cfg(unix)]
+            IrcStream::Unix(s) => s.shutdown(Shutdown::Both),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            IrcStream::Tcp(s) => s.set_read_timeout(timeout),
+            #[cfg(unix)]
+            IrcStream::Unix(s) => s.set_read_timeout(timeout),
+        }
+    }
+}
+
+impl Read for IrcStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            IrcStream::Tcp(s) => s.read(buf),
+            #[cfg(unix)]
+            IrcStream::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for IrcStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            IrcStream::Tcp(s) => s.write(buf),
+            #[cfg(unix)]
+            IrcStream::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            IrcStream::Tcp(s) => s.flush(),
+            #[cfg(unix)]
+            IrcStream::Unix(s) => s.flush(),
+        }
+    }
+}
+
+/// Tries each address `server_url` resolves to, giving every attempt at most
+/// `timeout`, so a black-holed server fails fast instead of hanging for the
+/// OS default.
+fn connect_with_timeout(server_url: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_err = None;
+    for addr in server_url.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                last_err = Some(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("Connecting to {} ({}) timed out after {}s", server_url, addr, timeout.as_secs()),
+                ));
+            }
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} did not resolve to any address", server_url))))
+}
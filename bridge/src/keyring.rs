@@ -0,0 +1,24 @@
+/// Reads a room password from the OS keyring (Secret Service, macOS
+/// Keychain or Windows Credential Manager), so it needn't be typed or kept
+/// in a file. Entries made by `secret-tool`, Python's `keyring` or anything
+/// else using the `keyring` crate are found under the same names.
+pub fn load_password(service: &str, account: &str) -> Result<String, String> {
+    let entry = ::keyring::Entry::new(service, account).map_err(|e| format!("Cannot open the keyring: {}", e))?;
+    match entry.get_password() {
+        Ok(password) if password.is_empty() => Err(format!("Keyring entry for service {} and account {} is empty", service, account)),
+        Ok(password) => Ok(password),
+        Err(::keyring::Error::NoEntry) => Err(format!("No keyring entry for service {} and account {}", service, account)),
+        Err(e) => Err(format!("Cannot read the keyring: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_missing_entry_names_service_and_account() {
+        ::keyring::set_default_credential_builder(::keyring::mock::default_credential_builder());
+        assert_eq!(load_password("amnezichat", "room1"), Err("No keyring entry for service amnezichat and account room1".into()));
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+/// How log lines are written (`--log-format`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// The plain messages, informational ones on stdout and the rest on
+    /// stderr.
+    #[default]
+    Text,
+    /// One JSON object per line on stdout, for log collectors.
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "text" => Some(LogFormat::Text),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+static FORMAT: OnceLock<LogFormat> = OnceLock::new();
+
+/// Chooses the format once at startup; later calls are ignored.
+pub fn set_format(format: LogFormat) {
+    let _ = FORMAT.set(format);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+/// What an event is about, where known. Only shows in JSON lines.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Context<'a> {
+    pub room_id: Option<&'a str>,
+    pub channel: Option<&'a str>,
+    /// `irc-to-amnezichat` or `amnezichat-to-irc` for relayed traffic, `in`
+    /// or `out` for raw IRC lines.
+    pub direction: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    timestamp: String,
+    level: Level,
+    event: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    room_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    channel: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    direction: Option<&'a str>,
+}
+
+fn json_line(level: Level, event: &str, context: Context, text: &str, now: SystemTime) -> String {
+    let (message, error) = if level == Level::Error { (None, Some(text)) } else { (Some(text), None) };
+    let line = JsonLine {
+        timestamp: rfc3339(now),
+        level,
+        event,
+        message,
+        error,
+        room_id: context.room_id,
+        channel: context.channel,
+        direction: context.direction,
+    };
+    serde_json::to_string(&line).unwrap_or_default()
+}
+
+/// UTC time as `2024-05-01T12:34:56.789Z`.
+fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rem) = ((secs / 86_400) as i64, secs % 86_400);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Writes one log event in the configured format.
+pub fn log(level: Level, event: &str, context: Context, message: impl AsRef<str>) {
+    let message = message.as_ref();
+    match (FORMAT.get().copied().unwrap_or_default(), level) {
+        (LogFormat::Json, _) => println!("{}", json_line(level, event, context, message, SystemTime::now())),
+        (LogFormat::Text, Level::Info) => println!("{}", message),
+        (LogFormat::Text, _) => eprintln!("{}", message),
+    }
+}
+
+pub fn info(event: &str, message: impl AsRef<str>) {
+    log(Level::Info, event, Context::default(), message);
+}
+
+pub fn warn(event: &str, message: impl AsRef<str>) {
+    log(Level::Warn, event, Context::default(), message);
+}
+
+/// How often a still-repeating error is summarized.
+const ROLLUP_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Repeat {
+    message: String,
+    count: u32,
+    since: Instant,
+}
+
+/// Collapses identical consecutive errors per category: the first one is
+/// printed right away, repeats are counted and summarized every
+/// `ROLLUP_INTERVAL`.
+#[derive(Default)]
+struct RepeatFilter {
+    repeats: HashMap<&'static str, Repeat>,
+}
+
+impl RepeatFilter {
+    fn record(&mut self, category: &'static str, message: String, now: Instant) -> Vec<String> {
+        let mut lines = Vec::new();
+        if let Some(r) = self.repeats.get_mut(category) {
+            if r.message == message {
+                r.count += 1;
+                let window = now.duration_since(r.since);
+                if window >= ROLLUP_INTERVAL {
+                    lines.push(format!("{} (repeated {} times in last {}s)", message, r.count, window.as_secs()));
+                    r.count = 0;
+                    r.since = now;
+                }
+                return lines;
+            }
+            lines.extend(Self::summary(r));
+        }
+        lines.push(message.clone());
+        self.repeats.insert(category, Repeat { message, count: 0, since: now });
+        lines
+    }
+
+    fn recover(&mut self, category: &'static str) -> Option<String> {
+        self.repeats.remove(category).as_ref().and_then(Self::summary)
+    }
+
+    fn summary(r: &Repeat) -> Option<String> {
+        (r.count > 0).then(|| format!("{} (repeated {} more times)", r.message, r.count))
+    }
+}
+
+static ERRORS: LazyLock<Mutex<RepeatFilter>> = LazyLock::new(|| Mutex::new(RepeatFilter::default()));
+
+/// Logs an error, folding it into a counted summary if it repeats the last
+/// error of the same category.
+pub fn log_error(category: &'static str, message: impl Into<String>) {
+    log_error_in(category, Context::default(), message);
+}
+
+/// `log_error` for an event tied to a room, channel or direction.
+pub fn log_error_in(category: &'static str, context: Context, message: impl Into<String>) {
+    let mut errors = ERRORS.lock().unwrap_or_else(|e| e.into_inner());
+    for line in errors.record(category, message.into(), Instant::now()) {
+        log(Level::Error, category, context, line);
+    }
+}
+
+/// Marks a category healthy again, flushing any pending repeat count.
+pub fn log_recovered(category: &'static str) {
+    let mut errors = ERRORS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(line) = errors.recover(category) {
+        log(Level::Error, category, Context::default(), line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ten_minute_outage_logs_a_handful_of_lines() {
+        let mut filter = RepeatFilter::default();
+        let start = Instant::now();
+        let mut printed = Vec::new();
+        for second in 0..600 {
+            printed.extend(filter.record("poll", "Amnezichat pull error: refused".into(), start + Duration::from_secs(second)));
+        }
+        printed.extend(filter.recover("poll"));
+
+        assert_eq!(printed[0], "Amnezichat pull error: refused");
+        assert_eq!(printed[1], "Amnezichat pull error: refused (repeated 60 times in last 60s)");
+        assert!(printed.len() <= 12, "{} lines", printed.len());
+    }
+
+    #[test]
+    fn json_lines_carry_the_event_fields() {
+        let context = Context { room_id: Some("room1"), channel: Some("#test"), direction: Some("amnezichat-to-irc") };
+        let at = UNIX_EPOCH + Duration::from_millis(1_714_566_896_789);
+        let line: serde_json::Value = serde_json::from_str(&json_line(Level::Error, "amnezichat-poll", context, "pull timeout", at)).unwrap();
+        assert_eq!(
+            line,
+            serde_json::json!({
+                "timestamp": "2024-05-01T12:34:56.789Z",
+                "level": "error",
+                "event": "amnezichat-poll",
+                "error": "pull timeout",
+                "room_id": "room1",
+                "channel": "#test",
+                "direction": "amnezichat-to-irc",
+            })
+        );
+        let line = json_line(Level::Info, "startup", Context::default(), "launched", UNIX_EPOCH);
+        assert_eq!(line, r#"{"timestamp":"1970-01-01T00:00:00.000Z","level":"info","event":"startup","message":"launched"}"#);
+        assert_eq!(rfc3339(UNIX_EPOCH + Duration::from_secs(951_782_400)), "2000-02-29T00:00:00.000Z");
+    }
+
+    #[test]
+    fn a_different_error_flushes_the_previous_count() {
+        let mut filter = RepeatFilter::default();
+        let now = Instant::now();
+        filter.record("send", "timeout".into(), now);
+        filter.record("send", "timeout".into(), now);
+        let lines = filter.record("send", "refused".into(), now);
+        assert_eq!(lines, vec!["timeout (repeated 1 more times)".to_string(), "refused".to_string()]);
+        assert_eq!(filter.record("poll", "refused".into(), now), vec!["refused".to_string()]);
+    }
+}
@@ -0,0 +1,105 @@
+use std::sync::LazyLock;
+
+use regex::{Captures, Regex};
+
+/// What happens to Amnezichat markup in room messages sent to IRC.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MarkupMode {
+    /// Known formatting tags become IRC formatting codes; other tags are
+    /// removed.
+    #[default]
+    Irc,
+    /// Every tag is removed, leaving plain text.
+    Strip,
+}
+
+impl MarkupMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "irc" => Some(MarkupMode::Irc),
+            "strip" => Some(MarkupMode::Strip),
+            _ => None,
+        }
+    }
+}
+
+/// Blocks that carry no text for IRC: profile pictures, attachments and
+/// length padding. Removed together with their content.
+static HIDDEN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)<pfp>.*?</pfp>|<media>.*?</media>|<padding>.*?</padding>").unwrap());
+static LINK: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?is)<a\s[^<>]*?href\s*=\s*["']([^"']*)["'][^<>]*>(.*?)</a>"#).unwrap());
+/// Anything shaped like an HTML tag. A `<` not directly followed by a
+/// letter, as in `<3` or `a < b`, is left alone.
+static TAG: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"</?([a-zA-Z][a-zA-Z0-9]*)(?:\s[^<>]*)?/?>").unwrap());
+
+/// Drops the blocks with no text (`<pfp>`, `<media>`, `<padding>`). Done as
+/// soon as a message is decrypted; formatting tags are kept for `render`.
+pub fn remove_hidden(text: &str) -> String {
+    HIDDEN.replace_all(text, "").into_owned()
+}
+
+/// IRC formatting code for a tag, if it has one.
+fn irc_code(tag: &str) -> Option<char> {
+    match tag.to_ascii_lowercase().as_str() {
+        "strong" | "b" => Some('\x02'),
+        "em" | "i" => Some('\x1d'),
+        "u" | "ins" => Some('\x1f'),
+        "s" | "del" | "strike" => Some('\x1e'),
+        "code" | "pre" => Some('\x11'),
+        _ => None,
+    }
+}
+
+/// Turns markup into IRC formatting or plain text per `mode`. Links become
+/// `text (url)`, line breaks spaces, and any other tag disappears while its
+/// text stays. Must run after sanitizing, which removes control codes a
+/// room member typed.
+pub fn render(text: &str, mode: MarkupMode) -> String {
+    let text = remove_hidden(text);
+    let text = LINK.replace_all(&text, |caps: &Captures| {
+        let (url, label) = (&caps[1], TAG.replace_all(&caps[2], ""));
+        if label.trim().is_empty() || label.trim() == url {
+            url.to_string()
+        } else {
+            format!("{} ({})", caps[2].trim(), url)
+        }
+    });
+    let text = TAG.replace_all(&text, |caps: &Captures| {
+        let name = &caps[1];
+        if name.eq_ignore_ascii_case("br") {
+            return " ".to_string();
+        }
+        match (mode, irc_code(name)) {
+            (MarkupMode::Irc, Some(code)) => code.to_string(),
+            _ => String::new(),
+        }
+    });
+    text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&#39;", "'").replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_known_tags_to_irc_formatting() {
+        assert_eq!(render("<strong>bold</strong> and <em>it</em>", MarkupMode::Irc), "\x02bold\x02 and \x1dit\x1d");
+        assert_eq!(render("run <code>ls -l</code>", MarkupMode::Irc), "run \x11ls -l\x11");
+        assert_eq!(render("<strong>bold</strong> and <em>it</em>", MarkupMode::Strip), "bold and it");
+    }
+
+    #[test]
+    fn unknown_tags_are_removed_but_keep_their_text() {
+        assert_eq!(render("<span class=\"x\">hi</span><br/>there", MarkupMode::Irc), "hi there");
+        assert_eq!(render("see <a href=\"https://example.com\">the docs</a>", MarkupMode::Irc), "see the docs (https://example.com)");
+        assert_eq!(render("<a href='https://example.com'>https://example.com</a>", MarkupMode::Irc), "https://example.com");
+        assert_eq!(render("<pfp>aGk=</pfp>hello<media>AAAA</media>", MarkupMode::Irc), "hello");
+    }
+
+    #[test]
+    fn text_that_only_looks_like_markup_survives() {
+        assert_eq!(render("i <3 you, 1 < 2 > 0", MarkupMode::Irc), "i <3 you, 1 < 2 > 0");
+        assert_eq!(render("&lt;b&gt; is bold &amp; fine", MarkupMode::Irc), "<b> is bold & fine");
+    }
+}
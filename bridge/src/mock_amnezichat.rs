@@ -0,0 +1,176 @@
+//! A minimal Amnezichat server for tests: `POST /send` records the message
+//! envelope and its room, `GET /messages` returns whatever `publish` added.
+//! Rooms aren't told apart when reading.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::envelope;
+
+#[derive(Default)]
+struct Room {
+    sent: Mutex<Vec<(String, String)>>,
+    published: Mutex<Vec<String>>,
+    polls: AtomicUsize,
+    /// Largest `POST /send` body accepted; 0 accepts any.
+    max_body: AtomicUsize,
+    /// How long each `POST /send` takes to answer, in milliseconds.
+    send_delay_ms: AtomicUsize,
+    sends_in_flight: AtomicUsize,
+    peak_sends: AtomicUsize,
+}
+
+pub struct MockAmnezichat {
+    url: String,
+    room: Arc<Room>,
+}
+
+impl MockAmnezichat {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let room = Arc::new(Room::default());
+        {
+            let room = Arc::clone(&room);
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let Ok(stream) = stream else { continue };
+                    let room = Arc::clone(&room);
+                    thread::spawn(move || serve(stream, room));
+                }
+            });
+        }
+        Self { url, room }
+    }
+
+    pub fn url(&self) -> String {
+        self.url.clone()
+    }
+
+    /// Encrypted payloads posted so far, envelope markers removed.
+    pub fn sent(&self) -> Vec<String> {
+        self.room.sent.lock().unwrap().iter().map(|(_, payload)| payload.clone()).collect()
+    }
+
+    /// Like `sent`, but only what was posted to `room_id`.
+    pub fn sent_to(&self, room_id: &str) -> Vec<String> {
+        self.room.sent.lock().unwrap().iter().filter(|(room, _)| room == room_id).map(|(_, payload)| payload.clone()).collect()
+    }
+
+    pub fn wait_for_sends(&self, count: usize, within: Duration) -> bool {
+        let deadline = Instant::now() + within;
+        while Instant::now() < deadline {
+            if self.room.sent.lock().unwrap().len() >= count {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    /// Adds an encrypted payload to what `GET /messages` returns.
+    pub fn publish(&self, payload: String) {
+        self.room.published.lock().unwrap().push(payload);
+    }
+
+    /// Answers 413 to sends with a body over `bytes`.
+    pub fn limit_body(&self, bytes: usize) {
+        self.room.max_body.store(bytes, Ordering::SeqCst);
+    }
+
+    /// Makes every send take `delay` before it is answered.
+    pub fn delay_sends(&self, delay: Duration) {
+        self.room.send_delay_ms.store(delay.as_millis() as usize, Ordering::SeqCst);
+    }
+
+    /// The most sends that were being answered at the same time.
+    pub fn peak_sends(&self) -> usize {
+        self.room.peak_sends.load(Ordering::SeqCst)
+    }
+
+    /// How often the room has been read.
+    pub fn polls(&self) -> usize {
+        self.room.polls.load(Ordering::SeqCst)
+    }
+
+    /// Waits until the room has been read `count` times.
+    pub fn wait_for_polls(&self, count: usize, within: Duration) -> bool {
+        let deadline = Instant::now() + within;
+        while Instant::now() < deadline {
+            if self.room.polls.load(Ordering::SeqCst) >= count {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+}
+
+fn serve(stream: TcpStream, room: Arc<Room>) {
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+            return;
+        }
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header).unwrap_or(0) == 0 {
+                return;
+            }
+            if header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+        let mut body = vec![0; content_length];
+        if reader.read_exact(&mut body).is_err() {
+            return;
+        }
+
+        let max_body = room.max_body.load(Ordering::SeqCst);
+        if request_line.starts_with("POST /send") && max_body > 0 && body.len() > max_body {
+            if writer.write_all(b"HTTP/1.1 413 Payload Too Large\r\nContent-Length: 0\r\n\r\n").is_err() {
+                return;
+            }
+            continue;
+        }
+        if request_line.starts_with("POST /send") {
+            let in_flight = room.sends_in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            room.peak_sends.fetch_max(in_flight, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(room.send_delay_ms.load(Ordering::SeqCst) as u64));
+            room.sends_in_flight.fetch_sub(1, Ordering::SeqCst);
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap_or_default();
+            let message = body["message"].as_str().unwrap_or("");
+            let payload = envelope::extract(message).first().copied().unwrap_or("");
+            let room_id = body["room_id"].as_str().unwrap_or("");
+            room.sent.lock().unwrap().push((room_id.to_string(), payload.to_string()));
+        }
+        let messages: Vec<String> = if request_line.starts_with("GET /messages") {
+            room.polls.fetch_add(1, Ordering::SeqCst);
+            room.published
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|payload| envelope::wrap(payload))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let body = serde_json::to_string(&messages).unwrap();
+        let response = format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        if writer.write_all(response.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,186 @@
+//! A tiny scripted IRC server for exercising `CustomIrcClient` in tests.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub type Responder = Arc<dyn Fn(&str) -> Vec<String> + Send + Sync>;
+
+/// Replies a well-behaved server would send to registration, SASL PLAIN
+/// and JOIN.
+pub fn default_responses(line: &str) -> Vec<String> {
+    let line = line.trim_end();
+    if line.starts_with("CAP LS") {
+        vec![":mock CAP * LS :sasl server-time batch".into()]
+    } else if let Some(caps) = line.strip_prefix("CAP REQ :") {
+        vec![format!(":mock CAP * ACK :{}", caps)]
+    } else if line == "AUTHENTICATE PLAIN" {
+        vec!["AUTHENTICATE +".into()]
+    } else if line.starts_with("AUTHENTICATE ") {
+        vec![":mock 903 bridge :SASL authentication successful".into()]
+    } else if line.starts_with("USER ") {
+        vec![
+            ":mock 001 bridge :Welcome to the mock network".into(),
+            ":mock 376 bridge :End of /MOTD command.".into(),
+        ]
+    } else if let Some(channels) = line.strip_prefix("JOIN ") {
+        channels
+            .split(',')
+            .flat_map(|channel| {
+                [format!(":bridge!bridge@mock JOIN {}", channel), format!(":mock 366 bridge {} :End of /NAMES list.", channel)]
+            })
+            .collect()
+    } else if let Some(token) = line.strip_prefix("PING ") {
+        vec![format!(":mock PONG mock {}", token)]
+    } else {
+        Vec::new()
+    }
+}
+
+pub struct MockIrcServer {
+    addr: SocketAddr,
+    received: Arc<Mutex<Vec<String>>>,
+    current: Arc<Mutex<Option<TcpStream>>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl MockIrcServer {
+    pub fn start() -> Self {
+        Self::start_with("127.0.0.1:0".parse().unwrap(), Arc::new(default_responses))
+    }
+
+    pub fn start_with(addr: SocketAddr, responder: Responder) -> Self {
+        // A previous server on the same port may still be releasing it.
+        let deadline = Instant::now() + Duration::from_secs(2);
+        let listener = loop {
+            match TcpListener::bind(addr) {
+                Ok(listener) => break listener,
+                Err(e) if Instant::now() >= deadline => panic!("bind mock IRC server: {}", e),
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        let addr = listener.local_addr().unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let current = Arc::new(Mutex::new(None));
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        {
+            let received = Arc::clone(&received);
+            let current = Arc::clone(&current);
+            let stopped = Arc::clone(&stopped);
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stopped.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let Ok(stream) = stream else { continue };
+                    *current.lock().unwrap() = Some(stream.try_clone().unwrap());
+                    let received = Arc::clone(&received);
+                    let responder = Arc::clone(&responder);
+                    thread::spawn(move || serve(stream, received, responder));
+                }
+            });
+        }
+
+        Self { addr, received, current, stopped }
+    }
+
+    pub fn addr(&self) -> String {
+        self.addr.to_string()
+    }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn received(&self) -> Vec<String> {
+        self.received.lock().unwrap().clone()
+    }
+
+    /// Writes a raw line to the most recently accepted client.
+    pub fn send(&self, line: &str) {
+        if let Some(stream) = self.current.lock().unwrap().as_mut() {
+            let _ = stream.write_all(format!("{}\r\n", line).as_bytes());
+        }
+    }
+
+    /// Writes raw bytes, e.g. a line that isn't valid UTF-8.
+    pub fn send_bytes(&self, bytes: &[u8]) {
+        if let Some(stream) = self.current.lock().unwrap().as_mut() {
+            let _ = stream.write_all(bytes);
+        }
+    }
+
+    /// Drops the active client connection from the server side.
+    pub fn disconnect(&self) {
+        if let Some(stream) = self.current.lock().unwrap().take() {
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+        }
+    }
+
+    /// Stops accepting connections and closes the active one.
+    pub fn shutdown(self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        self.disconnect();
+        let _ = TcpStream::connect(self.addr);
+    }
+
+    pub fn wait_for<F: Fn(&str) -> bool>(&self, pred: F, within: Duration) -> bool {
+        self.wait_for_count(pred, 1, within)
+    }
+
+    /// Waits until at least `count` received lines match, e.g. the JOIN of
+    /// a later connection.
+    pub fn wait_for_count<F: Fn(&str) -> bool>(&self, pred: F, count: usize, within: Duration) -> bool {
+        let deadline = Instant::now() + within;
+        while Instant::now() < deadline {
+            if self.received.lock().unwrap().iter().filter(|l| pred(l)).count() >= count {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+}
+
+/// Like a real server, registration replies are held back while capability
+/// negotiation is in progress (between `CAP LS` and `CAP END`).
+fn serve(stream: TcpStream, received: Arc<Mutex<Vec<String>>>, responder: Responder) {
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut negotiating = false;
+    let mut held = Vec::new();
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        let line = line.trim_end().to_string();
+        received.lock().unwrap().push(line.clone());
+        if line.starts_with("CAP LS") {
+            negotiating = true;
+        }
+
+        let mut replies = responder(&line);
+        if negotiating {
+            let (registration, other): (Vec<String>, Vec<String>) =
+                replies.into_iter().partition(|r| r.contains(" 001 ") || r.contains(" 376 ") || r.contains(" 422 "));
+            held.extend(registration);
+            replies = other;
+        }
+        if line == "CAP END" {
+            negotiating = false;
+            replies.append(&mut held);
+        }
+
+        for reply in replies {
+            if writer.write_all(format!("{}\r\n", reply).as_bytes()).is_err() {
+                return;
+            }
+        }
+    }
+}
@@ -1,26 +1,247 @@
-use regex::Regex;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use reqwest::Client;
-use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use std::borrow::Cow;
+use std::time::{Duration, Instant};
 use std::error::Error;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Mutex, OnceLock};
 
-use crate::{encryption::decrypt_data, MessageData};
+use crate::{encryption::decrypt_data, envelope, logging, markup::remove_hidden, pin, reactions::Reaction, MessageData};
 
-#[derive(Serialize, Deserialize)]
-struct Message {
-    message: String,
-    room_id: String,
+/// Sent instead of reqwest's default so requests don't stand out; matches
+/// the Tor Browser user agent.
+pub const DEFAULT_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; rv:128.0) Gecko/20100101 Firefox/128.0";
+
+/// Which HTTP redirects from the Amnezichat server are followed. Following
+/// one to another host would hand it the room id and encrypted messages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RedirectPolicy {
+    #[default]
+    None,
+    /// Only to the same scheme, host and port, e.g. a path rewrite.
+    SameOrigin,
 }
 
-pub fn create_client() -> Client {
+impl RedirectPolicy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "none" => Some(RedirectPolicy::None),
+            "same-origin" => Some(RedirectPolicy::SameOrigin),
+            _ => None,
+        }
+    }
+
+    fn to_reqwest(self) -> reqwest::redirect::Policy {
+        match self {
+            // Not followed; the response is reported by `check_redirect`.
+            RedirectPolicy::None => reqwest::redirect::Policy::none(),
+            RedirectPolicy::SameOrigin => reqwest::redirect::Policy::custom(|attempt| {
+                let first = &attempt.previous()[0];
+                if attempt.previous().len() > 5 {
+                    attempt.error("too many redirects")
+                } else if attempt.url().origin() != first.origin() {
+                    let error = format!("refusing to follow redirect from {} to {}", first.origin().ascii_serialization(), attempt.url().origin().ascii_serialization());
+                    attempt.error(error)
+                } else {
+                    attempt.follow()
+                }
+            }),
+        }
+    }
+}
+
+/// reqwest's own default.
+pub const DEFAULT_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+#[derive(Clone, Debug)]
+pub struct HttpOptions {
+    pub user_agent: String,
+    pub headers: Vec<(String, String)>,
+    pub redirects: RedirectPolicy,
+    /// Hash of the server's public key; when set, a certificate carrying
+    /// that key is the only one trusted, and the system CAs are not used.
+    pub pinned_key: Option<pin::Pin>,
+    /// Idle connections kept open to the server, and for how long; `None`
+    /// keeps them until the server closes them. With the poll running every
+    /// second, one connection stays warm.
+    pub pool_max_idle: usize,
+    pub pool_idle_timeout: Option<Duration>,
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        HttpOptions {
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            headers: Vec::new(),
+            redirects: RedirectPolicy::default(),
+            pinned_key: None,
+            pool_max_idle: usize::MAX,
+            pool_idle_timeout: Some(DEFAULT_POOL_IDLE_TIMEOUT),
+        }
+    }
+}
+
+/// Turns a redirect that wasn't followed into an error naming where it
+/// pointed, instead of a bare status code.
+fn check_redirect(res: &reqwest::Response) -> Result<(), String> {
+    if !res.status().is_redirection() {
+        return Ok(());
+    }
+    let location = res.headers().get(reqwest::header::LOCATION).and_then(|v| v.to_str().ok()).unwrap_or("?");
+    Err(format!("{} answered {} redirecting to {}; not followed (see --redirects)", res.url(), res.status(), location))
+}
+
+static CLIENT: OnceLock<Client> = OnceLock::new();
 
-    Client::builder()
+fn build_client(options: &HttpOptions) -> Result<Client, Box<dyn Error + Send + Sync>> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in &options.headers {
+        let name = HeaderName::from_bytes(name.trim().as_bytes()).map_err(|_| format!("Invalid header name: {}", name))?;
+        let value = HeaderValue::from_str(value.trim()).map_err(|_| format!("Invalid value for header {}", name))?;
+        headers.append(name, value);
+    }
+
+    let mut builder = Client::builder()
         .danger_accept_invalid_certs(false)
-        .build()
-        .unwrap()
+        .user_agent(options.user_agent.clone())
+        .default_headers(headers)
+        .redirect(options.redirects.to_reqwest())
+        .pool_max_idle_per_host(options.pool_max_idle)
+        .pool_idle_timeout(options.pool_idle_timeout);
+    if let Some(pinned_key) = options.pinned_key {
+        builder = builder.use_preconfigured_tls(pin::tls_config(pinned_key));
+    }
+
+    Ok(builder.build()?)
+}
+
+/// Builds the HTTP client shared by every Amnezichat request. Must be called
+/// before the first request to take effect.
+pub fn init_client(options: &HttpOptions) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let client = build_client(options)?;
+    CLIENT.set(client).map_err(|_| "HTTP client already initialized")?;
+    Ok(())
+}
+
+pub fn create_client() -> Client {
+    CLIENT
+        .get_or_init(|| build_client(&HttpOptions::default()).expect("default HTTP client"))
+        .clone()
+}
+
+/// Consecutive failures after which the next server in the list is tried.
+const FAILOVER_AFTER: u32 = 3;
+/// How long to stay on a mirror before trying the primary again.
+const PRIMARY_RETRY: Duration = Duration::from_secs(300);
+
+/// The primary Amnezichat server followed by mirrors serving the same rooms.
+/// Requests go to the active server; persistent failures move on to the next
+/// one, and the primary is retried every `PRIMARY_RETRY`.
+pub struct ServerList {
+    urls: Vec<String>,
+    state: Mutex<Failover>,
+}
+
+#[derive(Default)]
+struct Failover {
+    active: usize,
+    failures: u32,
+    failed_over_at: Option<Instant>,
+}
+
+impl ServerList {
+    pub fn new(primary: String, mirrors: Vec<String>) -> Self {
+        let urls = std::iter::once(primary).chain(mirrors).map(|u| u.trim_end_matches('/').to_string()).collect();
+        ServerList { urls, state: Mutex::new(Failover::default()) }
+    }
+
+    #[cfg(test)]
+    pub fn single(url: &str) -> Self {
+        Self::new(url.to_string(), Vec::new())
+    }
+
+    fn current_at(&self, now: Instant) -> String {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.active != 0 && state.failed_over_at.is_some_and(|t| now.duration_since(t) >= PRIMARY_RETRY) {
+            logging::info("amnezichat-failover", format!("Retrying primary Amnezichat server {}", self.urls[0]));
+            *state = Failover::default();
+        }
+        self.urls[state.active].clone()
+    }
+
+    fn report_at(&self, url: &str, ok: bool, now: Instant) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        // A result for a server we already moved away from says nothing
+        // about the active one.
+        if self.urls[state.active] != url {
+            return;
+        }
+        if ok {
+            state.failures = 0;
+            return;
+        }
+        state.failures += 1;
+        if state.failures >= FAILOVER_AFTER && self.urls.len() > 1 {
+            state.active = (state.active + 1) % self.urls.len();
+            state.failures = 0;
+            state.failed_over_at = Some(now);
+            logging::warn("amnezichat-failover", format!("Amnezichat server {} keeps failing; switching to {}", url, self.urls[state.active]));
+        }
+    }
+
+    /// Picks the server for the next request. The attempt counts as failed
+    /// unless `succeeded` is called, so a request dropped by a timeout
+    /// counts too.
+    fn attempt(&self) -> Attempt<'_> {
+        Attempt { servers: self, url: self.current_at(Instant::now()), ok: false }
+    }
+}
+
+struct Attempt<'a> {
+    servers: &'a ServerList,
+    url: String,
+    ok: bool,
+}
+
+impl Attempt<'_> {
+    fn succeeded(&mut self) {
+        self.ok = true;
+    }
 }
 
+impl Drop for Attempt<'_> {
+    fn drop(&mut self) {
+        self.servers.report_at(&self.url, self.ok, Instant::now());
+    }
+}
+
+/// The server refused a message as too large (413). That says nothing
+/// about the server's health, so it doesn't count towards failing over.
+#[derive(Debug)]
+pub struct TooLarge;
+
+impl std::fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Message too large for the server")
+    }
+}
+
+impl Error for TooLarge {}
+
 pub async fn send_encrypted_message(
+    encrypted_message: &str,
+    room_id: &str,
+    servers: &ServerList,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut attempt = servers.attempt();
+    let result = send_to(encrypted_message, room_id, &attempt.url).await;
+    if result.as_ref().map_or_else(|e| e.is::<TooLarge>(), |_| true) {
+        attempt.succeeded();
+    }
+    result
+}
+
+async fn send_to(
     encrypted_message: &str,
     room_id: &str,
     server_url: &str,
@@ -28,10 +249,7 @@ pub async fn send_encrypted_message(
 
     let client = create_client(); 
 
-    let formatted_encrypted_message = format!(
-        "-----BEGIN ENCRYPTED MESSAGE-----{}-----END ENCRYPTED MESSAGE-----",
-        encrypted_message
-    );
+    let formatted_encrypted_message = envelope::wrap(encrypted_message);
 
     let message_data = MessageData {
         message: formatted_encrypted_message,
@@ -47,22 +265,159 @@ pub async fn send_encrypted_message(
         .send()
         .await?; 
 
-    if res.status().is_success() {
-
-    } else {
-        eprintln!("Failed to send message: {}", res.status());
+    check_redirect(&res)?;
+    if res.status() == reqwest::StatusCode::PAYLOAD_TOO_LARGE {
+        return Err(TooLarge.into());
+    }
+    if !res.status().is_success() {
+        return Err(format!("Failed to send message: {}", res.status()).into());
     }
 
     Ok(())
 }
 
+/// Consecutive successful polls whose non-empty body held no message
+/// envelope at all.
+static BARREN_POLLS: AtomicU32 = AtomicU32::new(0);
+const BARREN_POLLS_WARN_AFTER: u32 = 10;
+const BARREN_POLLS_REPEAT_EVERY: u32 = 600;
+
+/// An empty room legitimately returns no envelopes, but a body with content
+/// and no envelopes, poll after poll, points at a protocol change or a proxy
+/// answering in the server's place.
+fn note_envelope_count(envelopes: usize, body: &str, content_type: &str) {
+    let trimmed = body.trim();
+    if envelopes > 0 || trimmed.is_empty() || trimmed == "[]" || trimmed == "{}" {
+        BARREN_POLLS.store(0, Ordering::Relaxed);
+        return;
+    }
+    let polls = BARREN_POLLS.fetch_add(1, Ordering::Relaxed) + 1;
+    if polls == BARREN_POLLS_WARN_AFTER || polls.is_multiple_of(BARREN_POLLS_REPEAT_EVERY) {
+        logging::warn(
+            "amnezichat-envelopes",
+            format!(
+                "Warning: {} consecutive /messages responses contained no encrypted messages ({} bytes, content type '{}')",
+                polls,
+                body.len(),
+                content_type
+            ),
+        );
+    }
+}
+
+/// Clients send dummy messages to hide when people are really talking.
+/// Only a message that starts with the marker is one; a user quoting it is
+/// a real message.
+pub fn is_dummy(message: &str) -> bool {
+    message.trim_start().starts_with("[DUMMY_DATA]:")
+}
+
+/// A decrypted room message, with reactions told apart from what people
+/// say.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RoomEvent {
+    Message(String),
+    Reaction(Reaction),
+}
+
+impl RoomEvent {
+    fn classify(message: String) -> Self {
+        match Reaction::parse(&message) {
+            Some(reaction) => RoomEvent::Reaction(reaction),
+            None => RoomEvent::Message(message),
+        }
+    }
+
+    /// The decrypted room message the event was read from.
+    pub fn text(&self) -> Cow<'_, str> {
+        match self {
+            RoomEvent::Message(message) => Cow::Borrowed(message),
+            RoomEvent::Reaction(reaction) => Cow::Owned(reaction.to_string()),
+        }
+    }
+}
+
+/// The room's messages, oldest first: in the order the server returns
+/// them, which is the order they were stored in. The protocol carries no
+/// timestamps or sequence numbers, so there is nothing else to sort by.
 pub async fn receive_and_fetch_messages(
+    room_id: &str,
+    shared_secret: &str,
+    servers: &ServerList,
+    gui: bool,
+) -> Result<Vec<RoomEvent>, Box<dyn Error + Send + Sync + 'static>> {
+    let mut attempt = servers.attempt();
+    let fetched = fetch_from(room_id, shared_secret, &attempt.url, gui).await?;
+    attempt.succeeded();
+    Ok(fetched.messages.into_iter().map(RoomEvent::classify).collect())
+}
+
+/// What to do when none of a room's messages decrypt at startup, which
+/// almost always means the room password is wrong.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WrongPassword {
+    #[default]
+    Fail,
+    Warn,
+}
+
+impl WrongPassword {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "fail" => Some(WrongPassword::Fail),
+            "warn" => Some(WrongPassword::Warn),
+            _ => None,
+        }
+    }
+}
+
+/// Reads the room once and checks that `shared_secret` opens at least one
+/// of its messages. An empty room passes, and so does a server that can't
+/// be reached; the poll loop reports that.
+pub async fn check_room_password(
+    room_id: &str,
+    shared_secret: &str,
+    servers: &ServerList,
+    policy: WrongPassword,
+) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    let context = logging::Context { room_id: Some(room_id), ..logging::Context::default() };
+    let mut attempt = servers.attempt();
+    let fetched = match fetch_from(room_id, shared_secret, &attempt.url, false).await {
+        Ok(fetched) => fetched,
+        Err(e) => {
+            logging::log(logging::Level::Warn, "room-password", context, format!("Could not check the room password: {}", e));
+            return Ok(());
+        }
+    };
+    attempt.succeeded();
+    if fetched.envelopes == 0 || fetched.undecryptable < fetched.envelopes {
+        return Ok(());
+    }
+    let message = format!("Room password appears incorrect: none of the {} messages in room {} decrypt", fetched.envelopes, room_id);
+    match policy {
+        WrongPassword::Fail => Err(message.into()),
+        WrongPassword::Warn => {
+            logging::log(logging::Level::Warn, "room-password", context, &message);
+            Ok(())
+        }
+    }
+}
+
+/// One `/messages` response: the decrypted messages worth relaying, and
+/// how many encrypted ones there were and failed to decrypt.
+struct Fetched {
+    messages: Vec<String>,
+    envelopes: usize,
+    undecryptable: usize,
+}
+
+async fn fetch_from(
     room_id: &str,
     shared_secret: &str,
     server_url: &str,
     gui: bool,
-) -> Result<Vec<String>, Box<dyn Error + Send + Sync + 'static>> {
-    let client = Client::new();
+) -> Result<Fetched, Box<dyn Error + Send + Sync + 'static>> {
+    let client = create_client();
     let url = format!("{}/messages?room_id={}", server_url, room_id);
 
     let res = client
@@ -73,61 +428,211 @@ pub async fn receive_and_fetch_messages(
 
     let mut messages = Vec::new();
 
+    check_redirect(&res)?;
     if res.status().is_success() {
-        let body = res.text().await?;
-
-        let re = Regex::new(
-            r"-----BEGIN ENCRYPTED MESSAGE-----\s*(.*?)\s*-----END ENCRYPTED MESSAGE-----",
-        )
-        .map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync + 'static>)?;
-
-        for cap in re.captures_iter(&body) {
-            if let Some(encrypted_message) = cap.get(1) {
-                let cleaned_message = encrypted_message.as_str().trim();
-
-                if let Ok(decrypted_message) =
-                    decrypt_data(cleaned_message, shared_secret)
-                {
-                    fn unpad_message(message: &str) -> String {
-                        if let (Some(start), Some(end)) =
-                            (message.find("<padding>"), message.find("</padding>"))
-                        {
-                            let (before, _) = message.split_at(start);
-                            let (_, after) = message.split_at(end + "</padding>".len());
-                            return format!("{}{}", before, after);
-                        }
-                        message.to_string()
-                    }
+        let content_type = res
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        if content_type.starts_with("text/html") {
+            return Err(format!(
+                "Unexpected content type '{}' from {}/messages; is this an Amnezichat server?",
+                content_type, server_url
+            )
+            .into());
+        }
 
-                    let unpadded = unpad_message(&decrypted_message);
+        let body = res.text().await?;
+        let mut envelopes = 0;
+        let mut undecryptable = 0;
 
-                    let mut cleaned = unpadded
-                        .replace("<strong>", "")
-                        .replace("</strong>", "");
+        for cleaned_message in envelope::extract(&body) {
+            envelopes += 1;
 
-                    let re_pfp: Regex = Regex::new(r#"<pfp>.*?</pfp>"#)
-                        .map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync + 'static>)?;
-                    let re_media: Regex = Regex::new(r#"<media>.*?</media>"#)
-                        .map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync + 'static>)?;
+            if let Ok(decrypted_message) =
+                decrypt_data(cleaned_message, shared_secret)
+            {
+                fn unpad_message(message: &str) -> String {
+                    if let (Some(start), Some(end)) =
+                        (message.find("<padding>"), message.find("</padding>"))
+                    {
+                        let (before, _) = message.split_at(start);
+                        let (_, after) = message.split_at(end + "</padding>".len());
+                        return format!("{}{}", before, after);
+                    }
+                    message.to_string()
+                }
 
-                    cleaned = re_pfp.replace_all(&cleaned, "").to_string();
-                    cleaned = re_media.replace_all(&cleaned, "").to_string();
+                let unpadded = unpad_message(&decrypted_message);
 
-                    if cleaned.contains("[DUMMY_DATA]:") {
-                        continue;
-                    }
+                // Formatting tags stay; they are translated for IRC
+                // only once the message is sanitized.
+                let cleaned = remove_hidden(&unpadded);
 
-                    messages.push(if gui { cleaned.clone() } else { cleaned });
+                if is_dummy(&cleaned) {
+                    continue;
                 }
+
+                messages.push(if gui { cleaned.clone() } else { cleaned });
+            } else {
+                undecryptable += 1;
             }
         }
+
+        note_envelope_count(envelopes, &body, &content_type);
+        Ok(Fetched { messages, envelopes, undecryptable })
     } else {
-        eprintln!(
+        Err(format!(
             "Failed to fetch messages: {} - {}",
             res.status(),
             res.text().await?
-        );
+        )
+        .into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Answers every request with a redirect to `location`.
+    async fn redirecting_server(location: &'static str) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buf = [0u8; 4096];
+                let _ = socket.read(&mut buf).await;
+                let response = format!("HTTP/1.1 307 Temporary Redirect\r\nLocation: {}\r\nContent-Length: 0\r\n\r\n", location);
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+        url
+    }
+
+    /// Answers every request with an empty 200, keeping connections open,
+    /// and counts the connections made.
+    async fn counting_server() -> (String, Arc<AtomicU32>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicU32::new(0));
+        let counter = Arc::clone(&connections);
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 4096];
+                    while matches!(socket.read(&mut buf).await, Ok(n) if n > 0) {
+                        let _ = socket.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+                    }
+                });
+            }
+        });
+        (url, connections)
     }
 
-    Ok(messages)
-}
\ No newline at end of file
+    /// Serves HTTPS on localhost with a fresh self-signed certificate,
+    /// answering every request with an empty 200. Returns the URL and the
+    /// pin of the certificate's key.
+    async fn tls_server() -> (String, pin::Pin) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let der = cert.serialize_der().unwrap();
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![rustls::Certificate(der.clone())], rustls::PrivateKey(cert.serialize_private_key_der()))
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("https://localhost:{}", listener.local_addr().unwrap().port());
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(mut tls) = acceptor.accept(socket).await else { return };
+                    let mut buf = [0u8; 4096];
+                    if matches!(tls.read(&mut buf).await, Ok(n) if n > 0) {
+                        let _ = tls.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await;
+                        let _ = tls.shutdown().await;
+                    }
+                });
+            }
+        });
+        (url, pin::spki_hash(&der).unwrap())
+    }
+
+    #[tokio::test]
+    async fn only_a_server_with_the_pinned_key_is_trusted() {
+        let (url, key) = tls_server().await;
+        let pinned = |pinned_key| build_client(&HttpOptions { pinned_key: Some(pinned_key), ..HttpOptions::default() }).unwrap();
+        assert!(pinned(key).get(&url).send().await.unwrap().status().is_success());
+
+        let error = pinned([0; 32]).get(&url).send().await.unwrap_err();
+        assert!(format!("{:?}", error).contains("not the one given to --pin-pubkey"), "{:?}", error);
+        // Nor is the self-signed certificate trusted without a pin.
+        assert!(build_client(&HttpOptions::default()).unwrap().get(&url).send().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn idle_connections_are_pooled_as_configured() {
+        for (pool_max_idle, expected) in [(1, 1), (0, 3)] {
+            let (url, connections) = counting_server().await;
+            let client = build_client(&HttpOptions { pool_max_idle, pool_idle_timeout: None, ..HttpOptions::default() }).unwrap();
+            for _ in 0..3 {
+                client.get(format!("{}/messages", url)).send().await.unwrap().bytes().await.unwrap();
+            }
+            assert_eq!(connections.load(Ordering::SeqCst), expected, "pool_max_idle {}", pool_max_idle);
+        }
+    }
+
+    #[tokio::test]
+    async fn redirects_to_another_host_are_refused() {
+        let url = redirecting_server("http://attacker.invalid/send").await;
+        for redirects in [RedirectPolicy::None, RedirectPolicy::SameOrigin] {
+            let client = build_client(&HttpOptions { redirects, ..HttpOptions::default() }).unwrap();
+            let result = client.post(format!("{}/send", url)).body("{}").send().await;
+            let error = match result {
+                Ok(res) => check_redirect(&res).unwrap_err(),
+                Err(e) => format!("{:?}", e),
+            };
+            assert!(error.contains("attacker.invalid"), "{:?}: {}", redirects, error);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_room_where_nothing_decrypts_has_the_wrong_password() {
+        let room = crate::mock_amnezichat::MockAmnezichat::start();
+        let servers = ServerList::single(&room.url());
+        let (right, wrong) = ("0".repeat(64), "1".repeat(64));
+        // An empty room says nothing about the password.
+        assert!(check_room_password("room1", &wrong, &servers, WrongPassword::Fail).await.is_ok());
+
+        room.publish(crate::encryption::encrypt_data("alice: hi", &right).unwrap());
+        let error = check_room_password("room1", &wrong, &servers, WrongPassword::Fail).await.unwrap_err();
+        assert!(error.to_string().contains("appears incorrect"), "{}", error);
+        assert!(check_room_password("room1", &wrong, &servers, WrongPassword::Warn).await.is_ok());
+        assert!(check_room_password("room1", &right, &servers, WrongPassword::Fail).await.is_ok());
+    }
+
+    #[test]
+    fn fails_over_to_mirrors_and_back_to_the_primary() {
+        let servers = ServerList::new("https://a.example/".into(), vec!["https://b.example".into()]);
+        let start = Instant::now();
+        assert_eq!(servers.current_at(start), "https://a.example");
+
+        for _ in 0..FAILOVER_AFTER {
+            servers.report_at("https://a.example", false, start);
+        }
+        assert_eq!(servers.current_at(start), "https://b.example");
+
+        // A late failure from the old server doesn't count against the mirror.
+        servers.report_at("https://a.example", false, start);
+        servers.report_at("https://b.example", true, start);
+        assert_eq!(servers.current_at(start + Duration::from_secs(10)), "https://b.example");
+        assert_eq!(servers.current_at(start + PRIMARY_RETRY), "https://a.example");
+    }
+}
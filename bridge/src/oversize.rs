@@ -0,0 +1,103 @@
+//! Keeping messages relayed into the room under the Amnezichat server's
+//! size limit, the room-side counterpart of `MAX_MESSAGE_CHARS` on IRC.
+
+use crate::graphemes;
+
+/// Appended to a message cut short by `OversizePolicy::Truncate`.
+pub const TRUNCATED_MARKER: &str = " [truncated]";
+
+/// Smallest part a message is cut into, even when the server keeps
+/// refusing.
+pub const MIN_PART_BYTES: usize = 64;
+
+/// What happens to a message longer than the room allows.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OversizePolicy {
+    /// Post it as several room messages.
+    #[default]
+    Split,
+    Truncate,
+}
+
+impl OversizePolicy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "split" => Some(OversizePolicy::Split),
+            "truncate" => Some(OversizePolicy::Truncate),
+            _ => None,
+        }
+    }
+
+    /// `text` in parts of at most `max_bytes` bytes each, cut between
+    /// words where possible and never inside a grapheme cluster.
+    pub fn fit(self, text: &str, max_bytes: usize) -> Vec<String> {
+        let max_bytes = max_bytes.max(MIN_PART_BYTES);
+        if text.len() <= max_bytes {
+            return vec![text.to_string()];
+        }
+        if self == OversizePolicy::Truncate {
+            let head = graphemes::truncate_bytes(text, max_bytes - TRUNCATED_MARKER.len());
+            return vec![format!("{}{}", head.trim_end(), TRUNCATED_MARKER)];
+        }
+        let mut parts = Vec::new();
+        let mut rest = text;
+        while !rest.is_empty() {
+            let mut head = graphemes::truncate_bytes(rest, max_bytes);
+            if head.len() < rest.len() {
+                if let Some(space) = head.rfind(' ').filter(|&i| i > 0) {
+                    head = &head[..space];
+                }
+            }
+            if head.is_empty() {
+                // A single cluster over the limit; cut it rather than stall.
+                head = &rest[..rest.chars().next().map_or(0, char::len_utf8)];
+            }
+            parts.push(head.trim_end().to_string());
+            rest = rest[head.len()..].trim_start();
+        }
+        parts
+    }
+}
+
+/// The size limit for text relayed into the room.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RoomLimit {
+    /// Longest relayed text per room message, in bytes before encryption;
+    /// `None` sends everything whole until the server refuses.
+    pub max_bytes: Option<usize>,
+    pub policy: OversizePolicy,
+}
+
+impl RoomLimit {
+    pub fn fit(self, text: &str) -> Vec<String> {
+        match self.max_bytes {
+            Some(max_bytes) => self.policy.fit(text, max_bytes),
+            None => vec![text.to_string()],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_messages_are_split_between_words() {
+        let text = format!("{} {}", "a".repeat(50), "b".repeat(50));
+        assert_eq!(OversizePolicy::Split.fit(&text, 80), vec!["a".repeat(50), "b".repeat(50)]);
+        assert_eq!(OversizePolicy::Split.fit("short", 80), vec!["short"]);
+
+        let word = "x".repeat(150);
+        let parts = OversizePolicy::Split.fit(&word, 64);
+        assert_eq!(parts.iter().map(|p| p.len()).collect::<Vec<_>>(), [64, 64, 22]);
+        assert_eq!(parts.concat(), word);
+    }
+
+    #[test]
+    fn truncated_messages_are_marked() {
+        let text = "y".repeat(100);
+        let fitted = OversizePolicy::Truncate.fit(&text, 80);
+        assert_eq!(fitted, vec![format!("{}{}", "y".repeat(80 - TRUNCATED_MARKER.len()), TRUNCATED_MARKER)]);
+        assert_eq!(RoomLimit::default().fit(&text), vec![text]);
+    }
+}
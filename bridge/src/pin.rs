@@ -0,0 +1,76 @@
+//! Trusting the Amnezichat server by the SHA-256 of its public key
+//! (`--pin-pubkey sha256//...`, the form curl's `--pinnedpubkey` takes)
+//! instead of by a CA. The pin outlives renewals that keep the key and
+//! works for self-signed certificates.
+
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use base64::Engine;
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, ServerName};
+use sha2::{Digest, Sha256};
+
+/// SHA-256 of a DER SubjectPublicKeyInfo.
+pub type Pin = [u8; 32];
+
+const PREFIX: &str = "sha256//";
+
+/// Parses `sha256//` followed by the base64 of the hash.
+pub fn parse(value: &str) -> Option<Pin> {
+    let encoded = value.trim().strip_prefix(PREFIX)?;
+    base64::engine::general_purpose::STANDARD.decode(encoded).ok()?.try_into().ok()
+}
+
+/// The pin of the key in a DER certificate.
+pub fn spki_hash(cert: &[u8]) -> Option<Pin> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    Some(Sha256::digest(cert.public_key().raw).into())
+}
+
+/// Accepts exactly the server certificates carrying the pinned key,
+/// whoever issued them. The handshake signature is still checked, so the
+/// server must hold the matching private key.
+struct PinVerifier(Pin);
+
+impl ServerCertVerifier for PinVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match spki_hash(&end_entity.0) {
+            Some(pin) if pin == self.0 => Ok(ServerCertVerified::assertion()),
+            Some(pin) => Err(rustls::Error::General(format!(
+                "the server's public key is {}{}, not the one given to --pin-pubkey",
+                PREFIX,
+                base64::engine::general_purpose::STANDARD.encode(pin)
+            ))),
+            None => Err(rustls::Error::InvalidCertificate(rustls::CertificateError::BadEncoding)),
+        }
+    }
+}
+
+/// TLS settings for a client that trusts only the pinned key.
+pub fn tls_config(pin: Pin) -> ClientConfig {
+    ClientConfig::builder().with_safe_defaults().with_custom_certificate_verifier(Arc::new(PinVerifier(pin))).with_no_client_auth()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pins_are_base64_sha256_hashes() {
+        let pin = [7u8; 32];
+        let encoded = format!("sha256//{}", base64::engine::general_purpose::STANDARD.encode(pin));
+        assert_eq!(parse(&encoded), Some(pin));
+        assert_eq!(parse(&encoded.replace("sha256//", "")), None);
+        assert_eq!(parse("sha256//AAAA"), None);
+        assert_eq!(parse("sha256//not base64!"), None);
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How far a `server-time` stamp may lag behind our connect time before a
+/// message counts as replayed history. Leaves room for clock skew between
+/// us and the server.
+const REPLAY_TOLERANCE: Duration = Duration::from_secs(30);
+
+/// Recognizes bouncer buffer playback so it isn't relayed again as new
+/// messages: anything inside a playback/chathistory `BATCH`, anything from
+/// ZNC's `*buffextras`, and messages whose `server-time` predates the
+/// connection.
+#[derive(Default)]
+pub struct PlaybackFilter {
+    batches: HashSet<String>,
+}
+
+impl PlaybackFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tracks `BATCH +id type` / `BATCH -id` lines.
+    pub fn observe_batch(&mut self, params: &[String]) {
+        let Some(reference) = params.first() else { return };
+        if let Some(id) = reference.strip_prefix('+') {
+            let kind = params.get(1).map(|k| k.as_str()).unwrap_or("");
+            if kind == "znc.in/playback" || kind == "chathistory" {
+                self.batches.insert(id.to_string());
+            }
+        } else if let Some(id) = reference.strip_prefix('-') {
+            self.batches.remove(id);
+        }
+    }
+
+    pub fn is_replay(&self, nick: Option<&str>, batch: Option<&str>, time: Option<&str>, connected_at: SystemTime) -> bool {
+        if nick.is_some_and(|n| n.eq_ignore_ascii_case("*buffextras")) {
+            return true;
+        }
+        if batch.is_some_and(|b| self.batches.contains(b)) {
+            return true;
+        }
+        match time.and_then(parse_server_time) {
+            Some(sent) => sent + REPLAY_TOLERANCE < connected_at,
+            None => false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.batches.clear();
+    }
+}
+
+/// Parses an IRCv3 `server-time` value such as `2024-03-01T12:30:05.123Z`.
+/// The tag comes from whoever sent the line, so years outside 1970..=9999
+/// and times `SystemTime` can't hold are `None` rather than a panic.
+pub fn parse_server_time(value: &str) -> Option<SystemTime> {
+    let value = value.strip_suffix('Z')?;
+    let (date, time) = value.split_once('T')?;
+
+    let mut date_parts = date.splitn(3, '-');
+    let year: i64 = date_parts.next()?.parse().ok()?;
+    let month: u32 = date_parts.next()?.parse().ok()?;
+    let day: u32 = date_parts.next()?.parse().ok()?;
+
+    let (hms, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let mut time_parts = hms.splitn(3, ':');
+    let hour: u64 = time_parts.next()?.parse().ok()?;
+    let minute: u64 = time_parts.next()?.parse().ok()?;
+    let second: u64 = time_parts.next()?.parse().ok()?;
+    if !(1970..=9999).contains(&year) || !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let millis: u64 = if fraction.is_empty() {
+        0
+    } else {
+        let digits: String = fraction.chars().take(3).collect();
+        format!("{:0<3}", digits).parse().ok()?
+    };
+
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    let secs = days.checked_mul(86_400)?.checked_add(hour * 3_600 + minute * 60 + second)?;
+    UNIX_EPOCH.checked_add(Duration::from_secs(secs) + Duration::from_millis(millis))
+}
+
+/// Days since 1970-01-01 for a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_server_time() {
+        let t = parse_server_time("2024-03-01T12:30:05.123Z").unwrap();
+        assert_eq!(t.duration_since(UNIX_EPOCH).unwrap(), Duration::from_millis(1_709_296_205_123));
+        assert_eq!(parse_server_time("1970-01-01T00:00:00Z"), Some(UNIX_EPOCH));
+        assert_eq!(parse_server_time("yesterday"), None);
+    }
+
+    #[test]
+    fn absurd_server_times_are_rejected() {
+        for value in [
+            "999999999999-01-01T00:00:00Z",
+            "9223372036854775807-12-31T23:59:59Z",
+            "10000-01-01T00:00:00Z",
+            "1969-12-31T23:59:59Z",
+            "-5-01-01T00:00:00Z",
+            "-9223372036854775808-01-01T00:00:00Z",
+            "2024-01-01T99999999999999999999:00:00Z",
+        ] {
+            assert_eq!(parse_server_time(value), None, "{}", value);
+        }
+        assert!(parse_server_time("9999-12-31T23:59:60.999Z").is_some());
+    }
+
+    #[test]
+    fn detects_replayed_messages() {
+        let connected_at = parse_server_time("2024-03-01T12:00:00Z").unwrap();
+        let mut filter = PlaybackFilter::new();
+        assert!(filter.is_replay(Some("*buffextras"), None, None, connected_at));
+        assert!(filter.is_replay(Some("alice"), None, Some("2024-03-01T11:00:00.000Z"), connected_at));
+        assert!(!filter.is_replay(Some("alice"), None, Some("2024-03-01T11:59:50.000Z"), connected_at));
+        assert!(!filter.is_replay(Some("alice"), None, None, connected_at));
+
+        filter.observe_batch(&["+abc".into(), "znc.in/playback".into(), "#test".into()]);
+        assert!(filter.is_replay(Some("alice"), Some("abc"), None, connected_at));
+        filter.observe_batch(&["-abc".into()]);
+        assert!(!filter.is_replay(Some("alice"), Some("abc"), None, connected_at));
+    }
+}
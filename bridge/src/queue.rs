@@ -0,0 +1,152 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use tokio::sync::Notify;
+
+use crate::logging::log_error;
+
+/// What happens to a message pushed onto a full queue.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait for room, which pauses whoever is pushing (the Amnezichat poll).
+    #[default]
+    Block,
+    DropOldest,
+    DropNewest,
+}
+
+impl OverflowPolicy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "block" => Some(OverflowPolicy::Block),
+            "drop-oldest" => Some(OverflowPolicy::DropOldest),
+            "drop-newest" => Some(OverflowPolicy::DropNewest),
+            _ => None,
+        }
+    }
+}
+
+/// Bounded queue of messages waiting to be sent to IRC, with an explicit
+/// overflow policy and a count of what it had to drop.
+pub struct OutboundQueue<T> {
+    items: Mutex<VecDeque<T>>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    dropped: AtomicU64,
+    ready: Notify,
+    space: Notify,
+}
+
+impl<T> OutboundQueue<T> {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        OutboundQueue {
+            items: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity: capacity.max(1),
+            policy,
+            dropped: AtomicU64::new(0),
+            ready: Notify::new(),
+            space: Notify::new(),
+        }
+    }
+
+    fn items(&self) -> std::sync::MutexGuard<'_, VecDeque<T>> {
+        self.items.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Queues `item`, and hands back whatever the overflow policy dropped
+    /// instead, for the caller to clean up after.
+    #[must_use = "a dropped item may hold resources to release"]
+    pub async fn push(&self, item: T) -> Option<T> {
+        loop {
+            {
+                let mut items = self.items();
+                if items.len() < self.capacity {
+                    items.push_back(item);
+                    self.ready.notify_one();
+                    return None;
+                }
+                match self.policy {
+                    OverflowPolicy::DropOldest => {
+                        let oldest = items.pop_front();
+                        items.push_back(item);
+                        self.overflowed("IRC send queue full; dropped the oldest message");
+                        self.ready.notify_one();
+                        return oldest;
+                    }
+                    OverflowPolicy::DropNewest => {
+                        self.overflowed("IRC send queue full; dropped a new message");
+                        return Some(item);
+                    }
+                    OverflowPolicy::Block => {
+                        log_error("irc-queue", "IRC send queue full; pausing Amnezichat polling");
+                    }
+                }
+            }
+            self.space.notified().await;
+        }
+    }
+
+    pub async fn pop(&self) -> T {
+        loop {
+            if let Some(item) = self.items().pop_front() {
+                self.space.notify_one();
+                return item;
+            }
+            self.ready.notified().await;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn overflowed(&self, message: &'static str) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        log_error("irc-queue", message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn drop_policies_keep_the_queue_bounded() {
+        let oldest = OutboundQueue::new(2, OverflowPolicy::DropOldest);
+        assert_eq!((oldest.push(1).await, oldest.push(2).await, oldest.push(3).await), (None, None, Some(1)));
+        assert_eq!((oldest.pop().await, oldest.pop().await, oldest.dropped()), (2, 3, 1));
+
+        let newest = OutboundQueue::new(2, OverflowPolicy::DropNewest);
+        assert_eq!((newest.push(1).await, newest.push(2).await, newest.push(3).await), (None, None, Some(3)));
+        assert_eq!((newest.pop().await, newest.pop().await, newest.dropped()), (1, 2, 1));
+        assert!(newest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_room() {
+        let queue = Arc::new(OutboundQueue::new(1, OverflowPolicy::Block));
+        assert_eq!(queue.push(1).await, None);
+        let pusher = {
+            let queue = Arc::clone(&queue);
+            tokio::spawn(async move { queue.push(2).await.is_none() })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!pusher.is_finished());
+
+        assert_eq!(queue.pop().await, 1);
+        assert!(tokio::time::timeout(Duration::from_secs(1), pusher).await.unwrap().unwrap());
+        assert_eq!(queue.pop().await, 2);
+        assert_eq!(queue.dropped(), 0);
+    }
+}
@@ -0,0 +1,119 @@
+//! Reactions in the room, behind `--relay-reactions`. Amnezichat has no
+//! reactions of its own; this is a format proposed by the bridge, which no
+//! Amnezichat client posts yet. A reaction is an ordinary room message,
+//! `alice: <reaction to="1a2b3c4d">👍</reaction>`, naming the message
+//! reacted to by the start of the SHA3-256 digest of its decrypted text,
+//! and is shown on IRC as a line of text.
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use sha3::{Digest, Sha3_256};
+
+/// Hex digits of the digest that name a message.
+const ID_LEN: usize = 8;
+/// Recent messages kept so reactions to them can say what they were.
+const TARGETS_LEN: usize = 200;
+/// Characters of the reacted-to message quoted on IRC.
+const SNIPPET_LEN: usize = 40;
+/// Longer than any emoji sequence; anything bigger is not a reaction.
+const MAX_EMOJI_BYTES: usize = 32;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reaction {
+    pub user: String,
+    pub emoji: String,
+    /// `message_id` of the message reacted to.
+    pub target: String,
+}
+
+impl Reaction {
+    /// Reads a decrypted room message as a reaction, if it is one.
+    pub fn parse(message: &str) -> Option<Reaction> {
+        let (user, rest) = message.split_once(": ")?;
+        let rest = rest.trim().strip_prefix("<reaction to=\"")?;
+        let (target, rest) = rest.split_once("\">")?;
+        let emoji = rest.strip_suffix("</reaction>")?;
+        let valid_target = target.len() == ID_LEN && target.bytes().all(|b| b.is_ascii_hexdigit());
+        let valid_emoji = !emoji.is_empty() && emoji.len() <= MAX_EMOJI_BYTES && !emoji.contains(|c: char| c.is_whitespace() || c == '<');
+        (!user.is_empty() && valid_target && valid_emoji).then(|| Reaction {
+            user: user.to_string(),
+            emoji: emoji.to_string(),
+            target: target.to_ascii_lowercase(),
+        })
+    }
+}
+
+/// The room message form, which `parse` reads back.
+impl fmt::Display for Reaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: <reaction to=\"{}\">{}</reaction>", self.user, self.target, self.emoji)
+    }
+}
+
+/// How a reaction refers to the decrypted room message `message`.
+pub fn message_id(message: &str) -> String {
+    let digest = Sha3_256::digest(message.as_bytes());
+    hex::encode(&digest[..ID_LEN / 2])
+}
+
+/// The last messages seen in a room, by id, so a reaction can name the
+/// message and who wrote it.
+#[derive(Default)]
+pub struct ReactionTargets {
+    entries: VecDeque<(String, String, String)>,
+}
+
+impl ReactionTargets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remembers `message` (the decrypted text reactions are made to) as
+    /// said by `nick`.
+    pub fn record(&mut self, message: &str, nick: &str, text: &str) {
+        if self.entries.len() == TARGETS_LEN {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((message_id(message), nick.to_string(), text.to_string()));
+    }
+
+    /// The line shown on IRC, e.g. `alice reacted 👍 to bob's message "lunch?"`.
+    pub fn describe(&self, user: &str, emoji: &str, target: &str) -> String {
+        match self.entries.iter().rev().find(|(id, _, _)| id == target) {
+            Some((_, nick, text)) => {
+                let mut snippet: String = text.chars().take(SNIPPET_LEN).collect();
+                if snippet.len() < text.len() {
+                    snippet.push('\u{2026}');
+                }
+                format!("{} reacted {} to {}'s message \"{}\"", user, emoji, nick, snippet)
+            }
+            None => format!("{} reacted {} to an earlier message", user, emoji),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reactions_are_told_apart_from_messages() {
+        let reaction = Reaction { user: "alice".into(), emoji: "👍".into(), target: message_id("bob: lunch?") };
+        assert_eq!(Reaction::parse(&reaction.to_string()), Some(reaction));
+
+        assert_eq!(Reaction::parse("alice: 👍"), None);
+        assert_eq!(Reaction::parse("alice: <reaction to=\"xyz\">👍</reaction>"), None);
+        assert_eq!(Reaction::parse("alice: <reaction to=\"1a2b3c4d\">not an emoji</reaction>"), None);
+        assert_eq!(Reaction::parse("alice: see <reaction to=\"1a2b3c4d\">👍</reaction>"), None);
+    }
+
+    #[test]
+    fn reactions_name_the_message_they_refer_to() {
+        let mut targets = ReactionTargets::new();
+        targets.record("bob: lunch at noon?", "bob", "lunch at noon?");
+        let id = message_id("bob: lunch at noon?");
+        assert_eq!(targets.describe("alice", "👍", &id), "alice reacted 👍 to bob's message \"lunch at noon?\"");
+        assert_eq!(targets.describe("alice", "🎉", &message_id("gone")), "alice reacted 🎉 to an earlier message");
+    }
+}
@@ -0,0 +1,88 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Messages kept for reply matching.
+const HISTORY_LEN: usize = 50;
+/// Older messages are not treated as the one being answered.
+const HISTORY_MAX_AGE: Duration = Duration::from_secs(600);
+/// Characters of the original message quoted in a reply.
+const SNIPPET_LEN: usize = 60;
+
+/// Recent bridged messages from both sides, so an IRC line starting with
+/// `nick:` or `@nick` can be shown as a reply to that nick's last message.
+/// Amnezichat has no reply metadata, so the reference is a short quote.
+#[derive(Default)]
+pub struct ReplyHistory {
+    entries: VecDeque<(String, String, Instant)>,
+}
+
+impl ReplyHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, nick: &str, text: &str, now: Instant) {
+        if self.entries.len() == HISTORY_LEN {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((nick.to_string(), text.to_string(), now));
+    }
+
+    fn last_from(&self, nick: &str, now: Instant) -> Option<&str> {
+        self.entries
+            .iter()
+            .rev()
+            .take_while(|(_, _, at)| now.duration_since(*at) < HISTORY_MAX_AGE)
+            .find(|(n, _, _)| n.eq_ignore_ascii_case(nick))
+            .map(|(_, text, _)| text.as_str())
+    }
+
+    /// Prefixes `msg` with a quote of the message it answers, if any.
+    pub fn annotate(&self, msg: &str, now: Instant) -> Option<String> {
+        let nick = addressed_nick(msg)?;
+        let original = self.last_from(nick, now)?;
+        let mut snippet: String = original.chars().take(SNIPPET_LEN).collect();
+        if snippet.len() < original.len() {
+            snippet.push('\u{2026}');
+        }
+        Some(format!("[re {}: \"{}\"] {}", nick, snippet, msg))
+    }
+}
+
+/// The nick a message is addressed to: `@nick ...`, `nick: ...` or
+/// `nick, ...`.
+fn addressed_nick(msg: &str) -> Option<&str> {
+    let is_nick_char = |c: char| c.is_alphanumeric() || "_-[]\\`^{}|".contains(c);
+    if let Some(rest) = msg.strip_prefix('@') {
+        let end = rest.find(|c: char| !is_nick_char(c)).unwrap_or(rest.len());
+        return (end > 0).then(|| &rest[..end]);
+    }
+    let end = msg.find([':', ','])?;
+    let nick = &msg[..end];
+    (!nick.is_empty() && nick.chars().all(is_nick_char) && msg[end + 1..].starts_with(' ')).then_some(nick)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_addressed_nick() {
+        assert_eq!(addressed_nick("alice: sure"), Some("alice"));
+        assert_eq!(addressed_nick("@bob thanks"), Some("bob"));
+        assert_eq!(addressed_nick("carol, hi"), Some("carol"));
+        assert_eq!(addressed_nick("see https://example.com"), None);
+        assert_eq!(addressed_nick("note:nospace"), None);
+    }
+
+    #[test]
+    fn quotes_the_last_recent_message() {
+        let mut history = ReplyHistory::new();
+        let start = Instant::now();
+        history.record("alice", "old", start);
+        history.record("Alice", "lunch at noon?", start);
+        assert_eq!(history.annotate("alice: sure", start).as_deref(), Some("[re alice: \"lunch at noon?\"] alice: sure"));
+        assert_eq!(history.annotate("bob: hi", start), None);
+        assert_eq!(history.annotate("alice: late", start + HISTORY_MAX_AGE), None);
+    }
+}
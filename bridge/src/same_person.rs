@@ -0,0 +1,78 @@
+//! `--same-person nick=name`: IRC nicks of people who are also in the room
+//! under their own Amnezichat name. Relaying their IRC messages as well
+//! would show them twice, once natively and once as `[IRC]nick`.
+
+use crate::bridge::same_nick;
+
+/// What happens to IRC messages from a nick mapped with `--same-person`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SamePersonMode {
+    /// Not relayed into the room; the person reads and writes there
+    /// directly.
+    #[default]
+    Suppress,
+    /// Relayed with their Amnezichat name after the IRC nick. The nick
+    /// comes first since anyone can take it: the label must not pass for
+    /// the room member.
+    Annotate,
+}
+
+impl SamePersonMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "suppress" => Some(SamePersonMode::Suppress),
+            "annotate" => Some(SamePersonMode::Annotate),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SamePerson {
+    /// IRC nick and Amnezichat name.
+    pairs: Vec<(String, String)>,
+    pub mode: SamePersonMode,
+}
+
+impl SamePerson {
+    /// Adds a `nick=name` pair; false when it isn't one.
+    pub fn add(&mut self, value: &str) -> bool {
+        let Some((nick, name)) = value.split_once('=').map(|(n, a)| (n.trim(), a.trim())) else { return false };
+        if nick.is_empty() || name.is_empty() || nick.contains(char::is_whitespace) {
+            return false;
+        }
+        self.pairs.retain(|(n, _)| !same_nick(n, nick));
+        self.pairs.push((nick.to_string(), name.to_string()));
+        true
+    }
+
+    /// The room label for a message from `nick` otherwise shown as
+    /// `label`, or `None` when it isn't relayed at all.
+    pub fn label(&self, nick: &str, label: String) -> Option<String> {
+        let Some((_, name)) = self.pairs.iter().find(|(n, _)| same_nick(n, nick)) else { return Some(label) };
+        match self.mode {
+            SamePersonMode::Suppress => None,
+            SamePersonMode::Annotate => Some(format!("{} (as {})", label, name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mapped_nicks_are_suppressed_or_annotated() {
+        let mut same = SamePerson::default();
+        assert!(same.add("alice_ = Alice"));
+        assert!(!same.add("bob"));
+        assert!(!same.add("=Bob"));
+        assert_eq!(same.label("ALICE_", "alice_".into()), None);
+        assert_eq!(same.label("carol", "carol".into()).as_deref(), Some("carol"));
+
+        same.mode = SamePersonMode::Annotate;
+        assert_eq!(same.label("alice_", "alice_ (unverified)".into()).as_deref(), Some("alice_ (unverified) (as Alice)"));
+        assert_eq!(SamePersonMode::parse("Annotate"), Some(SamePersonMode::Annotate));
+        assert_eq!(SamePersonMode::parse("hide"), None);
+    }
+}
@@ -0,0 +1,200 @@
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    IrcToAmnezichat,
+    AmnezichatToIrc,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnicodeFilter {
+    Off,
+    #[default]
+    Strip,
+    Flag,
+}
+
+impl UnicodeFilter {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "off" => Some(UnicodeFilter::Off),
+            "strip" => Some(UnicodeFilter::Strip),
+            "flag" => Some(UnicodeFilter::Flag),
+            _ => None,
+        }
+    }
+}
+
+/// mIRC formatting codes (bold, color, hex color, reset, monospace, reverse,
+/// italic, strikethrough, underline).
+const IRC_FORMATTING: [char; 9] = ['\x02', '\x03', '\x04', '\x0f', '\x11', '\x16', '\x1d', '\x1e', '\x1f'];
+
+/// Bidi overrides/isolates, zero-width and otherwise invisible characters that
+/// can be used to spoof nicks or hide content.
+fn is_deceptive(c: char) -> bool {
+    matches!(
+        c,
+        '\u{00ad}'
+            | '\u{034f}'
+            | '\u{061c}'
+            | '\u{115f}'
+            | '\u{1160}'
+            | '\u{17b4}'
+            | '\u{17b5}'
+            | '\u{180e}'
+            | '\u{200b}'..='\u{200f}'
+            | '\u{202a}'..='\u{202e}'
+            | '\u{2060}'..='\u{2064}'
+            | '\u{2066}'..='\u{2069}'
+            | '\u{3164}'
+            | '\u{feff}'
+            | '\u{ffa0}'
+    )
+}
+
+/// Removes mIRC formatting codes together with the color numbers after
+/// `\x03` (`04`, `04,12`) and `\x04` (`ff0000`, `ff0000,000000`), for rooms
+/// that want plain text only.
+pub fn strip_formatting(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if !IRC_FORMATTING.contains(&c) {
+            out.push(c);
+            continue;
+        }
+        let (is_digit, len): (fn(&char) -> bool, usize) = match c {
+            '\x03' => (|c| c.is_ascii_digit(), 2),
+            '\x04' => (|c| c.is_ascii_hexdigit(), 6),
+            _ => continue,
+        };
+        let skip_color = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+            let mut taken = 0;
+            while taken < len && chars.next_if(is_digit).is_some() {
+                taken += 1;
+            }
+            taken > 0
+        };
+        // A comma only belongs to the code when a background follows it.
+        if skip_color(&mut chars) && chars.peek() == Some(&',') {
+            let mut ahead = chars.clone();
+            ahead.next();
+            if ahead.peek().is_some_and(is_digit) {
+                chars.next();
+                skip_color(&mut chars);
+            }
+        }
+    }
+    out
+}
+
+fn is_emoji_like(c: char) -> bool {
+    matches!(c, '\u{2600}'..='\u{27bf}' | '\u{fe0f}' | '\u{1f000}'..='\u{1faff}')
+}
+
+/// Normalizes text before it is forwarded: runs of whitespace (including
+/// newlines and tabs) collapse to a single space, the result is trimmed and
+/// control characters are dropped. IRC formatting codes survive on their
+/// way into Amnezichat; text headed to IRC never carries any control bytes
+/// since the bridge adds its own formatting.
+///
+/// With a `unicode` filter other than `Off`, deceptive invisible characters
+/// are removed or replaced by a visible `<U+XXXX>` marker. A zero-width
+/// joiner between two emoji is kept so ZWJ sequences stay intact.
+pub fn sanitize(direction: Direction, text: &str, unicode: UnicodeFilter) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut pending_space = false;
+    let mut prev: Option<char> = None;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let before = prev;
+        prev = Some(c);
+
+        if unicode != UnicodeFilter::Off && is_deceptive(c) {
+            let joins_emoji = c == '\u{200d}'
+                && before.is_some_and(is_emoji_like)
+                && chars.peek().copied().is_some_and(is_emoji_like);
+            if !joins_emoji {
+                if unicode == UnicodeFilter::Flag {
+                    if pending_space {
+                        out.push(' ');
+                        pending_space = false;
+                    }
+                    out.push_str(&format!("<U+{:04X}>", c as u32));
+                }
+                continue;
+            }
+        }
+        if c.is_whitespace() {
+            pending_space = !out.is_empty();
+            continue;
+        }
+        if c.is_control() && !(direction == Direction::IrcToAmnezichat && IRC_FORMATTING.contains(&c)) {
+            continue;
+        }
+        if pending_space {
+            out.push(' ');
+            pending_space = false;
+        }
+        out.push(c);
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFF: UnicodeFilter = UnicodeFilter::Off;
+
+    #[test]
+    fn collapses_tabs_and_newlines() {
+        assert_eq!(sanitize(Direction::AmnezichatToIrc, "  hello\t\tworld\r\n again \n", OFF), "hello world again");
+        assert_eq!(sanitize(Direction::IrcToAmnezichat, "a \t b", OFF), "a b");
+    }
+
+    #[test]
+    fn drops_control_characters() {
+        assert_eq!(sanitize(Direction::AmnezichatToIrc, "in\x00ject\x07ed\x1b[31m", OFF), "injected[31m");
+        assert_eq!(sanitize(Direction::AmnezichatToIrc, "\x02bold\x02 \x0304red", OFF), "bold 04red");
+    }
+
+    #[test]
+    fn keeps_irc_formatting_towards_amnezichat() {
+        assert_eq!(sanitize(Direction::IrcToAmnezichat, "\x02bold\x02\x00", OFF), "\x02bold\x02");
+    }
+
+    #[test]
+    fn strips_formatting_and_color_numbers() {
+        assert_eq!(strip_formatting("\x02bold\x02 \x1ditalic\x0f \x0304red\x03 \x034,12on blue\x03"), "bold italic red on blue");
+        assert_eq!(strip_formatting("\x0312,5 sauce\x03, 2 eggs"), " sauce, 2 eggs");
+        assert_eq!(strip_formatting("\x0399 bottles \x03,1"), " bottles ,1");
+        assert_eq!(strip_formatting("\x04ff0000,00ff00hex\x04"), "hex");
+    }
+
+    #[test]
+    fn zero_width_characters_are_not_whitespace() {
+        assert_eq!(sanitize(Direction::IrcToAmnezichat, "a\u{200b}b", OFF), "a\u{200b}b");
+        assert_eq!(sanitize(Direction::AmnezichatToIrc, "\u{200b}", OFF), "\u{200b}");
+    }
+
+    #[test]
+    fn whitespace_only_becomes_empty() {
+        assert_eq!(sanitize(Direction::AmnezichatToIrc, " \r\n\t ", OFF), "");
+    }
+
+    #[test]
+    fn neutralizes_right_to_left_override() {
+        let spoof = "alice\u{202e}txt.exe";
+        assert_eq!(sanitize(Direction::IrcToAmnezichat, spoof, UnicodeFilter::Strip), "alicetxt.exe");
+        assert_eq!(sanitize(Direction::AmnezichatToIrc, spoof, UnicodeFilter::Strip), "alicetxt.exe");
+        assert_eq!(sanitize(Direction::AmnezichatToIrc, spoof, UnicodeFilter::Flag), "alice<U+202E>txt.exe");
+    }
+
+    #[test]
+    fn strips_zero_width_but_keeps_emoji_sequences() {
+        assert_eq!(sanitize(Direction::IrcToAmnezichat, "ad\u{200b}m\u{200d}in\u{feff}", UnicodeFilter::Strip), "admin");
+        let family = "\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}";
+        assert_eq!(sanitize(Direction::AmnezichatToIrc, family, UnicodeFilter::Strip), family);
+    }
+}
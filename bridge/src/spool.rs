@@ -0,0 +1,147 @@
+//! `--spool <dir>`: messages the bridge has taken on but not delivered yet,
+//! kept on disk so that a crash or restart doesn't lose them. Every message
+//! is one file, written before it is sent and removed once it is through;
+//! whatever is left at startup is delivered then.
+//!
+//! Nothing is stored in the clear. Room posts are kept as the ciphertext
+//! the server would get, lines for IRC encrypted with their room's key.
+
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::logging;
+
+/// Which way a spooled message is going.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Destination {
+    Irc,
+    Room,
+}
+
+impl Destination {
+    fn prefix(self) -> &'static str {
+        match self {
+            Destination::Irc => "irc",
+            Destination::Room => "room",
+        }
+    }
+}
+
+/// A message read back from the spool.
+#[derive(Debug)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub room_id: String,
+    pub payload: String,
+}
+
+pub struct Spool {
+    dir: PathBuf,
+    /// Orders messages stored within the same nanosecond.
+    seq: AtomicU64,
+}
+
+impl Spool {
+    /// Uses `dir`, creating it (readable only by its owner) if need be.
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        let mut builder = std::fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder.create(dir).map_err(|e| io::Error::new(e.kind(), format!("Cannot create the spool {}: {}", dir.display(), e)))?;
+        Ok(Spool { dir: dir.to_path_buf(), seq: AtomicU64::new(0) })
+    }
+
+    /// Keeps `payload` for `room_id` until `done` is called with the path
+    /// returned. A message that can't be spooled is still sent, so failures
+    /// are only logged.
+    pub fn store(&self, to: Destination, room_id: &str, payload: &str) -> Option<PathBuf> {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        let name = format!("{}-{:024}-{:08}", to.prefix(), nanos, self.seq.fetch_add(1, Ordering::Relaxed));
+        let path = self.dir.join(format!("{}.msg", name));
+        let partial = self.dir.join(format!("{}.tmp", name));
+        let written = (|| {
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            let mut file = options.open(&partial)?;
+            file.write_all(format!("{}\n{}\n", room_id, payload).as_bytes())?;
+            file.sync_all()?;
+            // Renamed only once complete, so a crash mid-write leaves no
+            // half a message to deliver.
+            std::fs::rename(&partial, &path)
+        })();
+        match written {
+            Ok(()) => Some(path),
+            Err(e) => {
+                logging::warn("spool", format!("Cannot spool a message to {}: {}", self.dir.display(), e));
+                let _ = std::fs::remove_file(&partial);
+                None
+            }
+        }
+    }
+
+    /// The messages for `to` stored before `before`, oldest first.
+    pub fn pending(&self, to: Destination, before: SystemTime) -> Vec<Entry> {
+        let before = before.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        let Ok(dir) = std::fs::read_dir(&self.dir) else { return Vec::new() };
+        let mut paths: Vec<PathBuf> = dir
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| {
+                let Some(name) = path.file_name().and_then(|n| n.to_str()).and_then(|n| n.strip_suffix(".msg")) else { return false };
+                let mut fields = name.split('-');
+                fields.next() == Some(to.prefix()) && fields.next().and_then(|t| t.parse::<u128>().ok()).is_some_and(|t| t < before)
+            })
+            .collect();
+        paths.sort();
+        paths
+            .into_iter()
+            .filter_map(|path| {
+                let text = std::fs::read_to_string(&path).ok()?;
+                let (room_id, payload) = text.trim_end().split_once('\n')?;
+                Some(Entry { room_id: room_id.to_string(), payload: payload.to_string(), path })
+            })
+            .collect()
+    }
+
+    /// Forgets a delivered message.
+    pub fn done(&self, path: &Path) {
+        if let Err(e) = std::fs::remove_file(path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                logging::warn("spool", format!("Cannot remove {} from the spool: {}", path.display(), e));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_stay_until_done_in_the_order_stored() {
+        let dir = std::env::temp_dir().join(format!("amnezichat-spool-{}", rand::random::<u64>()));
+        let spool = Spool::open(&dir).unwrap();
+        let first = spool.store(Destination::Room, "room1", "aa:bb:cc").unwrap();
+        spool.store(Destination::Irc, "room1", "dd:ee:ff").unwrap();
+        spool.store(Destination::Room, "room2", "11:22:33").unwrap();
+
+        let reopened = Spool::open(&dir).unwrap();
+        let pending: Vec<(String, String)> = reopened.pending(Destination::Room, SystemTime::now()).into_iter().map(|e| (e.room_id, e.payload)).collect();
+        assert_eq!(pending, vec![("room1".to_string(), "aa:bb:cc".to_string()), ("room2".to_string(), "11:22:33".to_string())]);
+        assert!(reopened.pending(Destination::Room, UNIX_EPOCH).is_empty());
+
+        reopened.done(&first);
+        assert_eq!(reopened.pending(Destination::Room, SystemTime::now()).len(), 1);
+        assert_eq!(reopened.pending(Destination::Irc, SystemTime::now())[0].payload, "dd:ee:ff");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&dir).unwrap().permissions().mode() & 0o777, 0o700);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,53 @@
+//! Threads in the room. Amnezichat rooms are one flat list of messages and
+//! have no threads of their own; this reads a format proposed by the
+//! bridge, which no Amnezichat client posts yet:
+//! `alice: <thread name="lunch">noon?</thread>`. IRC has nothing like it
+//! either, so the bridge flattens such a message: on IRC it shows behind a
+//! `[thread: lunch]` label.
+
+use std::borrow::Cow;
+
+/// Longer thread names are cut in the IRC label.
+const MAX_LABEL_CHARS: usize = 40;
+
+/// Whether `name` could have been written into the thread markup.
+fn valid_name(name: &str) -> bool {
+    !name.trim().is_empty() && !name.contains(['"', '<', '>'])
+}
+
+/// A decrypted room message (`user: text`) with any thread markup turned
+/// into a `[thread: name]` label in front of the text.
+pub fn flatten(message: &str) -> Cow<'_, str> {
+    let Some((user, body)) = message.split_once(": ") else { return Cow::Borrowed(message) };
+    let Some((name, text)) = parse(body) else { return Cow::Borrowed(message) };
+    let mut label: String = name.trim().chars().take(MAX_LABEL_CHARS).collect();
+    if label.len() < name.trim().len() {
+        label.push('\u{2026}');
+    }
+    Cow::Owned(format!("{}: [thread: {}] {}", user, label, text))
+}
+
+fn parse(body: &str) -> Option<(&str, &str)> {
+    let rest = body.trim().strip_prefix("<thread name=\"")?;
+    let (name, rest) = rest.split_once("\">")?;
+    let text = rest.strip_suffix("</thread>")?;
+    valid_name(name).then_some((name, text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn threaded_messages_keep_a_readable_label() {
+        assert_eq!(flatten("alice: <thread name=\"lunch\">noon?</thread>"), "alice: [thread: lunch] noon?");
+        assert_eq!(flatten("alice: <thread name=\"plans\">a\nb</thread>"), "alice: [thread: plans] a\nb");
+        let long = "x".repeat(60);
+        assert_eq!(flatten(&format!("bob: <thread name=\"{}\">hi</thread>", long)), format!("bob: [thread: {}\u{2026}] hi", "x".repeat(40)));
+
+        assert_eq!(flatten("alice: noon?"), "alice: noon?");
+        assert_eq!(flatten("alice: see <thread name=\"lunch\">noon?</thread>"), "alice: see <thread name=\"lunch\">noon?</thread>");
+        assert_eq!(flatten("alice: <thread name=\"\">noon?</thread>"), "alice: <thread name=\"\">noon?</thread>");
+        assert!(!valid_name("a\"b") && !valid_name(" ") && valid_name("release 1.2"));
+    }
+}
@@ -0,0 +1,53 @@
+use std::sync::{Arc, LazyLock};
+
+use regex::Regex;
+
+use crate::sanitize::Direction;
+
+/// Extension point for rewriting messages before they are forwarded, e.g.
+/// profanity filters or link shorteners. Called after sanitizing, once per
+/// message and direction. Leaving the message empty drops it.
+pub trait MessageTransform: Send + Sync {
+    fn apply(&self, direction: Direction, message: &mut String);
+}
+
+/// Forwards messages unchanged.
+pub struct NoTransform;
+
+impl MessageTransform for NoTransform {
+    fn apply(&self, _direction: Direction, _message: &mut String) {}
+}
+
+static URL: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?i)\b(?:https?://|www\.)\S+").unwrap());
+
+/// Removes links in both directions.
+pub struct StripUrls;
+
+impl MessageTransform for StripUrls {
+    fn apply(&self, _direction: Direction, message: &mut String) {
+        if URL.is_match(message) {
+            let stripped = URL.replace_all(message, "");
+            *message = stripped.split_whitespace().collect::<Vec<_>>().join(" ");
+        }
+    }
+}
+
+pub fn no_transform() -> Arc<dyn MessageTransform> {
+    Arc::new(NoTransform)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_urls() {
+        let mut msg = "see https://example.com/a?b=c and www.example.org too".to_string();
+        StripUrls.apply(Direction::IrcToAmnezichat, &mut msg);
+        assert_eq!(msg, "see and too");
+
+        let mut only = "http://example.com".to_string();
+        StripUrls.apply(Direction::AmnezichatToIrc, &mut only);
+        assert!(only.is_empty());
+    }
+}